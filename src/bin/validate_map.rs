@@ -0,0 +1,78 @@
+//! Checks a map before it ships. Prints every issue `validate_map` finds and exits with status 1
+//! if there were any.
+//!
+//!     validate_map <map.ron>
+//!     validate_map <project.ldtk> <level>
+//!     validate_map --generate <template> <seed> [<width>x<height>]
+
+use std::process::ExitCode;
+use bevy::math::IVec2;
+use stibag::stibag::map::ldtk::import_level;
+use stibag::stibag::map::mapfile::MapFile;
+use stibag::stibag::map::validate::{validate_map, ValidationContext};
+use stibag::stibag::map::{ActorPlacement, LightPlacement, Map};
+use stibag::stibag::map::gen;
+
+const USAGE: &str = "usage: validate_map <map.ron> | <project.ldtk> <level> | --generate <template> <seed> [<width>x<height>]";
+
+struct Loaded {
+    map: Map,
+    lights: Vec<LightPlacement>,
+    actors: Vec<ActorPlacement>,
+    start: Option<IVec2>,
+}
+
+fn parse_size(s: &str) -> Option<IVec2> {
+    let (w, h) = s.split_once('x')?;
+    Some(IVec2::new(w.parse().ok()?, h.parse().ok()?)).filter(|size| size.x > 0 && size.y > 0)
+}
+
+fn load(args: &[String]) -> Result<Loaded, String> {
+    match args {
+        [flag, template, seed, rest @ ..] if flag == "--generate" && rest.len() <= 1 => {
+            let seed = seed.parse().map_err(|_| format!("invalid seed {}", seed))?;
+            let size = match rest.first() {
+                Some(s) => parse_size(s).ok_or_else(|| format!("invalid size {}", s))?,
+                None => IVec2::new(80, 50),
+            };
+            let generated = gen::generate(template, size, seed);
+            Ok(Loaded { map: generated.map, lights: generated.lights, actors: generated.actors, start: Some(generated.player_spawn) })
+        }
+        [path] => {
+            let (map, lights) = MapFile::load(path).and_then(MapFile::into_map).map_err(|e| format!("{:?}", e))?;
+            Ok(Loaded { map, lights, actors: Vec::new(), start: None })
+        }
+        [path, level] => {
+            let import = import_level(path, level).map_err(|e| format!("{:?}", e))?;
+            Ok(Loaded { map: import.map, lights: import.lights, actors: import.actors, start: None })
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let loaded = match load(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let issues = validate_map(&loaded.map, &ValidationContext {
+        lights: &loaded.lights,
+        actors: &loaded.actors,
+        start: loaded.start,
+        known_maps: None,
+    });
+    for issue in issues.iter() {
+        println!("{}", issue);
+    }
+    if issues.is_empty() {
+        println!("no issues found");
+        ExitCode::SUCCESS
+    } else {
+        println!("{} issues found", issues.len());
+        ExitCode::from(1)
+    }
+}
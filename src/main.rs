@@ -1,6 +1,6 @@
 extern crate core;
 
-use crate::stibag::StibagGamePlugin;
+use stibag::stibag::StibagGamePlugin;
 // use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy::app::App;
 use bevy::DefaultPlugins;
@@ -10,7 +10,6 @@ use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
 use bevy::diagnostic::LogDiagnosticsPlugin;
 use bevy_ecs_tilemap::prelude::*;
 
-mod stibag;
 mod build;
 
 fn main() {
@@ -1,18 +1,40 @@
 ﻿use bevy::render::color::Color;
 use std::collections::HashMap;
-use std::ops::Add;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use bevy::log::info;
-use bevy::math::{IVec2, Vec4};
+use bevy::log::{error, info};
+use bevy::math::{IVec2, Vec3};
+use koto::prelude::{type_error_with_slice, KValue};
 use koto::Koto;
 use crate::stibag;
-use crate::stibag::map::{LightContribution, LightEmitter};
+use crate::stibag::map::{ActorPlacement, ItemPlacement, LightContribution, LightContributionType, LightEmitter, LightFalloff, LightPlacement, MapLayer, MapLink, TileDamage, DEFAULT_LIGHT_RADIUS};
+use crate::stibag::map::gen::{GenRng, GeneratedMap};
+use crate::stibag::map::ascii::{render_area, AsciiOverlay};
+use crate::stibag::map::region::MapRegion;
+use crate::stibag::map::validate::{validate_map, MapIssue, ValidationContext};
+use crate::stibag::map::liquid::{flow_liquids, Liquid, LiquidKind};
+use crate::stibag::map::fire;
+use crate::stibag::map::gas::{diffuse_gas, Gas, GasKind};
+use crate::stibag::map::chunk::ChunkChange;
+use crate::stibag::map::fov::FovAlgorithm;
+use crate::stibag::map::memory::{RememberedTile, TileMemory};
+use crate::stibag::map::ldtk::LdtkImportError;
+use crate::stibag::map::mapfile::{ExploredRecord, MapFile, MapFileError};
 
 pub type ItemId = u32;
 pub type ActorId = u32;
 
 pub type LightId = u32;
 
+/// Damage a dig or bash action deals to a tile per turn.
+const DIG_DAMAGE_PER_TURN: u32 = 10;
+/// Damage fire deals per turn to an actor standing in it.
+const FIRE_DAMAGE_PER_TURN: i32 = 3;
+/// Smoke a burning tile gives off per turn.
+const SMOKE_PER_FIRE: f32 = 0.3;
+/// Seeds the world's rng, so that fire and other random events replay the same way.
+const WORLD_SEED: u64 = 1;
+
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub enum ItemSlot {
@@ -44,6 +66,8 @@ pub trait Item {
     fn slot(&self) -> ItemSlot;
     fn display_name_singular(&self) -> String;
     fn weight(&self) -> f32;
+    /// Chance per turn to burn up when lying in fire; 0 for items that don't burn.
+    fn flammability(&self) -> f32;
 
     fn container(&self) -> Option<&ItemContainer>;
 }
@@ -55,7 +79,7 @@ pub trait WorldActor {
     fn info(&mut self) -> &mut ActorInfo;
 
     fn position(&mut self) -> IVec2 {
-        self.info().position.clone()
+        self.info().position
     }
 
     fn on_spawn(&self, world: &mut World);
@@ -65,6 +89,13 @@ pub trait WorldActor {
         self.info().position = new_position;
     }
     fn on_move(&mut self, world: &mut World, new_position: IVec2);
+
+    /// Called after tiles of the current map changed in a way that affects sight or movement.
+    fn on_map_changed(&mut self, _world: &mut World) {}
+
+    fn inventory(&mut self) -> Option<&mut ItemContainer> {
+        None
+    }
 }
 
 struct BasicItem {
@@ -73,6 +104,7 @@ struct BasicItem {
     slot: ItemSlot,
     display_name: String,
     weight: f32,
+    flammability: f32,
 }
 
 impl Item for BasicItem {
@@ -100,6 +132,10 @@ impl Item for BasicItem {
         self.weight
     }
 
+    fn flammability(&self) -> f32 {
+        self.flammability
+    }
+
     fn container(&self) -> Option<&ItemContainer> {
         None
     }
@@ -107,12 +143,28 @@ impl Item for BasicItem {
 
 pub struct ActorInfo {
     pub position: IVec2,
+    pub health: i32,
+    pub max_health: i32,
+    /// Lets the actor cross deep liquid.
+    pub can_swim: bool,
+    /// How the actor's vision is worked out.
+    pub fov: FovAlgorithm,
+}
+
+impl Default for ActorInfo {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ActorInfo {
     pub fn new() -> Self {
         ActorInfo {
             position: IVec2::new(0, 0),
+            health: 10,
+            max_health: 10,
+            can_swim: false,
+            fov: FovAlgorithm::default(),
         }
     }
 }
@@ -146,7 +198,17 @@ impl WorldActor for HumanoidActor {
 
     fn on_move(&mut self, world: &mut World, new_position: IVec2) {
         info!("Humanoid actor {} moved to {:?}", self.actor_id, new_position);
-        self.vision = world.map.calc_vision(new_position, self.vision_radius);
+        self.vision = world.map.calc_vision_with(new_position, self.vision_radius, self.info.fov);
+        world.remember_tiles(self.actor_id, &self.vision);
+    }
+
+    fn on_map_changed(&mut self, world: &mut World) {
+        self.vision = world.map.calc_vision_with(self.info.position, self.vision_radius, self.info.fov);
+        world.remember_tiles(self.actor_id, &self.vision);
+    }
+
+    fn inventory(&mut self) -> Option<&mut ItemContainer> {
+        Some(&mut self.inventory)
     }
 
     fn act(&self, _world: &mut World) -> u64 {
@@ -161,6 +223,12 @@ pub struct ItemContainer {
     contents: Vec<Box<dyn Item + Send + Sync>>,
 }
 
+impl Default for ItemContainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(dead_code)]
 impl ItemContainer {
     pub fn new() -> Self {
@@ -170,6 +238,7 @@ impl ItemContainer {
         }
     }
 
+    #[allow(clippy::borrowed_box)]
     pub fn can_contain(&self, _item: &Box<dyn Item + Send + Sync>) -> bool {
         true
     }
@@ -185,9 +254,70 @@ impl ItemContainer {
         self.contents.retain(|item| item.id() != item_id);
     }
 
+    #[allow(clippy::borrowed_box)]
     pub fn get_item(&self, item_id: ItemId) -> Option<&Box<dyn Item + Send + Sync>> {
         self.contents.iter().find(|item| item.id() == item_id)
     }
+
+    pub fn items(&self) -> impl Iterator<Item=&Box<dyn Item + Send + Sync>> {
+        self.contents.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    pub fn contains_named(&self, name: &str) -> bool {
+        self.contents.iter().any(|item| item.display_name_singular() == name)
+    }
+}
+
+/// A map change asked for by a script. Scripts can't reach the world while they run, so their
+/// changes are queued and carried out once the script has finished.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptCommand {
+    Ignite(IVec2),
+    PourLiquid(IVec2, LiquidKind, u8),
+    ReleaseGas(IVec2, GasKind, f32),
+}
+
+/// The possessed actor entering or leaving a named map region.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionEvent {
+    Entered(String),
+    Left(String),
+}
+
+/// What stopped a projectile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectileHit {
+    /// It reached its target without running into anything.
+    Nothing,
+    /// A tile it can't pass, such as a wall or a closed door.
+    Tile(IVec2),
+    Actor(ActorId, IVec2),
+}
+
+/// The tiles a projectile flew through, up to and including the one where it hit, and what it hit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectilePath {
+    pub path: Vec<IVec2>,
+    pub hit: ProjectileHit,
+}
+
+/// A map that is not the current one, together with the actors, lights and timeline entries
+/// that live on it. Items lying on the map stay in its tiles.
+pub struct StoredLevel {
+    map: stibag::map::Map,
+    actors: HashMap<ActorId, Box<dyn WorldActor + Send + Sync>>,
+    lights: HashMap<LightId, Box<LightEmitter>>,
+    timeline: Vec<(u64, ActorId)>,
+    /// Where the possessed actor arrives when no position is given.
+    entry: IVec2,
+    /// Placements of a generated level, spawned the first time it is entered.
+    pending: Option<(Vec<LightPlacement>, Vec<ActorPlacement>, Vec<ItemPlacement>)>,
+    liquid_lights: HashMap<IVec2, LightId>,
+    fire_lights: HashMap<IVec2, LightId>,
 }
 
 #[allow(dead_code)]
@@ -203,17 +333,80 @@ pub struct World {
     pub actors: Arc<Mutex<HashMap<ActorId, Box<dyn WorldActor + Send + Sync>>>>,
     pub items: Arc<Mutex<HashMap<ItemId, Box<dyn Item + Send + Sync>>>>,
     pub lights: Arc<Mutex<HashMap<LightId, Box<LightEmitter>>>>,
+    pub chunk_changes: Vec<ChunkChange>,
+    /// Positions whose tiles changed after they were built, for the renderer to refresh.
+    pub tile_changes: Vec<IVec2>,
+    /// Names of the regions the possessed actor is in, in the map's region order.
+    pub possessed_regions: Vec<String>,
+    /// Region changes of the possessed actor, for the story system to react to.
+    pub region_events: Vec<RegionEvent>,
+    /// Changes queued by the functions scripts call, see `run_script`.
+    pub script_commands: Arc<Mutex<Vec<ScriptCommand>>>,
+    /// Tiles actors are digging through, worked on each time the actor's turn comes up.
+    pub dig_jobs: HashMap<ActorId, IVec2>,
+    /// Name of the map in `map`.
+    pub current_map: String,
+    pub levels: HashMap<String, StoredLevel>,
+    /// Lights given off by glowing liquid such as lava, kept in step with the flow.
+    pub liquid_lights: HashMap<IVec2, LightId>,
+    /// A flickering light for every burning tile.
+    pub fire_lights: HashMap<IVec2, LightId>,
+    pub rng: GenRng,
+    /// What each actor has seen of the maps it has been on.
+    pub memories: HashMap<ActorId, TileMemory>,
+    /// The tiles each light contributes to, so its light can be taken off again without
+    /// relighting the whole map.
+    light_reach: HashMap<LightId, Vec<IVec2>>,
 }
 
 #[allow(dead_code)]
 impl World {
     pub fn init() -> Self {
+        let mut w = World::with_map(stibag::map::Map::new_from_template("default".to_string(), bevy::math::IVec2::new(100, 100)));
+        w.map.blit_tiles_from_charmap(IVec2::new(5, 5), vec![
+            "########".into(),
+            "#......#".into(),
+            ".......#".into(),
+            "#......#".into(),
+            "########".into(),
+        ], |c| match c {
+            '#' => Some("wall"),
+            _ => None
+        });
+        w.map.blit_layer_from_charmap(MapLayer::Overhead, IVec2::new(5, 5), vec![
+            "########".into(),
+            "########".into(),
+            "########".into(),
+            "########".into(),
+            "########".into(),
+        ], |c| match c {
+            '#' => Some("roof"),
+            _ => None
+        });
+        w.map.blit_layer_type_at(MapLayer::Feature, IVec2::new(5, 7), "door");
+        w.map.add_region(MapRegion::new_rect("house", IVec2::new(5, 5), IVec2::new(8, 5)).with_property("kind", "building"));
+        w.map.blit_layer_type_at(MapLayer::Feature, IVec2::new(3, 7), "tree");
+        w.map.blit_layer_type_at(MapLayer::Feature, IVec2::new(2, 9), "tree");
+        w.spawn_light(IVec2::new(5, 4), None, Color::WHITE, 1.0);
+        w.spawn_light(IVec2::new(10, 1), None, Color::AQUAMARINE, 1.0);
+        let dungeon = stibag::map::gen::generate("dungeon_bsp", IVec2::new(80, 50), 1);
+        let dungeon_entry = dungeon.player_spawn;
+        w.add_level("dungeon_1", dungeon);
+        w.connect_stairs(("town", IVec2::new(20, 20)), ("dungeon_1", dungeon_entry));
+        w.sync_liquid_lights();
+        w.recalculate_lighting();
+        info!("World initialized!");
+        w
+    }
+
+    /// A world with nothing but `map`, as the map named "town".
+    pub fn with_map(map: stibag::map::Map) -> Self {
         let mut w = World {
             player_interface: PlayerInterface {
                 possessed_actor: 0,
             },
             koto_env: Koto::default(),
-            map: stibag::map::Map::new_from_template("default".to_string(), bevy::math::IVec2::new(100, 100)),
+            map,
             current_timeslice: 0,
             actor_id_count: 1,
             item_id_count: 1,
@@ -222,31 +415,21 @@ impl World {
             actors: Arc::new(Mutex::new(HashMap::new())),
             items: Arc::new(Mutex::new(HashMap::new())),
             lights: Arc::new(Mutex::new(HashMap::new())),
+            chunk_changes: Vec::new(),
+            tile_changes: Vec::new(),
+            possessed_regions: Vec::new(),
+            region_events: Vec::new(),
+            script_commands: Arc::new(Mutex::new(Vec::new())),
+            dig_jobs: HashMap::new(),
+            current_map: "town".to_string(),
+            levels: HashMap::new(),
+            liquid_lights: HashMap::new(),
+            fire_lights: HashMap::new(),
+            rng: GenRng::new(WORLD_SEED),
+            memories: HashMap::new(),
+            light_reach: HashMap::new(),
         };
-        w.map.blit_tiles_from_charmap(IVec2::new(5, 5), vec![
-            "########".into(),
-            "#......#".into(),
-            ".......#".into(),
-            "#......#".into(),
-            "########".into(),
-        ], |c| match c {
-            '#' => Some(stibag::map::MapTile {
-                tile_type: "wall".to_string(),
-                tile_visual: "wall".to_string(),
-                position: bevy::math::IVec2::new(0, 0),
-                contained_items: ItemContainer::new(),
-                transparency: stibag::map::Transparency::Opaque,
-                light_color: Color::BLACK,
-                light_amount: 0.0,
-                traversal_cost: -1.0,
-                lighting: Vec::new(),
-            }),
-            _ => None
-        });
-        w.spawn_light(IVec2::new(5, 4), None, Color::WHITE, 1.0);
-        w.spawn_light(IVec2::new(10, 1), None, Color::AQUAMARINE, 1.0);
-        w.recalculate_lighting();
-        info!("World initialized!");
+        w.register_script_functions();
         w
     }
 
@@ -257,8 +440,11 @@ impl World {
             light_id: light_id.try_into().unwrap(),
             position,
             parent_actor,
-            color: color.clone(),
+            color,
             intensity: initial_intensity,
+            fov: FovAlgorithm::default(),
+            radius: DEFAULT_LIGHT_RADIUS,
+            falloff: LightFalloff::default(),
         });
         let l_cloned = self.lights.clone();
         let mut map = l_cloned.lock().unwrap();
@@ -266,6 +452,284 @@ impl World {
         light_id.try_into().unwrap()
     }
 
+    /// Spawns a light that is part of a map, with the placement's radius, falloff and field of view.
+    fn spawn_placed_light(&mut self, placement: LightPlacement) -> LightId {
+        let light_id = self.spawn_light(placement.position, None, placement.color, placement.intensity);
+        let l_cloned = self.lights.clone();
+        let mut map = l_cloned.lock().unwrap();
+        let light = map.get_mut(&light_id).unwrap();
+        light.radius = placement.radius;
+        light.falloff = placement.falloff;
+        light.fov = placement.fov;
+        drop(map);
+        light_id
+    }
+
+    /// Switches the algorithm that decides which tiles the light reaches and relights the map.
+    pub fn set_light_fov(&mut self, light_id: LightId, fov: FovAlgorithm) {
+        let l_cloned = self.lights.clone();
+        let mut map = l_cloned.lock().unwrap();
+        if let Some(light) = map.get_mut(&light_id) {
+            light.fov = fov;
+        }
+        drop(map);
+        self.relight(&[light_id]);
+    }
+
+    /// Changes how far the light reaches and how it fades out on the way, and relights what it reaches.
+    pub fn set_light_falloff(&mut self, light_id: LightId, radius: f32, falloff: LightFalloff) {
+        let l_cloned = self.lights.clone();
+        let mut map = l_cloned.lock().unwrap();
+        if let Some(light) = map.get_mut(&light_id) {
+            light.radius = radius;
+            light.falloff = falloff;
+        }
+        drop(map);
+        self.relight(&[light_id]);
+    }
+
+    /// Moves the light and relights what it reaches.
+    pub fn move_light(&mut self, light_id: LightId, position: IVec2) {
+        let l_cloned = self.lights.clone();
+        let mut map = l_cloned.lock().unwrap();
+        if let Some(light) = map.get_mut(&light_id) {
+            light.position = position;
+        }
+        drop(map);
+        self.relight(&[light_id]);
+    }
+
+    /// Moves the lights an actor carries along with it.
+    fn move_carried_lights(&mut self, actor_id: ActorId, position: IVec2) {
+        let l_cloned = self.lights.clone();
+        let map = l_cloned.lock().unwrap();
+        let mut carried: Vec<LightId> = map.values()
+            .filter(|l| l.parent_actor == Some(actor_id) && l.position != position)
+            .map(|l| l.light_id)
+            .collect();
+        drop(map);
+        carried.sort();
+        for light_id in carried {
+            self.move_light(light_id, position);
+        }
+    }
+
+    /// Removes the light and takes its light off the map.
+    pub fn remove_light(&mut self, light_id: LightId) {
+        let l_cloned = self.lights.clone();
+        let mut map = l_cloned.lock().unwrap();
+        map.remove(&light_id);
+        drop(map);
+        self.remove_light_contribution(light_id);
+    }
+
+    /// Swaps in a new map and queues chunk changes so the renderer drops the old chunks and
+    /// builds the new ones. Returns the old map.
+    fn replace_map(&mut self, map: stibag::map::Map) -> stibag::map::Map {
+        let old = std::mem::replace(&mut self.map, map);
+        self.chunk_changes.extend(old.loaded_chunk_coords().into_iter().map(ChunkChange::Unloaded));
+        self.chunk_changes.extend(self.map.loaded_chunk_coords().into_iter().map(ChunkChange::Loaded));
+        old
+    }
+
+    /// Stores a generated map under `name`; what the generator placed is spawned when the
+    /// level is first entered. Replaces a stored level of the same name.
+    pub fn add_level(&mut self, name: &str, generated: GeneratedMap) {
+        if name == self.current_map {
+            error!("Can't add level {}, it is the current map", name);
+            return;
+        }
+        self.levels.insert(name.to_string(), StoredLevel {
+            map: generated.map,
+            actors: HashMap::new(),
+            lights: HashMap::new(),
+            timeline: Vec::new(),
+            entry: generated.player_spawn,
+            pending: Some((generated.lights, generated.actors, generated.items)),
+            liquid_lights: HashMap::new(),
+            fire_lights: HashMap::new(),
+        });
+    }
+
+    pub fn map_named_mut(&mut self, name: &str) -> Option<&mut stibag::map::Map> {
+        if name == self.current_map {
+            Some(&mut self.map)
+        } else {
+            self.levels.get_mut(name).map(|l| &mut l.map)
+        }
+    }
+
+    /// Puts stairs down at `upper` and stairs up at `lower` and links them to each other.
+    pub fn connect_stairs(&mut self, upper: (&str, IVec2), lower: (&str, IVec2)) {
+        for ((name, pos), (other, other_pos), tile_type) in [(upper, lower, "stairs_down"), (lower, upper, "stairs_up")] {
+            let Some(map) = self.map_named_mut(name) else {
+                error!("Can't place stairs on unknown map {}", name);
+                continue;
+            };
+            let pos = map.normalize_position(pos);
+            map.blit_layer_type_at(MapLayer::Feature, pos, tile_type);
+            map.links.insert(pos, MapLink { map: other.to_string(), position: other_pos });
+        }
+    }
+
+    /// Makes the stored level `name` the current map and moves the possessed actor there, to
+    /// `arrival` or the level's entry. Everything else on the current map is stored with it.
+    pub fn change_map(&mut self, name: &str, arrival: Option<IVec2>) -> bool {
+        let possessed = self.player_interface.possessed_actor;
+        if name == self.current_map {
+            if let Some(position) = arrival {
+                self.place_actor_at(possessed, position);
+            }
+            return true;
+        }
+        let Some(mut level) = self.levels.remove(name) else {
+            error!("Can't change to unknown map {}", name);
+            return false;
+        };
+        info!("Changing map from {} to {}", self.current_map, name);
+
+        let leaving_from = self.get_actor_pos(possessed);
+        // dig jobs refer to positions on the map being left
+        self.dig_jobs.clear();
+        let old_map = self.replace_map(level.map);
+        let ac = self.actors.clone();
+        let mut actors = ac.lock().unwrap();
+        let staying: Vec<ActorId> = actors.keys().copied().filter(|id| *id != possessed).collect();
+        let stored_actors: HashMap<ActorId, Box<dyn WorldActor + Send + Sync>> = staying.into_iter()
+            .filter_map(|id| actors.remove(&id).map(|a| (id, a)))
+            .collect();
+        actors.extend(level.actors.drain());
+        drop(actors);
+        let l_cloned = self.lights.clone();
+        let mut lights = l_cloned.lock().unwrap();
+        let staying: Vec<LightId> = lights.iter()
+            .filter(|(_, l)| l.parent_actor != Some(possessed))
+            .map(|(id, _)| *id)
+            .collect();
+        let stored_lights: HashMap<LightId, Box<LightEmitter>> = staying.into_iter()
+            .filter_map(|id| lights.remove(&id).map(|l| (id, l)))
+            .collect();
+        lights.extend(level.lights.drain());
+        drop(lights);
+        let tl_clone = self.timeline.clone();
+        let mut tl = tl_clone.lock().unwrap();
+        let stored_timeline: Vec<(u64, ActorId)> = tl.iter().copied().filter(|(_, aid)| *aid != possessed).collect();
+        tl.retain(|(_, aid)| *aid == possessed);
+        tl.extend(level.timeline.drain(..));
+        drop(tl);
+
+        let old_name = std::mem::replace(&mut self.current_map, name.to_string());
+        self.levels.insert(old_name, StoredLevel {
+            map: old_map,
+            actors: stored_actors,
+            lights: stored_lights,
+            timeline: stored_timeline,
+            entry: leaving_from,
+            pending: None,
+            liquid_lights: std::mem::replace(&mut self.liquid_lights, level.liquid_lights),
+            fire_lights: std::mem::replace(&mut self.fire_lights, level.fire_lights),
+        });
+        if let Some((lights, actors, items)) = level.pending.take() {
+            for p in lights {
+                self.spawn_placed_light(p);
+            }
+            for p in actors {
+                self.spawn_actor_at(p.template, p.position);
+            }
+            for p in items {
+                self.spawn_item_at(p.template, p.position);
+            }
+        }
+        self.place_actor_at(possessed, arrival.unwrap_or(level.entry));
+        self.sync_liquid_lights();
+        self.sync_fire_lights();
+        self.recalculate_lighting();
+        true
+    }
+
+    /// Swaps in a new map, replaces the map-placed lights with `lights` and spawns `actors`.
+    /// Lights carried by actors are kept. Returns the ids of the spawned actors.
+    fn install_map(&mut self, map: stibag::map::Map, lights: Vec<LightPlacement>, actors: Vec<ActorPlacement>,
+                   items: Vec<ItemPlacement>) -> Vec<ActorId> {
+        self.replace_map(map);
+        // the new map takes over the name, but nobody has seen it yet
+        for memory in self.memories.values_mut() {
+            memory.forget_map(&self.current_map);
+        }
+        let l_cloned = self.lights.clone();
+        let mut l = l_cloned.lock().unwrap();
+        l.retain(|_lid, l| l.parent_actor.is_some());
+        drop(l);
+        self.liquid_lights.clear();
+        self.fire_lights.clear();
+        for p in lights {
+            self.spawn_placed_light(p);
+        }
+        self.sync_liquid_lights();
+        self.sync_fire_lights();
+        let spawned = actors.into_iter()
+            .map(|p| self.spawn_actor_at(p.template, p.position))
+            .collect();
+        for p in items {
+            self.spawn_item_at(p.template, p.position);
+        }
+        self.recalculate_lighting();
+        spawned
+    }
+
+    /// Replaces the current map with one loaded from a RON map file, along with its placed lights.
+    /// What the file says the player explored becomes the possessed actor's memory of the map.
+    pub fn load_map_file(&mut self, path: impl AsRef<Path>) -> Result<(), MapFileError> {
+        let mut file = MapFile::load(path)?;
+        let explored = std::mem::take(&mut file.explored);
+        let (map, placements) = file.into_map()?;
+        self.install_map(map, placements, Vec::new(), Vec::new());
+        let possessed = self.player_interface.possessed_actor;
+        self.memories.entry(possessed).or_default().replace_map(&self.current_map,
+            explored.into_iter().map(|r| (IVec2::new(r.position.0, r.position.1), r.tile)));
+        Ok(())
+    }
+
+    /// Replaces the current map with a level from an LDtk project and spawns the level's lights and actors.
+    /// Returns the ids of the spawned actors.
+    pub fn load_ldtk_level(&mut self, path: impl AsRef<Path>, level_identifier: &str) -> Result<Vec<ActorId>, LdtkImportError> {
+        let import = stibag::map::ldtk::import_level(path, level_identifier)?;
+        Ok(self.install_map(import.map, import.lights, import.actors, Vec::new()))
+    }
+
+    /// Replaces the current map with one generated from a template, spawns what the generator
+    /// placed and moves the possessed actor to the generator's player spawn.
+    /// Returns the ids of the spawned actors.
+    pub fn generate_map(&mut self, template: &str, dimensions: IVec2, seed: u64) -> Vec<ActorId> {
+        let generated = stibag::map::gen::generate(template, dimensions, seed);
+        let spawned = self.install_map(generated.map, generated.lights, generated.actors, generated.items);
+        let possessed = self.player_interface.possessed_actor;
+        if self.actors.lock().unwrap().contains_key(&possessed) {
+            self.place_actor_at(possessed, generated.player_spawn);
+        }
+        spawned
+    }
+
+    pub fn save_map_file(&self, path: impl AsRef<Path>) -> Result<(), MapFileError> {
+        let l_cloned = self.lights.clone();
+        let lights = l_cloned.lock().unwrap();
+        // liquid and fire lights come back with the liquid and fire
+        let mut placed: Vec<&Box<LightEmitter>> = lights.values()
+            .filter(|l| l.parent_actor.is_none())
+            .filter(|l| !self.liquid_lights.values().chain(self.fire_lights.values()).any(|id| *id == l.light_id))
+            .collect();
+        placed.sort_by_key(|l| l.light_id);
+        let placements: Vec<LightPlacement> = placed.iter().map(|l| l.placement()).collect();
+        drop(lights);
+        let mut file = MapFile::from_map(&self.map, &placements)?;
+        if let Some(memory) = self.memories.get(&self.player_interface.possessed_actor) {
+            file.explored = memory.tiles(&self.current_map).into_iter()
+                .map(|(p, tile)| ExploredRecord { position: (p.x, p.y), tile: tile.clone() })
+                .collect();
+        }
+        file.save(path)
+    }
+
     pub fn spawn_actor_from_template(&mut self, _template: String) -> ActorId {
         let actor_id = self.actor_id_count;
         self.actor_id_count += 1;
@@ -284,6 +748,49 @@ impl World {
         actor_id.try_into().unwrap()
     }
 
+    pub fn spawn_actor_at(&mut self, template: String, position: IVec2) -> ActorId {
+        let actor_id = self.spawn_actor_from_template(template);
+        self.place_actor_at(actor_id, position);
+        actor_id
+    }
+
+    /// Recomputes which regions the possessed actor is in and queues an event for every region
+    /// entered or left.
+    fn update_possessed_regions(&mut self) {
+        let pos = self.get_possessed_actor_pos();
+        let now: Vec<String> = self.map.regions_at(pos).into_iter().map(|r| r.name.clone()).collect();
+        for name in self.possessed_regions.iter() {
+            if !now.contains(name) {
+                info!("Left region {}", name);
+                self.region_events.push(RegionEvent::Left(name.clone()));
+            }
+        }
+        for name in now.iter() {
+            if !self.possessed_regions.contains(name) {
+                info!("Entered region {}", name);
+                self.region_events.push(RegionEvent::Entered(name.clone()));
+            }
+        }
+        self.possessed_regions = now;
+    }
+
+    /// Puts an actor at a position without checking whether it can stand there.
+    pub fn place_actor_at(&mut self, actor_id: ActorId, position: IVec2) {
+        if actor_id == self.player_interface.possessed_actor {
+            self.stream_map_around(position);
+        }
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let actor = map.get_mut(&actor_id).unwrap();
+        actor.move_to(position);
+        actor.on_move(self, position);
+        drop(map);
+        self.move_carried_lights(actor_id, position);
+        if actor_id == self.player_interface.possessed_actor {
+            self.update_possessed_regions();
+        }
+    }
+
     pub fn spawn_item_from_template(&mut self, _template: String) -> ItemId {
         let item_id = self.item_id_count;
         self.item_id_count += 1;
@@ -293,6 +800,7 @@ impl World {
             slot: ItemSlot::None,
             display_name: "A basic item".to_string(),
             weight: 0.0,
+            flammability: 0.5,
         });
         let i_cloned = self.items.clone();
         let mut map = i_cloned.lock().unwrap();
@@ -300,6 +808,25 @@ impl World {
         item_id.try_into().unwrap()
     }
 
+    /// Creates an item lying on the map tile at `position`.
+    pub fn spawn_item_at(&mut self, template: String, position: IVec2) -> ItemId {
+        let item_id = self.item_id_count;
+        self.item_id_count += 1;
+        let newitem = Box::new(BasicItem {
+            id: item_id.try_into().unwrap(),
+            parent_container: 0,
+            slot: ItemSlot::None,
+            display_name: template,
+            weight: 0.0,
+            flammability: 0.5,
+        });
+        match self.map.get_tile_at_mut(position) {
+            Some(tile) => tile.contained_items.add_item(newitem),
+            None => error!("Can't place item {} at {:?}, no tile there", item_id, position),
+        }
+        item_id.try_into().unwrap()
+    }
+
     pub fn set_action_timeslice_on_timeline_for(&mut self, actor_id: ActorId, target_timeslice: u64) {
         info!("Setting action timeslice for actor {} to {}", actor_id, target_timeslice);
         let tl_clone = self.timeline.clone();
@@ -324,42 +851,546 @@ impl World {
             tl.retain(|(_ts, aid)| aid != &actor_id);
         }
         tl.push((timeslice, actor_id));
-        tl.sort_by(|a, b| a.0.cmp(&b.0));
+        tl.sort_by_key(|(ts, _)| *ts);
         info!(" => done");
     }
     pub fn player_possess_actor(&mut self, actor_id: ActorId) {
         self.player_interface.possessed_actor = actor_id;
         info!("Player possessed actor {}", actor_id);
+        let pos = self.get_actor_pos(actor_id);
+        self.stream_map_around(pos);
+        self.update_possessed_regions();
+    }
+
+    /// Loads and unloads chunks of a streamed map around `center`. The changes are queued in
+    /// `chunk_changes` for the renderer to pick up.
+    pub fn stream_map_around(&mut self, center: IVec2) {
+        let changes = self.map.stream_around(center);
+        if !changes.is_empty() {
+            info!("Streamed {} chunk changes around {:?}", changes.len(), center);
+            self.sync_liquid_lights();
+            self.sync_fire_lights();
+            self.recalculate_lighting();
+            self.chunk_changes.extend(changes);
+        }
     }
 
     pub fn get_possessed_actor_pos(&self) -> IVec2 {
         self.get_actor_pos(self.player_interface.possessed_actor)
     }
 
+    pub fn get_actor_fov(&self, actor_id: ActorId) -> FovAlgorithm {
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        map.get_mut(&actor_id).map_or(FovAlgorithm::default(), |actor| actor.info().fov)
+    }
+
+    /// Switches the algorithm that decides what the actor sees and refreshes its vision.
+    pub fn set_actor_fov(&mut self, actor_id: ActorId, fov: FovAlgorithm) {
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        if let Some(actor) = map.get_mut(&actor_id) {
+            actor.info().fov = fov;
+            actor.on_map_changed(self);
+        }
+        drop(map);
+    }
+
     pub fn get_actor_pos(&self, actor_id: ActorId) -> IVec2 {
         let ac = self.actors.clone();
         let mut map = ac.lock().unwrap();
         if let Some(actor) = map.get_mut(&actor_id) {
-            let ret = actor.position().clone();
+            let ret = actor.position();
             drop(map);
-            return ret;
+            ret
         } else {
             drop(map);
-            return IVec2::new(0, 0);
+            IVec2::new(0, 0)
+        }
+    }
+    /// Relights around the tiles, refreshes every actor's vision and queues the tiles for the
+    /// renderer after they changed in a way that affects sight or movement.
+    pub fn on_tiles_changed(&mut self, positions: &[IVec2]) {
+        self.tile_changes.extend_from_slice(positions);
+        self.relight_tiles(positions);
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        for actor in map.values_mut() {
+            actor.on_map_changed(self);
+        }
+        drop(map);
+    }
+
+    pub fn actor_has_item_named(&self, actor_id: ActorId, name: &str) -> bool {
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        map.get_mut(&actor_id)
+            .and_then(|actor| actor.inventory().map(|inv| inv.contains_named(name)))
+            .unwrap_or(false)
+    }
+
+    /// Opens the closed door at `position`. A locked door is unlocked first if the actor carries
+    /// its key and stays shut otherwise.
+    pub fn open_door(&mut self, actor_id: ActorId, position: IVec2) -> bool {
+        let position = self.map.normalize_position(position);
+        if self.map.door_state_at(position) != Some(false) {
+            return false;
         }
+        if let Some(key) = self.map.locks.get(&position).cloned() {
+            if !self.actor_has_item_named(actor_id, &key) {
+                info!("Door at {:?} is locked, actor {} needs {}", position, actor_id, key);
+                return false;
+            }
+            info!("Actor {} unlocks the door at {:?} with {}", actor_id, position, key);
+            self.map.unlock_door(position);
+        }
+        self.map.set_door_open(position, true);
+        self.on_tiles_changed(&[position]);
+        true
     }
+
+    /// Closes the open door at `position` unless an actor stands in the doorway.
+    pub fn close_door(&mut self, _actor_id: ActorId, position: IVec2) -> bool {
+        let position = self.map.normalize_position(position);
+        if self.map.door_state_at(position) != Some(true) {
+            return false;
+        }
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let blocked = map.values_mut().any(|actor| actor.position() == position);
+        drop(map);
+        if blocked {
+            return false;
+        }
+        self.map.set_door_open(position, false);
+        self.on_tiles_changed(&[position]);
+        true
+    }
+
+    /// Starts digging or bashing through the tile at `position`, next to the actor. The work
+    /// happens on the actor's turns, replacing whatever the actor would otherwise do, until the
+    /// tile is destroyed.
+    pub fn start_dig(&mut self, actor_id: ActorId, position: IVec2) -> bool {
+        let position = self.map.normalize_position(position);
+        let apos = self.get_actor_pos(actor_id);
+        let d = self.map.wrapped_delta(apos, position).abs();
+        if d.x > 1 || d.y > 1 || !self.map.is_destructible_at(position) {
+            return false;
+        }
+        info!("Actor {} starts digging at {:?}", actor_id, position);
+        self.dig_jobs.insert(actor_id, position);
+        true
+    }
+
+    pub fn cancel_dig(&mut self, actor_id: ActorId) {
+        self.dig_jobs.remove(&actor_id);
+    }
+
+    /// One turn of digging; returns the time it took.
+    fn dig_step(&mut self, actor_id: ActorId, position: IVec2) -> u64 {
+        match self.map.damage_tile_at(position, DIG_DAMAGE_PER_TURN) {
+            TileDamage::Damaged { remaining } => {
+                info!("Actor {} digs at {:?}, {} left", actor_id, position, remaining);
+            }
+            TileDamage::Destroyed => {
+                info!("Actor {} dug through {:?}", actor_id, position);
+                self.dig_jobs.remove(&actor_id);
+                self.on_tiles_changed(&[position]);
+            }
+            TileDamage::Indestructible => {
+                self.dig_jobs.remove(&actor_id);
+            }
+        }
+        1
+    }
+
+    /// Takes the actor off the map, along with the lights it carries.
+    pub fn remove_actor(&mut self, actor_id: ActorId) {
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        map.remove(&actor_id);
+        drop(map);
+        let tl_clone = self.timeline.clone();
+        let mut tl = tl_clone.lock().unwrap();
+        tl.retain(|(_ts, aid)| aid != &actor_id);
+        drop(tl);
+        let l_cloned = self.lights.clone();
+        let mut l = l_cloned.lock().unwrap();
+        l.retain(|_lid, l| l.parent_actor != Some(actor_id));
+        drop(l);
+        self.dig_jobs.remove(&actor_id);
+        self.memories.remove(&actor_id);
+    }
+
+    /// The actor standing at `position`, the one with the lowest id if there are several.
+    pub fn actor_at(&self, position: IVec2) -> Option<ActorId> {
+        let position = self.map.normalize_position(position);
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        map.iter_mut()
+            .filter_map(|(id, actor)| (self.map.normalize_position(actor.position()) == position).then_some(*id))
+            .min()
+    }
+
+    /// Flies a projectile along the line from `from` to `to` and stops it at the first actor or
+    /// impassable tile on the way. The tile it starts from is left out.
+    pub fn trace_projectile(&self, from: IVec2, to: IVec2) -> ProjectilePath {
+        let mut path = Vec::new();
+        for p in self.map.line(from, to).into_iter().skip(1) {
+            path.push(p);
+            if let Some(actor_id) = self.actor_at(p) {
+                return ProjectilePath { path, hit: ProjectileHit::Actor(actor_id, p) };
+            }
+            if self.map.get_tile_at(p).is_none_or(|t| !t.is_passable()) {
+                return ProjectilePath { path, hit: ProjectileHit::Tile(p) };
+            }
+        }
+        ProjectilePath { path, hit: ProjectileHit::Nothing }
+    }
+
+    /// Records how the tiles at `positions` on the current map look, for the actor to remember.
+    pub fn remember_tiles(&mut self, actor_id: ActorId, positions: &[IVec2]) {
+        self.memories.entry(actor_id).or_default().remember(&self.current_map, &self.map, positions);
+    }
+
+    /// How the tile looked when the actor last saw it, if it ever did.
+    pub fn recall_tile(&self, actor_id: ActorId, position: IVec2) -> Option<&RememberedTile> {
+        self.memories.get(&actor_id)?.recall(&self.current_map, position)
+    }
+
+    /// Lowers the actor's health; an actor at zero health dies and is removed, unless it is the
+    /// possessed actor. Returns true if the actor died.
+    pub fn damage_actor(&mut self, actor_id: ActorId, amount: i32, cause: &str) -> bool {
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let Some(actor) = map.get_mut(&actor_id) else {
+            return false;
+        };
+        let info = actor.info();
+        info.health -= amount;
+        let health = info.health;
+        drop(map);
+        info!("Actor {} takes {} damage from {}, {} health left", actor_id, amount, cause, health);
+        if health > 0 {
+            return false;
+        }
+        info!("Actor {} died", actor_id);
+        if actor_id != self.player_interface.possessed_actor {
+            self.remove_actor(actor_id);
+        }
+        true
+    }
+
+    /// Adds liquid to the tile at `position`. A source keeps its depth forever and feeds the flow.
+    /// Fails on tiles that can't hold liquid or already hold another kind.
+    pub fn pour_liquid(&mut self, position: IVec2, kind: LiquidKind, depth: u8, source: bool) -> bool {
+        let Some(position) = self.map.resolve_position(position) else {
+            return false;
+        };
+        let Some(tile) = self.map.get_tile_at(position) else {
+            return false;
+        };
+        if !tile.is_passable() || tile.liquid.is_some_and(|l| l.kind != kind) {
+            return false;
+        }
+        let current = tile.liquid.map_or(0, |l| l.depth);
+        let mut liquid = Liquid::new(kind, current.saturating_add(depth));
+        liquid.source = source;
+        if let Some(tile) = self.map.get_tile_at_mut(position) {
+            tile.liquid = Some(liquid);
+        }
+        self.tile_changes.push(position);
+        let spawned = self.sync_liquid_lights();
+        self.relight(&spawned);
+        true
+    }
+
+    /// Spawns lights for the newly `glowing` tiles and removes the `tracked` lights of tiles that
+    /// stopped glowing, along with their light. Returns the spawned lights, which the caller
+    /// still has to relight.
+    fn sync_tile_lights(&mut self, tracked: &mut HashMap<IVec2, LightId>, glowing: HashMap<IVec2, (Color, f32)>) -> Vec<LightId> {
+        let mut gone: Vec<IVec2> = tracked.keys().copied().filter(|p| !glowing.contains_key(p)).collect();
+        gone.sort_by_key(|p| (p.y, p.x));
+        for position in gone {
+            let light_id = tracked.remove(&position).unwrap();
+            self.remove_light(light_id);
+        }
+        let mut glowing: Vec<(IVec2, (Color, f32))> = glowing.into_iter().filter(|(p, _)| !tracked.contains_key(p)).collect();
+        glowing.sort_by_key(|(p, _)| (p.y, p.x));
+        let mut spawned = Vec::with_capacity(glowing.len());
+        for (position, (color, intensity)) in glowing {
+            let light_id = self.spawn_light(position, None, color, intensity);
+            tracked.insert(position, light_id);
+            spawned.push(light_id);
+        }
+        spawned
+    }
+
+    fn sync_liquid_lights(&mut self) -> Vec<LightId> {
+        let glowing = self.map.tiles()
+            .filter_map(|t| t.liquid.and_then(|l| l.kind.light()).map(|light| (t.position, light)))
+            .collect();
+        let mut tracked = std::mem::take(&mut self.liquid_lights);
+        let changed = self.sync_tile_lights(&mut tracked, glowing);
+        self.liquid_lights = tracked;
+        changed
+    }
+
+    fn sync_fire_lights(&mut self) -> Vec<LightId> {
+        let glowing = self.map.tiles()
+            .filter(|t| t.fire > 0)
+            .map(|t| (t.position, (Color::rgb(1.0, 0.6, 0.2), 0.8)))
+            .collect();
+        let mut tracked = std::mem::take(&mut self.fire_lights);
+        let changed = self.sync_tile_lights(&mut tracked, glowing);
+        self.fire_lights = tracked;
+        changed
+    }
+
+    /// Sets the tile on fire if anything on it can burn, for scripts and story tags.
+    pub fn ignite(&mut self, position: IVec2) -> bool {
+        let position = self.map.normalize_position(position);
+        if !fire::ignite(&mut self.map, position) {
+            return false;
+        }
+        info!("Fire started at {:?}", position);
+        self.tile_changes.push(position);
+        let spawned = self.sync_fire_lights();
+        self.relight(&spawned);
+        true
+    }
+
+    /// Spreads and burns down the fires, hurts the actors standing in them and makes the fire
+    /// lights flicker.
+    fn fire_step(&mut self) {
+        let step = fire::spread_fire(&mut self.map, &mut self.rng);
+        if !step.destroyed_items.is_empty() {
+            info!("Fire destroyed items {:?}", step.destroyed_items);
+        }
+        self.tile_changes.extend_from_slice(&step.changed);
+        self.sync_fire_lights();
+        if self.fire_lights.is_empty() && step.changed.is_empty() {
+            return;
+        }
+        let l_cloned = self.lights.clone();
+        let mut l = l_cloned.lock().unwrap();
+        let mut flicker: Vec<LightId> = self.fire_lights.values().copied().collect();
+        flicker.sort();
+        for light_id in flicker.iter() {
+            if let Some(light) = l.get_mut(light_id) {
+                light.intensity = 0.6 + self.rng.next_f32() * 0.4;
+            }
+        }
+        drop(l);
+        // new fire lights are among the flickering ones
+        self.relight(&flicker);
+        if !step.burnt_out.is_empty() {
+            // burnt doors and trees change what can be seen and walked through
+            self.on_tiles_changed(&step.burnt_out);
+        }
+
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let burning: Vec<ActorId> = map.iter_mut()
+            .filter_map(|(id, actor)| self.map.get_tile_at(actor.position()).is_some_and(|t| t.fire > 0).then_some(*id))
+            .collect();
+        drop(map);
+        for actor_id in burning {
+            self.damage_actor(actor_id, FIRE_DAMAGE_PER_TURN, "fire");
+        }
+    }
+
+    /// Adds gas to the tile at `position`. Fails on tiles that block sight or already hold
+    /// another gas.
+    pub fn release_gas(&mut self, position: IVec2, kind: GasKind, amount: f32) -> bool {
+        let Some(position) = self.map.resolve_position(position) else {
+            return false;
+        };
+        let Some(tile) = self.map.get_tile_at(position) else {
+            return false;
+        };
+        if tile.is_opaque() || tile.gas.is_some_and(|g| g.kind != kind) {
+            return false;
+        }
+        let concentration = tile.gas.map_or(0.0, |g| g.concentration) + amount;
+        if let Some(tile) = self.map.get_tile_at_mut(position) {
+            tile.gas = Some(Gas { kind, concentration });
+        }
+        self.on_tiles_changed(&[position]);
+        true
+    }
+
+    /// Adds `ignite(x, y)`, `pour_liquid(x, y, kind, depth)` and `release_gas(x, y, kind, amount)`
+    /// to the script prelude. Kinds are given by name, such as "lava" or "smoke".
+    // koto's error type is large and the prelude functions have to return it
+    #[allow(clippy::result_large_err)]
+    fn register_script_functions(&mut self) {
+        let prelude = self.koto_env.prelude();
+        let commands = self.script_commands.clone();
+        prelude.add_fn("ignite", move |ctx| match ctx.args() {
+            [KValue::Number(x), KValue::Number(y)] => {
+                let position = IVec2::new(i64::from(*x) as i32, i64::from(*y) as i32);
+                commands.lock().unwrap().push(ScriptCommand::Ignite(position));
+                Ok(KValue::Null)
+            }
+            unexpected => type_error_with_slice("x and y Numbers as arguments", unexpected),
+        });
+        let commands = self.script_commands.clone();
+        prelude.add_fn("pour_liquid", move |ctx| match ctx.args() {
+            [KValue::Number(x), KValue::Number(y), KValue::Str(kind), KValue::Number(depth)] => {
+                let position = IVec2::new(i64::from(*x) as i32, i64::from(*y) as i32);
+                match LiquidKind::from_name(kind.as_str()) {
+                    Some(kind) => {
+                        let depth = i64::from(*depth).clamp(0, u8::MAX as i64) as u8;
+                        commands.lock().unwrap().push(ScriptCommand::PourLiquid(position, kind, depth));
+                    }
+                    None => error!("Script poured unknown liquid {}", kind.as_str()),
+                }
+                Ok(KValue::Null)
+            }
+            unexpected => type_error_with_slice("x and y Numbers, a kind String and an amount Number as arguments", unexpected),
+        });
+        let commands = self.script_commands.clone();
+        prelude.add_fn("release_gas", move |ctx| match ctx.args() {
+            [KValue::Number(x), KValue::Number(y), KValue::Str(kind), KValue::Number(amount)] => {
+                let position = IVec2::new(i64::from(*x) as i32, i64::from(*y) as i32);
+                match GasKind::from_name(kind.as_str()) {
+                    Some(kind) => {
+                        commands.lock().unwrap().push(ScriptCommand::ReleaseGas(position, kind, f64::from(*amount) as f32));
+                    }
+                    None => error!("Script released unknown gas {}", kind.as_str()),
+                }
+                Ok(KValue::Null)
+            }
+            unexpected => type_error_with_slice("x and y Numbers, a kind String and an amount Number as arguments", unexpected),
+        });
+    }
+
+    /// Runs a koto script, then carries out the map changes it asked for, in order. Changes
+    /// queued before a runtime error are still carried out.
+    #[allow(clippy::result_large_err)]
+    pub fn run_script(&mut self, script: &str) -> Result<(), koto::Error> {
+        let result = self.koto_env.compile_and_run(script);
+        let sc_cloned = self.script_commands.clone();
+        let mut sc = sc_cloned.lock().unwrap();
+        let commands = std::mem::take(&mut *sc);
+        drop(sc);
+        for command in commands {
+            let done = match command {
+                ScriptCommand::Ignite(position) => self.ignite(position),
+                ScriptCommand::PourLiquid(position, kind, depth) => self.pour_liquid(position, kind, depth, false),
+                ScriptCommand::ReleaseGas(position, kind, amount) => self.release_gas(position, kind, amount),
+            };
+            if !done {
+                info!("Script command {:?} had no effect", command);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Burning tiles smoke, then the gas spreads and thins out. Sight and light are only
+    /// refreshed where the gas got thick or thin enough to change them, and actors standing in
+    /// harmful gas are hurt.
+    fn gas_step(&mut self) {
+        let opacity_before: HashMap<IVec2, f32> = self.map.tiles()
+            .filter(|t| t.gas.is_some())
+            .map(|t| (t.position, t.gas_opacity()))
+            .collect();
+        let mut burning: Vec<IVec2> = self.fire_lights.keys().copied().collect();
+        burning.sort_by_key(|p| (p.y, p.x));
+        for position in burning {
+            let Some(tile) = self.map.get_tile_at_mut(position) else {
+                continue;
+            };
+            match tile.gas.as_mut() {
+                Some(gas) if gas.kind == GasKind::Smoke => gas.concentration += SMOKE_PER_FIRE,
+                Some(_) => {}
+                None => tile.gas = Some(Gas { kind: GasKind::Smoke, concentration: SMOKE_PER_FIRE }),
+            }
+        }
+        let changed = diffuse_gas(&mut self.map);
+        if changed.is_empty() {
+            return;
+        }
+        let (sight_changed, drawn_changed): (Vec<IVec2>, Vec<IVec2>) = changed.into_iter().partition(|p| {
+            let opacity = self.map.get_tile_at(*p).map_or(0.0, |t| t.gas_opacity());
+            opacity != opacity_before.get(p).copied().unwrap_or(0.0)
+        });
+        self.tile_changes.extend_from_slice(&drawn_changed);
+        if !sight_changed.is_empty() {
+            self.on_tiles_changed(&sight_changed);
+        }
+
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let harmed: Vec<(ActorId, GasKind, i32)> = map.iter_mut()
+            .filter_map(|(id, actor)| {
+                let gas = self.map.get_tile_at(actor.position()).and_then(|t| t.gas)?;
+                let damage = gas.kind.damage(gas.concentration);
+                (damage > 0).then_some((*id, gas.kind, damage))
+            })
+            .collect();
+        drop(map);
+        for (actor_id, kind, damage) in harmed {
+            self.damage_actor(actor_id, damage, &format!("{:?}", kind));
+        }
+    }
+
+    /// Lets liquid flow one step and hurts the actors standing in harmful liquid.
+    fn liquid_step(&mut self) {
+        let changed = flow_liquids(&mut self.map, self.current_timeslice);
+        if !changed.is_empty() {
+            self.tile_changes.extend_from_slice(&changed);
+            let spawned = self.sync_liquid_lights();
+            self.relight(&spawned);
+        }
+        let ac = self.actors.clone();
+        let mut map = ac.lock().unwrap();
+        let harmed: Vec<(ActorId, LiquidKind)> = map.iter_mut()
+            .filter_map(|(id, actor)| {
+                let liquid = self.map.get_tile_at(actor.position()).and_then(|t| t.liquid)?;
+                (liquid.kind.damage() > 0).then_some((*id, liquid.kind))
+            })
+            .collect();
+        drop(map);
+        for (actor_id, kind) in harmed {
+            self.damage_actor(actor_id, kind.damage(), &format!("{:?}", kind));
+        }
+    }
+
     pub fn try_move_actor_to(&mut self, actor_id: ActorId, new_position: IVec2) -> bool {
+        // walking off a wrapping edge comes out on the other side, a clamped edge stops the actor
+        let Some(new_position) = self.map.resolve_position(new_position) else {
+            return false;
+        };
         let ac = self.actors.clone();
         let mut map = ac.lock().unwrap();
         let actor = map.get_mut(&actor_id).unwrap();
+        let can_swim = actor.info().can_swim;
         let tile = self.map.get_tile_at(new_position);
         if let Some(t) = tile {
-            if t.traversal_cost > 0.0 {
+            if t.can_enter(can_swim) {
+                self.dig_jobs.remove(&actor_id);
                 actor.move_to(new_position);
+                if actor_id == self.player_interface.possessed_actor {
+                    self.stream_map_around(new_position);
+                }
                 actor.on_move(self, new_position);
                 drop(map);
+                self.move_carried_lights(actor_id, new_position);
+                if actor_id == self.player_interface.possessed_actor {
+                    self.update_possessed_regions();
+                    if let Some(link) = self.map.links.get(&new_position).cloned() {
+                        self.change_map(&link.map, Some(link.position));
+                    }
+                }
                 true
             } else {
+                drop(map);
+                // the player opens closed doors by walking into them
+                if actor_id == self.player_interface.possessed_actor && self.map.door_state_at(new_position) == Some(false) {
+                    self.open_door(actor_id, new_position);
+                }
                 false
             }
         } else {
@@ -372,79 +1403,183 @@ impl World {
         let ac = self.actors.clone();
         let mut map = ac.lock().unwrap();
         let actor = map.get_mut(&actor_id).unwrap();
-        let apos = actor.position().clone();
+        let apos = actor.position();
         drop(map);
         let new_position = apos + delta;
         self.try_move_actor_to(actor_id, new_position)
     }
 
+    /// Text dump of part of the current map for debugging: '@' is the possessed actor, 'a' any
+    /// other actor and '*' a light placed on the map.
+    pub fn render_ascii(&self, top_left: IVec2, size: IVec2) -> String {
+        let possessed = self.player_interface.possessed_actor;
+        let ac = self.actors.clone();
+        let mut actors = ac.lock().unwrap();
+        let mut actor_glyphs: Vec<(IVec2, char)> = actors.iter_mut()
+            .map(|(id, actor)| (actor.position(), if *id == possessed { '@' } else { 'a' }))
+            .collect();
+        drop(actors);
+        // the possessed actor wins when several share a tile
+        actor_glyphs.sort_by_key(|(_, c)| *c != '@');
+        let l_cloned = self.lights.clone();
+        let lights = l_cloned.lock().unwrap();
+        let light_positions = lights.values().filter(|l| l.parent_actor.is_none()).map(|l| l.position).collect();
+        drop(lights);
+        render_area(&self.map, top_left, size, &AsciiOverlay {
+            actors: actor_glyphs,
+            lights: light_positions,
+            ..Default::default()
+        })
+    }
+
+    /// Runs `validate_map` on the current map with the world's placed lights and actors. Reachability
+    /// is checked from the possessed actor and links may lead to any level the world knows.
+    pub fn validate_current_map(&self) -> Vec<MapIssue> {
+        let possessed = self.player_interface.possessed_actor;
+        let mut start = None;
+        let ac = self.actors.clone();
+        let mut actors = ac.lock().unwrap();
+        let actor_placements: Vec<ActorPlacement> = actors.iter_mut()
+            .map(|(id, actor)| {
+                if *id == possessed {
+                    start = Some(actor.position());
+                }
+                ActorPlacement { template: format!("#{}", id), position: actor.position() }
+            })
+            .collect();
+        drop(actors);
+        let l_cloned = self.lights.clone();
+        let lights = l_cloned.lock().unwrap();
+        let light_placements: Vec<LightPlacement> = lights.values()
+            .filter(|l| l.parent_actor.is_none())
+            .map(|l| l.placement())
+            .collect();
+        drop(lights);
+        let mut known_maps: Vec<String> = self.levels.keys().cloned().collect();
+        known_maps.push(self.current_map.clone());
+        validate_map(&self.map, &ValidationContext {
+            lights: &light_placements,
+            actors: &actor_placements,
+            start,
+            known_maps: Some(&known_maps),
+        })
+    }
+
     pub fn get_ambient_light_value(&self) -> (Color, f32) {
         (Color::WHITE, 0.25)
     }
     pub fn get_light_value_at(&self, position: IVec2) -> (Color, f32) {
         self.map.get_tile_at(position).map(|tile| (tile.light_color, tile.light_amount)).unwrap_or((Color::BLACK, 0.0))
     }
-    pub fn recalculate_lighting(&mut self) {
-        let l_cloned = self.lights.clone();
-        let mut l = l_cloned.lock().unwrap();
 
+    /// All the light on the tile as unclamped linear RGB, for the renderer to tone map.
+    pub fn get_hdr_light_at(&self, position: IVec2) -> Vec3 {
+        self.map.get_tile_at(position).map_or(Vec3::ZERO, |tile| tile.light)
+    }
+    /// Clears all lighting and lights the map again from every emitter.
+    pub fn recalculate_lighting(&mut self) {
         let amb = self.get_ambient_light_value();
-        self.map.tiles.iter_mut().for_each(|tile| {
+        self.map.tiles_mut().for_each(|tile| {
             tile.lighting.clear();
             let ambient = LightContribution::new_ambient(amb.0, amb.1);
             tile.lighting.push(ambient);
+            tile.resolve_lighting();
         });
+        self.light_reach.clear();
 
-        for (_lid, emitter) in l.iter_mut() {
-            let pos = emitter.position;
-            let l_color = emitter.color;
-            let intensity = emitter.intensity;
-            let light_vision = self.map.calc_vision(pos, 30.0);
-            for pos in light_vision {
-                let tile = self.map.get_tile_at_mut(pos);
-
-                let dist = (emitter.position.distance_squared(pos) as f32).sqrt();
-                // info!("RELIGHT {} <=> {} dist={}", emitter.position, pos, dist);
-                let l_intensity = if dist > 0.0 {
-                    intensity / dist
-                } else {
-                    emitter.intensity
-                };
+        let mut light_ids: Vec<LightId> = self.lights.lock().unwrap().keys().copied().collect();
+        light_ids.sort();
+        for light_id in light_ids {
+            self.add_light_contribution(light_id);
+        }
+    }
 
-                let color = Color::rgba(
-                    l_color.r() * l_intensity,
-                    l_color.g() * l_intensity,
-                    l_color.b() * l_intensity,
-                    1.0);
+    /// Recomputes only what the given lights add to the map, as after they moved or changed.
+    /// Lights that no longer exist are taken off the map.
+    pub fn relight(&mut self, light_ids: &[LightId]) {
+        for light_id in light_ids {
+            self.remove_light_contribution(*light_id);
+            self.add_light_contribution(*light_id);
+        }
+    }
 
+    /// Relights the emitters that reach any of `positions`. Light can only get past a tile that
+    /// changed if it reached the tile before, so the other emitters are unaffected.
+    pub fn relight_tiles(&mut self, positions: &[IVec2]) {
+        let mut light_ids: Vec<LightId> = positions.iter()
+            .filter_map(|p| self.map.get_tile_at(*p))
+            .flat_map(|tile| tile.lighting.iter())
+            .filter_map(|c| match c.light_contribution_type {
+                LightContributionType::Emitter(light_id) => Some(light_id),
+                LightContributionType::Ambient => None,
+            })
+            .collect();
+        light_ids.sort();
+        light_ids.dedup();
+        self.relight(&light_ids);
+    }
 
-                tile.light_color = tile.light_color.add(color);
-                let mut tcv: Vec4 = tile.light_color.rgba_to_vec4();
-                tcv = tcv.normalize();
-                tile.light_color = Color::rgba(tcv.x, tcv.y, tcv.z, tcv.w);
-                tile.light_amount += l_intensity;
-                tile.lighting.push(LightContribution::new_emitter(emitter.light_id, color, l_intensity));
-            }
+    /// Adds the light's contribution to every tile it reaches and records those tiles.
+    fn add_light_contribution(&mut self, light_id: LightId) {
+        let l_cloned = self.lights.clone();
+        let l = l_cloned.lock().unwrap();
+        let Some(emitter) = l.get(&light_id) else {
+            return;
+        };
+        let light_vision = self.map.calc_vision_with(emitter.position, emitter.radius, emitter.fov);
+        for pos in light_vision.iter().copied() {
+            let dist = self.map.wrapped_distance(emitter.position, pos);
+            let l_intensity = emitter.intensity * emitter.falloff.attenuation(dist, emitter.radius);
+            let Some(tile) = self.map.get_tile_at_mut(pos) else {
+                continue;
+            };
+            tile.lighting.push(LightContribution::new_emitter(emitter.light_id, emitter.color, l_intensity));
+            tile.resolve_lighting();
+        }
+        drop(l);
+        self.light_reach.insert(light_id, light_vision);
+    }
+
+    /// Takes the light's contribution off the tiles it reached.
+    fn remove_light_contribution(&mut self, light_id: LightId) {
+        let Some(reach) = self.light_reach.remove(&light_id) else {
+            return;
+        };
+        for pos in reach {
+            // the chunk may have been unloaded since
+            let Some(tile) = self.map.get_tile_at_mut(pos) else {
+                continue;
+            };
+            tile.lighting.retain(|c| !matches!(c.light_contribution_type, LightContributionType::Emitter(id) if id == light_id));
+            tile.resolve_lighting();
         }
     }
 
     pub fn tick(&mut self) -> bool {
         self.current_timeslice += 1;
+        self.liquid_step();
+        self.fire_step();
+        self.gas_step();
         let tl_clone = self.timeline.clone();
         let tl = tl_clone.lock().unwrap();
-        let next = tl.get(0);
+        let next = tl.first();
 
         let mut ret = false;
-        if let Some((ts, aid)) = next.clone() {
+        if let Some((ts, aid)) = next {
             if ts == &self.current_timeslice {
-                let ac = self.actors.clone();
-                let mut map = ac.lock().unwrap();
-                let actor = map.get_mut(aid).unwrap();
-                let delay = actor.act(self);
                 let a_id = *aid;
-                let target = self.current_timeslice + delay;
                 drop(tl);
                 drop(tl_clone);
+                let delay = match self.dig_jobs.get(&a_id).copied() {
+                    Some(position) => self.dig_step(a_id, position),
+                    None => {
+                        let ac = self.actors.clone();
+                        let mut map = ac.lock().unwrap();
+                        let actor = map.get_mut(&a_id).unwrap();
+                        actor.act(self)
+                    }
+                };
+                let target = self.current_timeslice + delay;
                 self.set_action_timeslice_on_timeline_for(a_id, target);
                 ret = true;
             }
@@ -457,4 +1592,289 @@ impl World {
             self.tick();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{IVec2, Vec3};
+    use bevy::render::color::Color;
+    use crate::stibag::core::{LightId, ProjectileHit, RegionEvent, World};
+    use crate::stibag::map::{ActorPlacement, ItemPlacement, LightContributionType, LightPlacement, Map, MapLayer, WrapMode};
+    use crate::stibag::map::gas::GasKind;
+    use crate::stibag::map::gen::GeneratedMap;
+    use crate::stibag::map::liquid::LiquidKind;
+    use crate::stibag::map::region::MapRegion;
+
+    /// A small walled field with a house like the town's: walls from (5, 5) to (12, 9), a door
+    /// at (5, 7) and the "house" region, lit by a lamp at (5, 4).
+    fn test_world() -> World {
+        let mut map = Map::new_filled(IVec2::new(20, 12), "grass");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        map.blit_tiles_from_charmap(IVec2::new(5, 5), vec![
+            "########".into(),
+            "#......#".into(),
+            ".......#".into(),
+            "#......#".into(),
+            "########".into(),
+        ], |c| match c {
+            '#' => Some("wall"),
+            _ => None
+        });
+        map.blit_layer_type_at(MapLayer::Feature, IVec2::new(5, 7), "door");
+        map.add_region(MapRegion::new_rect("house", IVec2::new(5, 5), IVec2::new(8, 5)));
+        let mut world = World::with_map(map);
+        world.spawn_light(IVec2::new(5, 4), None, Color::WHITE, 1.0);
+        world.recalculate_lighting();
+        world
+    }
+
+    #[test]
+    fn doors_toggle_opacity() {
+        let mut world = test_world();
+        let player = world.spawn_actor_from_template("player".to_string());
+        world.place_actor_at(player, IVec2::new(4, 7));
+        let door = IVec2::new(5, 7);
+        let inside = IVec2::new(8, 7);
+        assert!(world.map.get_tile_at(door).unwrap().is_opaque());
+        assert!(!world.map.calc_vision(IVec2::new(4, 7), 10.0).contains(&inside));
+
+        assert!(world.open_door(player, door));
+        assert!(!world.map.get_tile_at(door).unwrap().is_opaque());
+        assert!(world.map.calc_vision(IVec2::new(4, 7), 10.0).contains(&inside));
+        assert!(!world.open_door(player, door));
+
+        assert!(world.close_door(player, door));
+        assert!(world.map.get_tile_at(door).unwrap().is_opaque());
+        assert!(!world.close_door(player, door));
+    }
+
+    #[test]
+    fn locked_door_needs_key() {
+        let mut world = test_world();
+        let player = world.spawn_actor_from_template("player".to_string());
+        world.place_actor_at(player, IVec2::new(4, 7));
+        let door = IVec2::new(5, 7);
+        world.map.locks.insert(door, "brass key".to_string());
+        assert!(!world.open_door(player, door));
+        assert!(world.map.get_tile_at(door).unwrap().is_opaque());
+    }
+
+    #[test]
+    fn region_entered_once() {
+        let mut world = test_world();
+        let player = world.spawn_actor_from_template("player".to_string());
+        world.player_possess_actor(player);
+        world.place_actor_at(player, IVec2::new(4, 7));
+        world.region_events.clear();
+
+        // the first step opens the door
+        assert!(!world.try_move_actor_to(player, IVec2::new(5, 7)));
+        assert!(world.try_move_actor_to(player, IVec2::new(5, 7)));
+        assert!(world.try_move_actor_to(player, IVec2::new(6, 7)));
+        assert!(world.try_move_actor_to(player, IVec2::new(7, 7)));
+        assert_eq!(world.region_events, vec![RegionEvent::Entered("house".to_string())]);
+        assert_eq!(world.possessed_regions, vec!["house".to_string()]);
+
+        assert!(world.try_move_actor_to(player, IVec2::new(6, 7)));
+        assert!(world.try_move_actor_to(player, IVec2::new(5, 7)));
+        assert!(world.try_move_actor_to(player, IVec2::new(4, 7)));
+        assert_eq!(world.region_events, vec![RegionEvent::Entered("house".to_string()), RegionEvent::Left("house".to_string())]);
+    }
+
+    #[test]
+    fn poison_gas_hurts() {
+        let mut world = test_world();
+        let victim = world.spawn_actor_at("monster".to_string(), IVec2::new(8, 7));
+        assert!(world.release_gas(IVec2::new(8, 7), GasKind::Poison, 2.0));
+        assert!(!world.release_gas(IVec2::new(8, 7), GasKind::Smoke, 1.0));
+        world.tick();
+        let ac = world.actors.clone();
+        let mut actors = ac.lock().unwrap();
+        assert!(actors.get_mut(&victim).unwrap().info().health < 10);
+    }
+
+    #[test]
+    fn explored_tiles_are_saved() {
+        let mut world = test_world();
+        let player = world.spawn_actor_from_template("player".to_string());
+        world.player_possess_actor(player);
+        world.place_actor_at(player, IVec2::new(8, 7));
+        let seen = world.recall_tile(player, IVec2::new(12, 7)).cloned().expect("wall not remembered");
+        assert_eq!(seen.ground, "wall");
+        assert!(world.recall_tile(player, IVec2::new(15, 7)).is_none());
+
+        let path = std::env::temp_dir().join(format!("stibag_explored_{}.ron", std::process::id()));
+        world.save_map_file(&path).unwrap();
+        world.memories.clear();
+        world.load_map_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(world.recall_tile(player, IVec2::new(12, 7)), Some(&seen));
+        assert!(world.recall_tile(player, IVec2::new(15, 7)).is_none());
+    }
+
+    #[test]
+    fn projectiles_stop_at_first_obstacle() {
+        let mut world = test_world();
+        let (inside, outside) = (IVec2::new(8, 7), IVec2::new(15, 7));
+        for (from, to, hit, length) in [
+            (inside, outside, ProjectileHit::Tile(IVec2::new(12, 7)), 4),
+            (outside, inside, ProjectileHit::Tile(IVec2::new(12, 7)), 3),
+            (inside, IVec2::new(10, 6), ProjectileHit::Nothing, 2),
+        ] {
+            let traced = world.trace_projectile(from, to);
+            assert_eq!(traced.hit, hit, "{} to {}", from, to);
+            assert_eq!(traced.path.len(), length, "{} to {}", from, to);
+            assert_eq!(traced.path, world.map.line(from, to)[1..=length].to_vec());
+        }
+
+        let target = world.spawn_actor_at("monster".to_string(), IVec2::new(10, 7));
+        assert_eq!(world.trace_projectile(inside, outside).hit, ProjectileHit::Actor(target, IVec2::new(10, 7)));
+        assert_eq!(world.trace_projectile(inside, outside).path, vec![IVec2::new(9, 7), IVec2::new(10, 7)]);
+    }
+
+    /// A tile's position, light and the lights reaching it.
+    type TileLighting = (IVec2, Vec3, Vec<(Option<LightId>, [f32; 4], f32)>);
+
+    /// Each tile's light and the lights reaching it, in position order.
+    fn lighting_of(world: &World) -> Vec<TileLighting> {
+        let mut tiles: Vec<_> = world.map.tiles().map(|t| {
+            let mut contributions: Vec<_> = t.lighting.iter().map(|c| {
+                let id = match c.light_contribution_type {
+                    LightContributionType::Emitter(id) => Some(id),
+                    LightContributionType::Ambient => None,
+                };
+                (id, c.color.as_rgba_f32(), c.intensity)
+            }).collect();
+            contributions.sort_by_key(|c| c.0);
+            (t.position, t.light, contributions)
+        }).collect();
+        tiles.sort_by_key(|t| (t.0.y, t.0.x));
+        tiles
+    }
+
+    fn assert_same_as_full_relight(world: &mut World, after: &str) {
+        let incremental = lighting_of(world);
+        world.recalculate_lighting();
+        let full = lighting_of(world);
+        for (a, b) in incremental.iter().zip(full.iter()) {
+            assert_eq!(a.2, b.2, "lights reaching {} after {}", a.0, after);
+            // the same contributions, only added up in another order
+            assert!((a.1 - b.1).abs().max_element() < 1e-5, "light at {} after {}: {} instead of {}", a.0, after, a.1, b.1);
+        }
+    }
+
+    #[test]
+    fn incremental_lighting_matches_full_relight() {
+        let mut world = test_world();
+        let digger = world.spawn_actor_at("player".to_string(), IVec2::new(8, 4));
+        assert!(world.start_dig(digger, IVec2::new(8, 5)));
+        world.tick_until(3);
+        assert!(world.map.get_tile_at(IVec2::new(8, 5)).unwrap().is_passable());
+        assert_same_as_full_relight(&mut world, "digging through the wall");
+
+        assert!(world.open_door(digger, IVec2::new(5, 7)));
+        assert_same_as_full_relight(&mut world, "opening the door");
+
+        let lamp = world.spawn_light(IVec2::new(2, 2), None, Color::RED, 1.0);
+        world.relight(&[lamp]);
+        assert_same_as_full_relight(&mut world, "adding a light");
+        world.move_light(lamp, IVec2::new(9, 7));
+        assert_same_as_full_relight(&mut world, "moving a light into the house");
+        world.move_light(1, IVec2::new(15, 10));
+        assert_same_as_full_relight(&mut world, "moving a light away");
+
+        assert!(world.release_gas(IVec2::new(9, 6), GasKind::Smoke, 3.0));
+        assert_same_as_full_relight(&mut world, "releasing smoke");
+        for turn in 0..10 {
+            world.tick();
+            assert_same_as_full_relight(&mut world, &format!("{} turns of smoke", turn + 1));
+        }
+    }
+
+    #[test]
+    fn digging_takes_turns() {
+        let mut world = test_world();
+        let digger = world.spawn_actor_at("player".to_string(), IVec2::new(4, 4));
+        let wall = IVec2::new(5, 5);
+        assert!(!world.start_dig(digger, IVec2::new(7, 7)));
+        assert!(world.start_dig(digger, wall));
+        // a wall takes three turns of digging
+        for turn in 1..=3 {
+            assert!(!world.map.get_tile_at(wall).unwrap().is_passable(), "dug through after {} turns", turn - 1);
+            assert!(world.tick());
+        }
+        assert_eq!(world.map.get_tile_at(wall).unwrap().tile_type, "rubble");
+        assert!(world.map.get_tile_at(wall).unwrap().is_passable());
+        assert!(!world.dig_jobs.contains_key(&digger));
+    }
+
+    #[test]
+    fn scripts_change_the_map() {
+        let mut world = test_world();
+        world.run_script("ignite 2, 2\npour_liquid 15, 2, 'water', 3\npour_liquid 16, 2, 'tar', 3").unwrap();
+        assert!(world.map.get_tile_at(IVec2::new(2, 2)).unwrap().fire > 0);
+        assert_eq!(world.map.get_tile_at(IVec2::new(15, 2)).unwrap().liquid.map(|l| (l.kind, l.depth)), Some((LiquidKind::Water, 3)));
+        assert!(world.map.get_tile_at(IVec2::new(16, 2)).unwrap().liquid.is_none());
+        world.run_script("release_gas 3, 3, 'fog', 2").unwrap();
+        assert_eq!(world.map.get_tile_at(IVec2::new(3, 3)).unwrap().gas.map(|g| g.kind), Some(GasKind::Fog));
+        assert!(world.run_script("ignite 'here'").is_err());
+        assert!(world.script_commands.lock().unwrap().is_empty());
+    }
+
+    /// Positions of the map's own lights, leaving out those actors carry.
+    fn placed_lights(world: &World) -> Vec<IVec2> {
+        let mut lights: Vec<IVec2> = world.lights.lock().unwrap().values()
+            .filter(|l| l.parent_actor.is_none())
+            .map(|l| l.position)
+            .collect();
+        lights.sort_by_key(|p| (p.y, p.x));
+        lights
+    }
+
+    #[test]
+    fn levels_keep_their_actors_items_lights_and_memory() {
+        let mut world = test_world();
+        let player = world.spawn_actor_at("player".to_string(), IVec2::new(8, 7));
+        world.player_possess_actor(player);
+        assert_eq!(world.recall_tile(player, IVec2::new(12, 7)).map(|t| t.ground.as_str()), Some("wall"));
+        let townsfolk = world.spawn_actor_at("monster".to_string(), IVec2::new(2, 2));
+        world.spawn_item_at("rock".to_string(), IVec2::new(3, 3));
+
+        let mut cellar = GeneratedMap::without_spawns(Map::new_filled(IVec2::new(16, 10), "floor"));
+        cellar.player_spawn = IVec2::new(1, 1);
+        cellar.lights.push(LightPlacement::new(IVec2::new(4, 4), Color::RED, 1.0));
+        cellar.actors.push(ActorPlacement { template: "monster".to_string(), position: IVec2::new(6, 6) });
+        cellar.items.push(ItemPlacement { template: "rock".to_string(), position: IVec2::new(2, 2) });
+        world.add_level("cellar", cellar);
+
+        assert!(world.change_map("cellar", None));
+        assert_eq!(world.current_map, "cellar");
+        assert_eq!(world.get_actor_pos(player), IVec2::new(1, 1));
+        let cellar_dweller = {
+            let actors = world.actors.lock().unwrap();
+            assert!(!actors.contains_key(&townsfolk));
+            assert_eq!(actors.len(), 2);
+            *actors.keys().find(|id| **id != player).unwrap()
+        };
+        assert_eq!(world.get_actor_pos(cellar_dweller), IVec2::new(6, 6));
+        assert_eq!(placed_lights(&world), vec![IVec2::new(4, 4)]);
+        assert!(!world.map.get_tile_at(IVec2::new(2, 2)).unwrap().contained_items.is_empty());
+        assert!(world.map.get_tile_at(IVec2::new(3, 3)).unwrap().contained_items.is_empty());
+        assert_eq!(world.recall_tile(player, IVec2::new(12, 7)).map(|t| t.ground.as_str()), Some("floor"));
+
+        assert!(world.change_map("town", Some(IVec2::new(8, 7))));
+        assert!(world.actors.lock().unwrap().contains_key(&townsfolk));
+        assert!(!world.actors.lock().unwrap().contains_key(&cellar_dweller));
+        assert_eq!(world.get_actor_pos(townsfolk), IVec2::new(2, 2));
+        assert_eq!(placed_lights(&world), vec![IVec2::new(5, 4)]);
+        assert!(!world.map.get_tile_at(IVec2::new(3, 3)).unwrap().contained_items.is_empty());
+        assert_eq!(world.recall_tile(player, IVec2::new(12, 7)).map(|t| t.ground.as_str()), Some("wall"));
+
+        // what the cellar's generator placed is only spawned the first time
+        assert!(world.change_map("cellar", None));
+        assert_eq!(world.actors.lock().unwrap().len(), 2);
+        assert_eq!(placed_lights(&world), vec![IVec2::new(4, 4)]);
+        assert!(!world.map.get_tile_at(IVec2::new(2, 2)).unwrap().contained_items.is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+use bevy::math::IVec2;
+use crate::stibag::map::{Map, MapTile};
+
+/// What to draw over the map in `render_map`. Actors are drawn over lights, lights over tiles.
+#[derive(Default)]
+pub struct AsciiOverlay {
+    pub actors: Vec<(IVec2, char)>,
+    pub lights: Vec<IVec2>,
+    /// If set, only these tiles are drawn and everything else is left blank.
+    pub vision: Option<HashSet<IVec2>>,
+    /// Draw each tile's light amount as a digit from 0 to 9 instead of its glyph.
+    pub light_levels: bool,
+}
+
+/// The glyph of the tile's feature if it has one, otherwise of its ground or the liquid covering
+/// it. Overheads are left out so that what is under a roof stays visible.
+pub fn tile_glyph(map: &Map, tile: &MapTile) -> char {
+    match tile.feature.as_ref() {
+        Some(feature) => map.tile_types.glyph(&feature.tile_visual),
+        None => map.tile_types.glyph(tile.ground_visual()),
+    }
+}
+
+fn light_digit(tile: &MapTile) -> char {
+    let level = (tile.light_amount * 9.0).round().clamp(0.0, 9.0) as u32;
+    char::from_digit(level, 10).unwrap()
+}
+
+/// The inverse of `Map::blit_tiles_from_charmap`: one string per row of the `size` area at
+/// `top_left`, with `glyph_func` picking each tile's character. Unloaded tiles are blank.
+pub fn map_to_charmap(map: &Map, top_left: IVec2, size: IVec2, glyph_func: impl Fn(&Map, &MapTile) -> char) -> Vec<String> {
+    (top_left.y..top_left.y + size.y).map(|y| {
+        (top_left.x..top_left.x + size.x).map(|x| {
+            map.get_tile_at(IVec2::new(x, y)).map_or(' ', |t| glyph_func(map, t))
+        }).collect()
+    }).collect()
+}
+
+/// Dumps the whole map as text, one line per row with row 0 first.
+pub fn render_map(map: &Map, overlay: &AsciiOverlay) -> String {
+    render_area(map, IVec2::new(0, 0), IVec2::new(map.width as i32, map.height as i32), overlay)
+}
+
+/// Like `render_map`, for the `size` area at `top_left` only.
+pub fn render_area(map: &Map, top_left: IVec2, size: IVec2, overlay: &AsciiOverlay) -> String {
+    let rows = map_to_charmap(map, top_left, size, |map, tile| {
+        if let Some(vision) = overlay.vision.as_ref() {
+            if !vision.contains(&tile.position) {
+                return ' ';
+            }
+        }
+        if let Some((_, c)) = overlay.actors.iter().find(|(p, _)| *p == tile.position) {
+            return *c;
+        }
+        if overlay.lights.contains(&tile.position) {
+            return '*';
+        }
+        if overlay.light_levels {
+            light_digit(tile)
+        } else {
+            tile_glyph(map, tile)
+        }
+    });
+    let mut out = rows.join("\n");
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::PathBuf;
+    use bevy::math::IVec2;
+    use bevy::prelude::Color;
+    use crate::stibag::core::World;
+    use crate::stibag::map::{Map, MapLayer};
+    use crate::stibag::map::fov::FovAlgorithm;
+    use crate::stibag::map::ascii::{render_map, AsciiOverlay};
+    use crate::stibag::map::gen;
+    use crate::stibag::map::prefab::{Prefab, PrefabTransform, Rotation};
+
+    /// Compares `actual` with tests/snapshots/<name>.txt. Run with UPDATE_SNAPSHOTS=1 to write
+    /// the snapshots instead, then review the diff.
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("snapshots").join(format!("{}.txt", name));
+        if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("missing snapshot {}, run with UPDATE_SNAPSHOTS=1 to create it", path.display()));
+        // checkouts may have turned the line endings into \r\n
+        assert_eq!(expected.replace("\r\n", "\n"), actual, "snapshot {} differs", name);
+    }
+
+    fn spawn_overlay(generated: &gen::GeneratedMap) -> AsciiOverlay {
+        let mut actors = vec![(generated.player_spawn, '@')];
+        actors.extend(generated.actors.iter().map(|a| (a.position, 'm')));
+        AsciiOverlay {
+            actors,
+            lights: generated.lights.iter().map(|l| l.position).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bsp_dungeon() {
+        let generated = gen::generate("dungeon_bsp", IVec2::new(60, 30), 7);
+        assert_snapshot("bsp_dungeon", &render_map(&generated.map, &spawn_overlay(&generated)));
+    }
+
+    #[test]
+    fn cave() {
+        let generated = gen::generate("cave", IVec2::new(60, 30), 7);
+        assert_snapshot("cave", &render_map(&generated.map, &spawn_overlay(&generated)));
+    }
+
+    #[test]
+    fn overworld() {
+        let generated = gen::generate("overworld", IVec2::new(64, 32), 7);
+        assert_snapshot("overworld", &render_map(&generated.map, &spawn_overlay(&generated)));
+    }
+
+    #[test]
+    fn prefab_transforms() {
+        let prefab = &Prefab::builtin_vaults()[0];
+        let mut out = String::new();
+        for mirrored in [false, true] {
+            for rotation in [Rotation::None, Rotation::Cw90, Rotation::Cw180, Rotation::Cw270] {
+                let transform = PrefabTransform { rotation, mirrored };
+                let size = prefab.size(transform);
+                let mut map = Map::new_filled(size, "grass");
+                let stamped = prefab.stamp(&mut map, IVec2::new(0, 0), transform);
+                let overlay = AsciiOverlay {
+                    actors: stamped.actors.iter().map(|a| (a.position, 'g'))
+                        .chain(stamped.items.iter().map(|i| (i.position, '$')))
+                        .collect(),
+                    lights: stamped.lights.iter().map(|l| l.position).collect(),
+                    ..Default::default()
+                };
+                out.push_str(&format!("{:?} mirrored={}\n", rotation, mirrored));
+                out.push_str(&render_map(&map, &overlay));
+            }
+        }
+        assert_snapshot("prefab_transforms", &out);
+    }
+
+    fn pillar_room() -> Map {
+        let mut map = Map::new_filled(IVec2::new(21, 15), "floor");
+        map.blit_tiles_from_charmap(IVec2::new(0, 0), vec![
+            "#####################".into(),
+            "#...................#".into(),
+            "#...................#".into(),
+            "#.....#.............#".into(),
+            "#...................#".into(),
+            "#..........##.......#".into(),
+            "#..........##.......#".into(),
+            "#...................#".into(),
+            "#...................#".into(),
+            "#...#...............#".into(),
+            "#...................#".into(),
+            "#...................#".into(),
+            "#...................#".into(),
+            "#...................#".into(),
+            "#####################".into(),
+        ], |c| match c {
+            '#' => Some("wall"),
+            _ => None
+        });
+        map
+    }
+
+    #[test]
+    fn fov_around_pillars() {
+        let map = pillar_room();
+        let from = IVec2::new(8, 7);
+        let overlay = AsciiOverlay {
+            actors: vec![(from, '@')],
+            vision: Some(map.calc_vision(from, 30.0).into_iter().collect::<HashSet<IVec2>>()),
+            ..Default::default()
+        };
+        assert_snapshot("fov_around_pillars", &render_map(&map, &overlay));
+    }
+
+    #[test]
+    fn fov_algorithms() {
+        let map = pillar_room();
+        let from = IVec2::new(8, 7);
+        let mut out = String::new();
+        for algorithm in [FovAlgorithm::RecursiveShadowcasting, FovAlgorithm::SymmetricShadowcasting,
+                          FovAlgorithm::Permissive, FovAlgorithm::Raycasting] {
+            let vision = map.calc_vision_with(from, 8.0, algorithm);
+            assert_eq!(vision.len(), vision.iter().collect::<HashSet<_>>().len(), "{:?} repeats tiles", algorithm);
+            let overlay = AsciiOverlay {
+                actors: vec![(from, '@')],
+                vision: Some(vision.into_iter().collect()),
+                ..Default::default()
+            };
+            out.push_str(&format!("{:?}\n{}\n", algorithm, render_map(&map, &overlay)));
+        }
+        assert_snapshot("fov_algorithms", &out);
+    }
+
+    #[test]
+    fn lighting_levels() {
+        let mut world = World::with_map(pillar_room());
+        world.map.blit_layer_type_at(MapLayer::Feature, IVec2::new(15, 3), "door");
+        world.spawn_light(IVec2::new(8, 7), None, Color::WHITE, 1.0);
+        world.recalculate_lighting();
+        let overlay = AsciiOverlay {
+            light_levels: true,
+            ..Default::default()
+        };
+        assert_snapshot("lighting_levels", &render_map(&world.map, &overlay));
+    }
+
+    #[test]
+    fn world_town() {
+        let mut world = World::init();
+        let player = world.spawn_actor_from_template("player".to_string());
+        world.player_possess_actor(player);
+        world.place_actor_at(player, IVec2::new(9, 7));
+        assert_snapshot("world_town", &world.render_ascii(IVec2::new(0, 0), IVec2::new(24, 16)));
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use bevy::log::error;
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::MapTile;
+use crate::stibag::map::mapfile::{MapFileError, MapTileRecord};
+use crate::stibag::map::tiletypes::TileTypeRegistry;
+
+pub const DEFAULT_CHUNK_SIZE: u32 = 32;
+
+/// A rectangular block of tiles. Chunks on the right and bottom edge of a map may be smaller
+/// than the map's chunk size.
+pub struct MapChunk {
+    pub coord: IVec2,
+    pub origin: IVec2,
+    pub size: IVec2,
+    pub tiles: Vec<MapTile>,
+}
+
+impl MapChunk {
+    pub fn contains(&self, position: IVec2) -> bool {
+        let local = position - self.origin;
+        local.x >= 0 && local.y >= 0 && local.x < self.size.x && local.y < self.size.y
+    }
+
+    pub fn tile_index(&self, position: IVec2) -> usize {
+        let local = position - self.origin;
+        (local.y * self.size.x + local.x) as usize
+    }
+}
+
+/// Produces the tiles of a chunk that is not loaded yet. Tiles are returned row by row and must
+/// already have their map positions set.
+pub trait ChunkGenerator: Send + Sync {
+    fn generate_chunk(&self, tile_types: &TileTypeRegistry, origin: IVec2, size: IVec2) -> Vec<MapTile>;
+}
+
+/// Fills every chunk with a single tile type.
+pub struct FlatChunkGenerator {
+    pub tile_type: String,
+}
+
+impl ChunkGenerator for FlatChunkGenerator {
+    fn generate_chunk(&self, tile_types: &TileTypeRegistry, origin: IVec2, size: IVec2) -> Vec<MapTile> {
+        let mut tiles = Vec::with_capacity((size.x * size.y) as usize);
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let mut t = tile_types.make_tile(&self.tile_type);
+                t.position = origin + IVec2::new(x, y);
+                tiles.push(t);
+            }
+        }
+        tiles
+    }
+}
+
+/// How a streamed map brings chunks in and out around the possessed actor. Radii are in chunks.
+pub struct ChunkStreaming {
+    pub generator: Arc<dyn ChunkGenerator>,
+    /// Where unloaded chunks are written to and looked for before generating; without it chunks
+    /// are simply regenerated and any changes to them are lost.
+    pub chunk_dir: Option<PathBuf>,
+    pub load_radius: i32,
+    pub unload_radius: i32,
+}
+
+impl ChunkStreaming {
+    pub fn new(generator: Arc<dyn ChunkGenerator>) -> Self {
+        ChunkStreaming {
+            generator,
+            chunk_dir: None,
+            load_radius: 2,
+            unload_radius: 3,
+        }
+    }
+
+    fn chunk_path(&self, coord: IVec2) -> Option<PathBuf> {
+        self.chunk_dir.as_ref().map(|dir| dir.join(format!("chunk_{}_{}.ron", coord.x, coord.y)))
+    }
+
+    pub fn load_stored(&self, coord: IVec2, origin: IVec2, size: IVec2) -> Option<Vec<MapTile>> {
+        let path = self.chunk_path(coord)?;
+        if !path.exists() {
+            return None;
+        }
+        match ChunkFile::load(&path) {
+            Ok(chunk_file) if chunk_file.tiles.len() == (size.x * size.y) as usize => {
+                Some(chunk_file.tiles.into_iter().enumerate()
+                    .map(|(i, rec)| rec.into_tile(origin + IVec2::new(i as i32 % size.x, i as i32 / size.x)))
+                    .collect())
+            }
+            Ok(_) => {
+                error!("Stored chunk {:?} has the wrong size, regenerating", coord);
+                None
+            }
+            Err(e) => {
+                error!("Failed to load stored chunk {:?}: {}", coord, e);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, chunk: &MapChunk) {
+        let Some(path) = self.chunk_path(chunk.coord) else {
+            return;
+        };
+        let chunk_file = ChunkFile {
+            tiles: chunk.tiles.iter().map(MapTileRecord::from_tile).collect(),
+        };
+        if let Err(e) = chunk_file.save(&path) {
+            error!("Failed to store chunk {:?}: {}", chunk.coord, e);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChunkChange {
+    Loaded(IVec2),
+    Unloaded(IVec2),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkFile {
+    tiles: Vec<MapTileRecord>,
+}
+
+impl ChunkFile {
+    fn load(path: &PathBuf) -> Result<Self, MapFileError> {
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, path: &PathBuf) -> Result<(), MapFileError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, ron::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use bevy::math::IVec2;
+    use crate::stibag::core::World;
+    use crate::stibag::map::Map;
+    use crate::stibag::map::chunk::{ChunkChange, ChunkStreaming, FlatChunkGenerator};
+
+    #[test]
+    fn chunks_with_items_stay_loaded() {
+        let chunk_dir = std::env::temp_dir().join(format!("stibag_chunks_{}", std::process::id()));
+        let mut streaming = ChunkStreaming::new(Arc::new(FlatChunkGenerator { tile_type: "grass".to_string() }));
+        streaming.chunk_dir = Some(chunk_dir.clone());
+        streaming.load_radius = 0;
+        streaming.unload_radius = 0;
+        let mut world = World::with_map(Map::new_streamed(IVec2::new(128, 32), streaming));
+        world.map.stream_around(IVec2::new(0, 0));
+        let item = world.spawn_item_at("rock".to_string(), IVec2::new(3, 3));
+        world.map.blit_tile_type_at(IVec2::new(4, 4), "floor");
+
+        let changes = world.map.stream_around(IVec2::new(80, 0));
+        assert!(!changes.contains(&ChunkChange::Unloaded(IVec2::new(0, 0))));
+        assert!(world.map.get_tile_at(IVec2::new(3, 3)).unwrap().contained_items.get_item(item).is_some());
+
+        world.map.get_tile_at_mut(IVec2::new(3, 3)).unwrap().contained_items.remove_item(item);
+        let changes = world.map.stream_around(IVec2::new(80, 0));
+        assert!(changes.contains(&ChunkChange::Unloaded(IVec2::new(0, 0))));
+        assert!(world.map.get_tile_at(IVec2::new(3, 3)).is_none());
+        assert!(world.map.get_tile_at_mut(IVec2::new(3, 3)).is_none());
+
+        let changes = world.map.stream_around(IVec2::new(0, 0));
+        assert!(changes.contains(&ChunkChange::Loaded(IVec2::new(0, 0))));
+        assert_eq!(world.map.get_tile_at(IVec2::new(4, 4)).unwrap().tile_type, "floor");
+        fs::remove_dir_all(chunk_dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use bevy::math::IVec2;
+use crate::stibag::core::ItemId;
+use crate::stibag::map::{Map, MapLayer};
+use crate::stibag::map::gen::GenRng;
+use crate::stibag::map::liquid::LiquidKind;
+
+/// Turns a tile burns once it has caught fire.
+pub const BURN_TURNS: u32 = 4;
+
+/// What a step of `spread_fire` did.
+#[derive(Default)]
+pub struct FireStep {
+    /// Tiles that caught fire, burnt out or were put out; their visuals changed.
+    pub changed: Vec<IVec2>,
+    /// Tiles that burnt out and turned into something else, which can change sight and movement.
+    pub burnt_out: Vec<IVec2>,
+    pub destroyed_items: Vec<ItemId>,
+}
+
+/// Chance per turn for the tile to catch fire next to a fire: the highest flammability of its
+/// ground, its feature and the items lying on it. Tiles under liquid don't burn.
+pub fn flammability_at(map: &Map, position: IVec2) -> f32 {
+    let Some(tile) = map.get_tile_at(position) else {
+        return 0.0;
+    };
+    if tile.liquid.is_some() {
+        return 0.0;
+    }
+    let ground = map.tile_types.get_or_unknown(&tile.tile_type).flammability;
+    let feature = tile.feature.as_ref().map_or(0.0, |f| map.tile_types.get_or_unknown(&f.tile_type).flammability);
+    let items = tile.contained_items.items().map(|i| i.flammability()).fold(0.0, f32::max);
+    ground.max(feature).max(items)
+}
+
+/// Sets the tile on fire if anything on it can burn. Returns false if it can't or already burns.
+pub fn ignite(map: &mut Map, position: IVec2) -> bool {
+    let Some(position) = map.resolve_position(position) else {
+        return false;
+    };
+    if map.get_tile_at(position).is_none_or(|t| t.fire > 0) || flammability_at(map, position) <= 0.0 {
+        return false;
+    }
+    let Some(tile) = map.get_tile_at_mut(position) else {
+        return false;
+    };
+    tile.fire = BURN_TURNS;
+    true
+}
+
+/// What is left of the tile when its fire goes out: a flammable feature burns into its
+/// `burns_into` or is gone, a flammable ground burns into its `burns_into`.
+fn burn_out(map: &mut Map, position: IVec2) {
+    let tile = map.get_tile_at(position).unwrap();
+    let feature = tile.feature.as_ref()
+        .map(|f| map.tile_types.get_or_unknown(&f.tile_type))
+        .filter(|def| def.flammability > 0.0)
+        .map(|def| def.burns_into.clone());
+    let ground = map.tile_types.get_or_unknown(&tile.tile_type);
+    let ground = (ground.flammability > 0.0).then(|| ground.burns_into.clone()).flatten();
+    match feature {
+        Some(Some(into)) => map.blit_layer_type_at(MapLayer::Feature, position, &into),
+        Some(None) => {
+            map.clear_layer_at(MapLayer::Feature, position);
+            map.locks.remove(&position);
+        }
+        None => {}
+    }
+    if let Some(into) = ground {
+        map.blit_tile_type_at(position, &into);
+    }
+}
+
+/// One turn of fire over the loaded part of the map. Burning tiles and lava set flammable
+/// neighbours alight with a chance of the neighbour's flammability, burn up the items lying on
+/// them and count down until they burn out. Fires that liquid flowed over go out.
+pub fn spread_fire(map: &mut Map, rng: &mut GenRng) -> FireStep {
+    let mut step = FireStep::default();
+    let mut burning: Vec<IVec2> = map.tiles().filter(|t| t.fire > 0).map(|t| t.position).collect();
+    let mut lava: Vec<IVec2> = map.tiles()
+        .filter(|t| t.liquid.is_some_and(|l| l.kind == LiquidKind::Lava))
+        .map(|t| t.position)
+        .collect();
+    // the rng has to be drawn from in the same order every time for a seed to replay
+    burning.sort_by_key(|p| (p.y, p.x));
+    lava.sort_by_key(|p| (p.y, p.x));
+
+    let mut lit = HashSet::new();
+    for position in burning.iter().chain(lava.iter()) {
+        for n in map.neighbours(*position) {
+            if lit.contains(&n) || map.get_tile_at(n).is_none_or(|t| t.fire > 0) {
+                continue;
+            }
+            let flammability = flammability_at(map, n);
+            if flammability > 0.0 && rng.chance(flammability) {
+                lit.insert(n);
+            }
+        }
+    }
+
+    for position in burning {
+        let Some(tile) = map.get_tile_at_mut(position) else {
+            continue;
+        };
+        if tile.liquid.is_some() {
+            tile.fire = 0;
+            step.changed.push(position);
+            continue;
+        }
+        let items: Vec<(ItemId, f32)> = tile.contained_items.items().map(|i| (i.id(), i.flammability())).collect();
+        for (item_id, flammability) in items {
+            if flammability > 0.0 && rng.chance(flammability) {
+                tile.contained_items.remove_item(item_id);
+                step.destroyed_items.push(item_id);
+            }
+        }
+        tile.fire -= 1;
+        if tile.fire == 0 {
+            burn_out(map, position);
+            step.changed.push(position);
+            step.burnt_out.push(position);
+        }
+    }
+
+    let mut lit: Vec<IVec2> = lit.into_iter().collect();
+    lit.sort_by_key(|p| (p.y, p.x));
+    for position in lit {
+        if let Some(tile) = map.get_tile_at_mut(position) {
+            tile.fire = BURN_TURNS;
+            step.changed.push(position);
+        }
+    }
+    step
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use crate::stibag::core::{Item, ItemContainer, ItemId, ItemSlot};
+    use crate::stibag::map::{Map, WrapMode};
+    use crate::stibag::map::fire::{ignite, spread_fire, BURN_TURNS};
+    use crate::stibag::map::gen::GenRng;
+    use crate::stibag::map::liquid::{Liquid, LiquidKind};
+
+    struct Kindling(ItemId);
+
+    impl Item for Kindling {
+        fn id(&self) -> ItemId {
+            self.0
+        }
+        fn set_parent_container(&mut self, _container_id: ItemId) {}
+        fn parent_container(&self) -> ItemId {
+            0
+        }
+        fn slot(&self) -> ItemSlot {
+            ItemSlot::None
+        }
+        fn display_name_singular(&self) -> String {
+            "kindling".to_string()
+        }
+        fn weight(&self) -> f32 {
+            0.1
+        }
+        fn flammability(&self) -> f32 {
+            1.0
+        }
+        fn container(&self) -> Option<&ItemContainer> {
+            None
+        }
+    }
+
+    fn clamped(map_type: &str, width: i32) -> Map {
+        let mut map = Map::new_filled(IVec2::new(width, 1), map_type);
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        map
+    }
+
+    #[test]
+    fn grass_burns_into_ash() {
+        let mut map = clamped("grass", 1);
+        let mut rng = GenRng::new(7);
+        assert!(ignite(&mut map, IVec2::ZERO));
+        for turn in 1..BURN_TURNS {
+            let step = spread_fire(&mut map, &mut rng);
+            assert!(step.burnt_out.is_empty(), "burnt out after {} turns", turn);
+        }
+        let step = spread_fire(&mut map, &mut rng);
+        assert_eq!(step.burnt_out, vec![IVec2::ZERO]);
+        let tile = map.get_tile_at(IVec2::ZERO).unwrap();
+        assert_eq!(tile.fire, 0);
+        assert_eq!(tile.tile_type, "ash");
+    }
+
+    #[test]
+    fn fire_destroys_flammable_items() {
+        // floor doesn't burn, but the kindling lying on it does
+        let mut map = clamped("floor", 2);
+        map.get_tile_at_mut(IVec2::ZERO).unwrap().contained_items.add_item(Box::new(Kindling(3)));
+        let mut rng = GenRng::new(7);
+        assert!(!ignite(&mut map, IVec2::new(1, 0)));
+        assert!(ignite(&mut map, IVec2::ZERO));
+        let step = spread_fire(&mut map, &mut rng);
+        assert_eq!(step.destroyed_items, vec![3]);
+        assert_eq!(map.get_tile_at(IVec2::ZERO).unwrap().contained_items.items().count(), 0);
+    }
+
+    #[test]
+    fn tiles_under_liquid_dont_burn() {
+        let mut map = clamped("grass", 3);
+        map.get_tile_at_mut(IVec2::new(1, 0)).unwrap().liquid = Some(Liquid::new(LiquidKind::Water, 2));
+        assert!(!ignite(&mut map, IVec2::new(1, 0)));
+        assert!(ignite(&mut map, IVec2::ZERO));
+        let mut rng = GenRng::new(7);
+        for _ in 0..BURN_TURNS * 2 {
+            spread_fire(&mut map, &mut rng);
+            assert_eq!(map.get_tile_at(IVec2::new(1, 0)).unwrap().fire, 0);
+        }
+        assert_eq!(map.get_tile_at(IVec2::new(1, 0)).unwrap().tile_type, "grass");
+        assert_eq!(map.get_tile_at(IVec2::new(2, 0)).unwrap().fire, 0, "fire jumped the water");
+    }
+
+    #[test]
+    fn ignite_needs_something_to_burn() {
+        let mut map = clamped("floor", 2);
+        map.blit_tile_type_at(IVec2::new(1, 0), "wall");
+        assert!(!ignite(&mut map, IVec2::ZERO));
+        assert!(!ignite(&mut map, IVec2::new(1, 0)));
+        assert!(map.tiles().all(|t| t.fire == 0));
+
+        let mut map = clamped("grass", 1);
+        assert!(ignite(&mut map, IVec2::ZERO));
+        assert!(!ignite(&mut map, IVec2::ZERO), "lit twice");
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::HashSet;
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::Map;
+
+/// What a field of view algorithm needs to know about the map.
+pub trait FOVQuery {
+    /// The map position an unwrapped position refers to, or None if it is off the map.
+    fn resolve(&self, x: i32, y: i32) -> Option<IVec2>;
+    fn is_blocked(&self, x: i32, y: i32) -> bool;
+    fn radius(&self, x: f32, y: f32) -> f32;
+}
+
+impl FOVQuery for Map {
+    fn resolve(&self, x: i32, y: i32) -> Option<IVec2> {
+        self.resolve_position(IVec2::new(x, y))
+    }
+
+    fn is_blocked(&self, x: i32, y: i32) -> bool {
+        // tiles in chunks that aren't loaded block sight
+        self.get_tile_at(IVec2::new(x, y)).is_none_or(|tile| tile.is_opaque() || tile.gas_opacity() >= 1.0)
+    }
+
+    fn radius(&self, x: f32, y: f32) -> f32 {
+        (x * x + y * y).sqrt()
+    }
+}
+
+/// Whether sight stops at the unwrapped position; positions off the map do.
+fn blocks_at(query: &dyn FOVQuery, position: IVec2) -> bool {
+    query.resolve(position.x, position.y).is_none_or(|p| query.is_blocked(p.x, p.y))
+}
+
+/// Which tiles count as seen from a position. Actors and lights each pick one.
+#[allow(dead_code)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FovAlgorithm {
+    /// Fast, but not symmetric: a tile may be seen from a spot that can't be seen from it.
+    #[default]
+    RecursiveShadowcasting,
+    /// Sees a floor tile exactly when that tile would see back; walls are lit as soon as any
+    /// part of them is.
+    SymmetricShadowcasting,
+    /// Sees a tile if an unblocked line runs from anywhere in the viewer's tile to anywhere in
+    /// it. Symmetric, and sees the most of the room around pillars and along walls.
+    Permissive,
+    /// Casts a ray to every tile on the edge of the radius and sees along it up to the first
+    /// blocking tile. Cheap, but leaves gaps far from the viewer.
+    Raycasting,
+}
+
+impl FovAlgorithm {
+    /// The map positions seen from `origin` within `radius`, each once, origin included.
+    pub fn compute(self, origin: IVec2, radius: f32, query: &dyn FOVQuery) -> Vec<IVec2> {
+        let found = match self {
+            FovAlgorithm::RecursiveShadowcasting => {
+                let mut fov = FOVCalc::start_new(origin.x, origin.y, radius, query);
+                fov.calculate();
+                fov.results
+            }
+            FovAlgorithm::SymmetricShadowcasting => symmetric_shadowcasting(origin, radius, query),
+            FovAlgorithm::Permissive => permissive(origin, radius, query),
+            FovAlgorithm::Raycasting => raycasting(origin, radius, query),
+        };
+        // octant edges are visited twice, and on wrapping maps several positions reach one tile
+        let mut seen = HashSet::new();
+        found.into_iter().filter(|p| seen.insert(*p)).collect()
+    }
+}
+
+struct FOVCalc<'a> {
+    pub startx: i32,
+    pub starty: i32,
+    pub radius: f32,
+    pub map_query: &'a dyn FOVQuery,
+    pub results: Vec<IVec2>,
+}
+
+impl<'a> FOVCalc<'a> {
+    pub fn start_new(startx: i32, starty: i32, radius: f32, map_query: &'a dyn FOVQuery) -> Self {
+        FOVCalc {
+            startx,
+            starty,
+            radius,
+            map_query,
+            results: Vec::new(),
+        }
+    }
+
+    pub fn calculate(&mut self) {
+        self.results = Vec::new();
+        let diagonals: [IVec2; 4] = [
+            IVec2::new(-1, -1),
+            IVec2::new(-1, 1),
+            IVec2::new(1, -1),
+            IVec2::new(1, 1),
+        ];
+        if let Some(start) = self.map_query.resolve(self.startx, self.starty) {
+            self.results.push(start);
+        }
+        for d in diagonals {
+            self.castlight(1, 1.0, 0.0, 0, d.x, d.y, 0);
+            self.castlight(1, 1.0, 0.0, d.x, 0, 0, d.y);
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn castlight(&mut self, row: i32, mut start: f32, end: f32, xx: i32, xy: i32, yx: i32, yy: i32) {
+        let radius = self.radius.ceil() as i32;
+
+        let mut newstart: f32 = 0.0;
+        if start < end {
+            return;
+        }
+        let mut blocked = false;
+        let mut distance = row;
+        while distance <= radius && !blocked {
+            let delta_y = -distance;
+            for delta_x in -distance..=0 {
+                let current_x = self.startx + xx * delta_x + xy * delta_y;
+                let current_y = self.starty + yx * delta_x + yy * delta_y;
+                let left_slope = (delta_x as f32 - 0.5) / (delta_y as f32 + 0.5);
+                let right_slope = (delta_x as f32 + 0.5) / (delta_y as f32 - 0.5);
+
+                // positions across a wrapping edge resolve to the tiles on the other side
+                let Some(pos) = self.map_query.resolve(current_x, current_y) else {
+                    continue;
+                };
+                if start < right_slope {
+                    continue;
+                } else if end > left_slope {
+                    break;
+                }
+
+                if self.map_query.radius(delta_x as f32, delta_y as f32) <= self.radius {
+                    self.results.push(pos);
+                }
+
+                if blocked {
+                    if self.map_query.is_blocked(pos.x, pos.y) {
+                        newstart = right_slope;
+                        continue;
+                    } else {
+                        blocked = false;
+                        start = newstart;
+                    }
+                } else {
+                    if self.map_query.is_blocked(pos.x, pos.y) && distance < radius {
+                        blocked = true;
+                        self.castlight(row + 1, start, left_slope, xx, xy, yx, yy);
+                        newstart = right_slope;
+                    }
+                }
+            }
+            distance += 1;
+        }
+    }
+}
+
+/// A slope kept as a fraction so that the symmetry checks are exact.
+#[derive(Copy, Clone)]
+struct Slope {
+    num: i32,
+    den: i32,
+}
+
+impl Slope {
+    fn new(num: i32, den: i32) -> Self {
+        Slope { num, den }
+    }
+
+    /// The slope through the left edge of the tile at `col` in the row at `depth`.
+    fn of_tile(depth: i32, col: i32) -> Self {
+        Slope::new(2 * col - 1, 2 * depth)
+    }
+}
+
+/// A row of a quadrant, between two slopes, `depth` tiles away from the viewer.
+struct Row {
+    depth: i32,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    /// The first column whose centre is at or past `start`, rounding ties towards the end.
+    fn min_col(&self) -> i32 {
+        (2 * self.depth * self.start.num + self.start.den).div_euclid(2 * self.start.den)
+    }
+
+    /// The last column whose centre is at or before `end`, rounding ties towards the start.
+    fn max_col(&self) -> i32 {
+        -(self.end.den - 2 * self.depth * self.end.num).div_euclid(2 * self.end.den)
+    }
+
+    /// Whether the tile's centre lies between the row's slopes, which is what makes seeing it
+    /// symmetric.
+    fn is_symmetric(&self, col: i32) -> bool {
+        col * self.start.den >= self.depth * self.start.num && col * self.end.den <= self.depth * self.end.num
+    }
+
+    fn next(&self) -> Row {
+        Row { depth: self.depth + 1, start: self.start, end: self.end }
+    }
+}
+
+fn symmetric_shadowcasting(origin: IVec2, radius: f32, query: &dyn FOVQuery) -> Vec<IVec2> {
+    let mut results: Vec<IVec2> = query.resolve(origin.x, origin.y).into_iter().collect();
+    let max_depth = radius.ceil() as i32;
+    // each quadrant turns (depth, column) into an offset from the viewer
+    let quadrants: [fn(i32, i32) -> IVec2; 4] = [
+        |depth, col| IVec2::new(col, depth),
+        |depth, col| IVec2::new(col, -depth),
+        |depth, col| IVec2::new(depth, col),
+        |depth, col| IVec2::new(-depth, col),
+    ];
+    for transform in quadrants {
+        let mut rows = vec![Row { depth: 1, start: Slope::new(-1, 1), end: Slope::new(1, 1) }];
+        while let Some(mut row) = rows.pop() {
+            if row.depth > max_depth {
+                continue;
+            }
+            let mut prev_blocked: Option<bool> = None;
+            for col in row.min_col()..=row.max_col() {
+                let offset = transform(row.depth, col);
+                let position = origin + offset;
+                let blocked = blocks_at(query, position);
+                let in_radius = query.radius(offset.x as f32, offset.y as f32) <= radius;
+                if in_radius && (blocked || row.is_symmetric(col)) {
+                    if let Some(p) = query.resolve(position.x, position.y) {
+                        results.push(p);
+                    }
+                }
+                if prev_blocked == Some(true) && !blocked {
+                    row.start = Slope::of_tile(row.depth, col);
+                }
+                if prev_blocked == Some(false) && blocked {
+                    let mut next = row.next();
+                    next.end = Slope::of_tile(row.depth, col);
+                    rows.push(next);
+                }
+                prev_blocked = Some(blocked);
+            }
+            if prev_blocked == Some(false) {
+                rows.push(row.next());
+            }
+        }
+    }
+    results
+}
+
+/// A line between two grid corners of a quadrant, from the viewer's tile outwards.
+#[derive(Copy, Clone)]
+struct SightLine {
+    near: IVec2,
+    far: IVec2,
+}
+
+impl SightLine {
+    /// Positive if the line passes below `p`, negative if above and zero if through it.
+    fn relative_slope(&self, p: IVec2) -> i32 {
+        (self.far.y - self.near.y) * (self.far.x - p.x) - (self.far.y - p.y) * (self.far.x - self.near.x)
+    }
+
+    fn is_below(&self, p: IVec2) -> bool {
+        self.relative_slope(p) > 0
+    }
+
+    fn is_below_or_contains(&self, p: IVec2) -> bool {
+        self.relative_slope(p) >= 0
+    }
+
+    fn is_above(&self, p: IVec2) -> bool {
+        self.relative_slope(p) < 0
+    }
+
+    fn is_above_or_contains(&self, p: IVec2) -> bool {
+        self.relative_slope(p) <= 0
+    }
+
+    fn contains(&self, p: IVec2) -> bool {
+        self.relative_slope(p) == 0
+    }
+
+    fn is_collinear(&self, other: &SightLine) -> bool {
+        self.contains(other.near) && self.contains(other.far)
+    }
+}
+
+/// A wedge of a quadrant that can still be seen into, between its shallow and steep line. The
+/// bumps are the corners of blocking tiles the lines were bent around, oldest first.
+#[derive(Clone)]
+struct View {
+    shallow: SightLine,
+    steep: SightLine,
+    shallow_bumps: Vec<IVec2>,
+    steep_bumps: Vec<IVec2>,
+}
+
+impl View {
+    /// Moves the shallow line up to pass above `p`, without letting it cut through a steep bump.
+    fn add_shallow_bump(&mut self, p: IVec2) {
+        self.shallow.far = p;
+        self.shallow_bumps.push(p);
+        for bump in self.steep_bumps.iter().rev() {
+            if self.shallow.is_above(*bump) {
+                self.shallow.near = *bump;
+            }
+        }
+    }
+
+    /// Moves the steep line down to pass below `p`, without letting it cut through a shallow bump.
+    fn add_steep_bump(&mut self, p: IVec2) {
+        self.steep.far = p;
+        self.steep_bumps.push(p);
+        for bump in self.shallow_bumps.iter().rev() {
+            if self.steep.is_below(*bump) {
+                self.steep.near = *bump;
+            }
+        }
+    }
+
+    /// Whether the view has shrunk to a line along the edge of the viewer's tile, which sees nothing.
+    fn is_closed(&self) -> bool {
+        self.shallow.is_collinear(&self.steep)
+            && (self.shallow.contains(IVec2::new(0, 1)) || self.shallow.contains(IVec2::new(1, 0)))
+    }
+}
+
+/// Precise permissive field of view: a tile is seen if any unblocked line runs from some point
+/// of the viewer's tile to some point of it. Each quadrant is walked diagonal by diagonal while
+/// the views that are still open are narrowed around the blocking tiles.
+fn permissive(origin: IVec2, radius: f32, query: &dyn FOVQuery) -> Vec<IVec2> {
+    let mut results: Vec<IVec2> = query.resolve(origin.x, origin.y).into_iter().collect();
+    let extent = radius.ceil() as i32;
+    for quadrant in [IVec2::new(1, 1), IVec2::new(-1, 1), IVec2::new(-1, -1), IVec2::new(1, -1)] {
+        let mut views = vec![View {
+            shallow: SightLine { near: IVec2::new(0, 1), far: IVec2::new(extent, 0) },
+            steep: SightLine { near: IVec2::new(1, 0), far: IVec2::new(0, extent) },
+            shallow_bumps: Vec::new(),
+            steep_bumps: Vec::new(),
+        }];
+        for i in 1..=2 * extent {
+            if views.is_empty() {
+                break;
+            }
+            let mut view_index = 0;
+            for j in (i - extent).max(0)..=i.min(extent) {
+                if view_index >= views.len() {
+                    break;
+                }
+                let square = IVec2::new(i - j, j);
+                let top_left = IVec2::new(square.x, square.y + 1);
+                let bottom_right = IVec2::new(square.x + 1, square.y);
+                // skip the views that pass below the square entirely
+                while view_index < views.len() && views[view_index].steep.is_below_or_contains(bottom_right) {
+                    view_index += 1;
+                }
+                if view_index >= views.len() || views[view_index].shallow.is_above_or_contains(top_left) {
+                    continue;
+                }
+
+                let offset = square * quadrant;
+                let position = origin + offset;
+                if query.radius(offset.x as f32, offset.y as f32) <= radius {
+                    if let Some(p) = query.resolve(position.x, position.y) {
+                        results.push(p);
+                    }
+                }
+                if !blocks_at(query, position) {
+                    continue;
+                }
+
+                let view = &mut views[view_index];
+                let shallow_crosses = view.shallow.is_above(bottom_right);
+                let steep_crosses = view.steep.is_below(top_left);
+                if shallow_crosses && steep_crosses {
+                    // the square fills the whole view
+                    views.remove(view_index);
+                } else if shallow_crosses {
+                    view.add_shallow_bump(top_left);
+                    if view.is_closed() {
+                        views.remove(view_index);
+                    }
+                } else if steep_crosses {
+                    view.add_steep_bump(bottom_right);
+                    if view.is_closed() {
+                        views.remove(view_index);
+                    }
+                } else {
+                    // the square splits the view in a part below and a part above it
+                    let mut below = view.clone();
+                    below.add_steep_bump(bottom_right);
+                    view.add_shallow_bump(top_left);
+                    let above_closed = view.is_closed();
+                    if above_closed {
+                        views.remove(view_index);
+                    }
+                    if !below.is_closed() {
+                        views.insert(view_index, below);
+                        view_index += 1;
+                    }
+                }
+            }
+        }
+    }
+    results
+}
+
+fn raycasting(origin: IVec2, radius: f32, query: &dyn FOVQuery) -> Vec<IVec2> {
+    let mut results: Vec<IVec2> = query.resolve(origin.x, origin.y).into_iter().collect();
+    let reach = radius.ceil() as i32;
+    let mut edge = Vec::new();
+    for i in -reach..=reach {
+        edge.extend([IVec2::new(i, -reach), IVec2::new(i, reach), IVec2::new(-reach, i), IVec2::new(reach, i)]);
+    }
+    for end in edge {
+        for step in 1..=reach {
+            let offset = (end.as_vec2() * (step as f32 / reach as f32)).round().as_ivec2();
+            if query.radius(offset.x as f32, offset.y as f32) > radius {
+                break;
+            }
+            let position = origin + offset;
+            let Some(p) = query.resolve(position.x, position.y) else {
+                break;
+            };
+            results.push(p);
+            if query.is_blocked(p.x, p.y) {
+                break;
+            }
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use crate::stibag::map::{Map, WrapMode};
+    use crate::stibag::map::fov::FovAlgorithm;
+
+    fn pillars() -> Map {
+        let mut map = Map::new_filled(IVec2::new(15, 11), "floor");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        for p in [IVec2::new(4, 3), IVec2::new(9, 2), IVec2::new(6, 6), IVec2::new(7, 6), IVec2::new(11, 8), IVec2::new(2, 8)] {
+            map.blit_tile_type_at(p, "wall");
+        }
+        map
+    }
+
+    #[test]
+    fn permissive_is_symmetric() {
+        let map = pillars();
+        let floors: Vec<IVec2> = map.tiles().filter(|t| !t.is_opaque()).map(|t| t.position).collect();
+        for from in floors.iter() {
+            let seen = map.calc_vision_with(*from, 8.0, FovAlgorithm::Permissive);
+            for to in floors.iter() {
+                if map.wrapped_distance(*from, *to) > 8.0 {
+                    continue;
+                }
+                let back = map.calc_vision_with(*to, 8.0, FovAlgorithm::Permissive);
+                assert_eq!(seen.contains(to), back.contains(from), "{} and {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn permissive_sees_past_corners() {
+        let mut map = Map::new_filled(IVec2::new(7, 7), "floor");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        // a wall with a gap one tile wide, seen at a steep angle
+        for x in 0..7 {
+            if x != 3 {
+                map.blit_tile_type_at(IVec2::new(x, 3), "wall");
+            }
+        }
+        let seen = map.calc_vision_with(IVec2::new(3, 5), 10.0, FovAlgorithm::Permissive);
+        // lines from the corners of the viewer's tile through the gap fan out to x = 2..5 at the top
+        for p in [IVec2::new(3, 2), IVec2::new(2, 0), IVec2::new(4, 0), IVec2::new(2, 3), IVec2::new(4, 3)] {
+            assert!(seen.contains(&p), "{} not seen", p);
+        }
+        for p in [IVec2::new(0, 0), IVec2::new(6, 0), IVec2::new(0, 2), IVec2::new(6, 2)] {
+            assert!(!seen.contains(&p), "{} seen", p);
+        }
+        // the gap is out of line for a viewer far off to the side
+        let sealed = map.calc_vision_with(IVec2::new(0, 4), 10.0, FovAlgorithm::Permissive);
+        assert!(!sealed.iter().any(|p| p.y < 3));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::Map;
+
+/// Share of its gas a tile passes on to its open neighbours each step.
+const DIFFUSION_RATE: f32 = 0.4;
+/// Gas thinner than this is gone.
+const MIN_CONCENTRATION: f32 = 0.02;
+/// Gas whose opacity is below this is not drawn.
+const VISIBLE_OPACITY: f32 = 0.2;
+/// Sight and light go by the opacity in steps of a quarter, so gas that only thickens or thins
+/// a little doesn't make them be worked out again.
+const OPACITY_STEPS: f32 = 4.0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum GasKind {
+    Smoke,
+    Poison,
+    Fog,
+}
+
+impl GasKind {
+    /// The kind with the given lower-case name, as scripts write it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "smoke" => Some(GasKind::Smoke),
+            "poison" => Some(GasKind::Poison),
+            "fog" => Some(GasKind::Fog),
+            _ => None,
+        }
+    }
+
+    /// The tile visual drawn in place of the ground where the gas is thick enough to see.
+    pub fn visual(self) -> &'static str {
+        match self {
+            GasKind::Smoke => "smoke",
+            GasKind::Poison => "poison_gas",
+            GasKind::Fog => "fog",
+        }
+    }
+
+    /// Share of the gas lost each step.
+    pub fn decay(self) -> f32 {
+        match self {
+            GasKind::Smoke => 0.05,
+            GasKind::Poison => 0.03,
+            GasKind::Fog => 0.01,
+        }
+    }
+
+    /// How much sight one unit of concentration takes away; at an opacity of 1 sight is blocked.
+    pub fn density(self) -> f32 {
+        match self {
+            GasKind::Smoke => 1.5,
+            GasKind::Poison => 0.4,
+            GasKind::Fog => 1.0,
+        }
+    }
+
+    /// Damage dealt each tick to an actor standing in the gas.
+    pub fn damage(self, concentration: f32) -> i32 {
+        match self {
+            GasKind::Smoke if concentration >= 0.5 => 1,
+            GasKind::Poison if concentration >= 0.1 => 1 + (concentration * 2.0) as i32,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gas {
+    pub kind: GasKind,
+    pub concentration: f32,
+}
+
+impl Gas {
+    /// How much of the sight through the tile the gas takes away, from 0 to 1.
+    pub fn opacity(&self) -> f32 {
+        (self.concentration * self.kind.density()).min(1.0)
+    }
+
+    /// The opacity rounded down to a whole step; this is what sight and light go by.
+    pub fn sight_opacity(&self) -> f32 {
+        (self.opacity() * OPACITY_STEPS).floor() / OPACITY_STEPS
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.opacity() >= VISIBLE_OPACITY
+    }
+}
+
+/// Gas spreads through tiles that don't block sight, and only into tiles that are free of other gases.
+fn is_open(map: &Map, position: IVec2, kind: GasKind) -> bool {
+    map.get_tile_at(position).is_some_and(|t| !t.is_opaque() && t.gas.is_none_or(|g| g.kind == kind))
+}
+
+/// One step of gas over the loaded part of the map: every tile hands part of its gas to its
+/// open neighbours, then all gas thins out by its kind's decay. Returns the positions whose gas
+/// changed.
+pub fn diffuse_gas(map: &mut Map) -> Vec<IVec2> {
+    let mut clouds: Vec<(IVec2, f32, GasKind)> = map.tiles()
+        .filter_map(|t| t.gas.map(|g| (t.position, g.concentration, g.kind)))
+        .collect();
+    if clouds.is_empty() {
+        return Vec::new();
+    }
+    // float sums depend on the order they are added in
+    clouds.sort_by_key(|(p, _, _)| (p.y, p.x));
+
+    let mut deltas: HashMap<IVec2, (GasKind, f32)> = HashMap::new();
+    for (position, concentration, kind) in clouds.iter().copied() {
+        let share = concentration * DIFFUSION_RATE / map.grid.orthogonal_offsets(position).len() as f32;
+        for n in map.orthogonal_neighbours(position) {
+            if !is_open(map, n, kind) || deltas.get(&n).is_some_and(|(k, _)| *k != kind) {
+                continue;
+            }
+            deltas.entry(n).or_insert((kind, 0.0)).1 += share;
+            deltas.entry(position).or_insert((kind, 0.0)).1 -= share;
+        }
+    }
+
+    let mut changed: Vec<IVec2> = clouds.iter().map(|(p, _, _)| *p).collect();
+    for (position, (kind, delta)) in deltas {
+        let Some(tile) = map.get_tile_at_mut(position) else {
+            continue;
+        };
+        match tile.gas.as_mut() {
+            Some(gas) => gas.concentration += delta,
+            None => {
+                tile.gas = Some(Gas { kind, concentration: delta });
+                changed.push(position);
+            }
+        }
+    }
+    for position in changed.iter() {
+        let Some(tile) = map.get_tile_at_mut(*position) else {
+            continue;
+        };
+        if let Some(gas) = tile.gas.as_mut() {
+            gas.concentration *= 1.0 - gas.kind.decay();
+            if gas.concentration < MIN_CONCENTRATION {
+                tile.gas = None;
+            }
+        }
+    }
+    changed.sort_by_key(|p| (p.y, p.x));
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use crate::stibag::map::{Map, WrapMode};
+    use crate::stibag::map::gas::{diffuse_gas, Gas, GasKind};
+
+    fn total(map: &Map) -> f32 {
+        map.tiles().filter_map(|t| t.gas).map(|g| g.concentration).sum()
+    }
+
+    #[test]
+    fn diffusion_only_loses_decay() {
+        let mut map = Map::new_filled(IVec2::new(9, 9), "floor");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        map.blit_tile_type_at(IVec2::new(5, 4), "wall");
+        // thick enough that none of it thins out below the minimum within three steps
+        map.get_tile_at_mut(IVec2::new(4, 4)).unwrap().gas = Some(Gas { kind: GasKind::Fog, concentration: 100.0 });
+
+        let mut expected = 100.0;
+        for step in 1..=3 {
+            let changed = diffuse_gas(&mut map);
+            expected *= 1.0 - GasKind::Fog.decay();
+            assert!((total(&map) - expected).abs() < 1e-3, "step {}: {} instead of {}", step, total(&map), expected);
+            assert!(changed.contains(&IVec2::new(4, 4)));
+        }
+        assert!(map.get_tile_at(IVec2::new(5, 4)).unwrap().gas.is_none(), "gas went into a wall");
+        assert!(map.get_tile_at(IVec2::new(3, 4)).unwrap().gas.is_some());
+    }
+}
@@ -0,0 +1,206 @@
+use bevy::math::IVec2;
+use crate::stibag::map::{ActorPlacement, Map, MapLayer, WrapMode};
+use crate::stibag::map::gen::{GenRng, GeneratedMap, Rect};
+use crate::stibag::map::prefab::{place_prefab, Prefab};
+use crate::stibag::map::region::MapRegion;
+
+pub struct BspSettings {
+    /// Leaves are not split further once either side would drop below this.
+    pub min_leaf_size: i32,
+    pub min_room_size: i32,
+    pub max_depth: u32,
+    pub max_monsters_per_room: i32,
+    pub monster_template: String,
+    /// Prefabs stamped into solid rock between the rooms, picked at random.
+    pub vaults: Vec<Prefab>,
+    pub max_vaults: u32,
+}
+
+impl Default for BspSettings {
+    fn default() -> Self {
+        BspSettings {
+            min_leaf_size: 8,
+            min_room_size: 4,
+            max_depth: 6,
+            max_monsters_per_room: 2,
+            monster_template: "monster".to_string(),
+            vaults: Prefab::builtin_vaults(),
+            max_vaults: 2,
+        }
+    }
+}
+
+enum BspNode {
+    Leaf { room: Rect },
+    Split { left: Box<BspNode>, right: Box<BspNode> },
+}
+
+impl BspNode {
+    fn rooms(&self, out: &mut Vec<Rect>) {
+        match self {
+            BspNode::Leaf { room } => out.push(*room),
+            BspNode::Split { left, right } => {
+                left.rooms(out);
+                right.rooms(out);
+            }
+        }
+    }
+
+    /// Any room in this subtree, used as the anchor when connecting it to its sibling.
+    fn any_room(&self, rng: &mut GenRng) -> Rect {
+        let mut rooms = Vec::new();
+        self.rooms(&mut rooms);
+        rooms[rng.range(0, rooms.len() as i32) as usize]
+    }
+}
+
+fn split(area: Rect, depth: u32, settings: &BspSettings, rng: &mut GenRng) -> BspNode {
+    let can_split_x = area.size.x >= settings.min_leaf_size * 2;
+    let can_split_y = area.size.y >= settings.min_leaf_size * 2;
+    if depth >= settings.max_depth || (!can_split_x && !can_split_y) {
+        return BspNode::Leaf { room: place_room(area, settings, rng) };
+    }
+    // split across the longer side, or randomly if the area is roughly square
+    let split_x = match (can_split_x, can_split_y) {
+        (true, false) => true,
+        (false, true) => false,
+        _ if area.size.x as f32 > area.size.y as f32 * 1.25 => true,
+        _ if area.size.y as f32 > area.size.x as f32 * 1.25 => false,
+        _ => rng.chance(0.5),
+    };
+    let (a, b) = if split_x {
+        let at = rng.range(settings.min_leaf_size, area.size.x - settings.min_leaf_size + 1);
+        (Rect::new(area.pos.x, area.pos.y, at, area.size.y),
+         Rect::new(area.pos.x + at, area.pos.y, area.size.x - at, area.size.y))
+    } else {
+        let at = rng.range(settings.min_leaf_size, area.size.y - settings.min_leaf_size + 1);
+        (Rect::new(area.pos.x, area.pos.y, area.size.x, at),
+         Rect::new(area.pos.x, area.pos.y + at, area.size.x, area.size.y - at))
+    };
+    BspNode::Split {
+        left: Box::new(split(a, depth + 1, settings, rng)),
+        right: Box::new(split(b, depth + 1, settings, rng)),
+    }
+}
+
+/// A room somewhere inside a leaf, keeping a one tile wall margin to the leaf's edges.
+fn place_room(leaf: Rect, settings: &BspSettings, rng: &mut GenRng) -> Rect {
+    let max = leaf.size - IVec2::new(2, 2);
+    let w = rng.range(settings.min_room_size.min(max.x), max.x + 1);
+    let h = rng.range(settings.min_room_size.min(max.y), max.y + 1);
+    let x = leaf.pos.x + 1 + rng.range(0, max.x - w + 1);
+    let y = leaf.pos.y + 1 + rng.range(0, max.y - h + 1);
+    Rect::new(x, y, w, h)
+}
+
+fn connect(node: &BspNode, corridors: &mut Vec<IVec2>, rng: &mut GenRng) {
+    if let BspNode::Split { left, right } = node {
+        connect(left, corridors, rng);
+        connect(right, corridors, rng);
+        let from = left.any_room(rng).center();
+        let to = right.any_room(rng).center();
+        // L-shaped corridor, randomly going horizontal or vertical first
+        let corner = if rng.chance(0.5) { IVec2::new(to.x, from.y) } else { IVec2::new(from.x, to.y) };
+        for (a, b) in [(from, corner), (corner, to)] {
+            let step = (b - a).signum();
+            let mut p = a;
+            loop {
+                corridors.push(p);
+                if p == b {
+                    break;
+                }
+                p += step;
+            }
+        }
+    }
+}
+
+/// Doors go where a corridor passes through the wall ring around a room, between two walls.
+fn place_doors(map: &mut Map, rooms: &[Rect]) {
+    for room in rooms {
+        let ring = Rect::new(room.pos.x - 1, room.pos.y - 1, room.size.x + 2, room.size.y + 2);
+        for p in ring.positions() {
+            if room.contains(p) || rooms.iter().any(|r| r.contains(p)) {
+                continue;
+            }
+            let on_vertical_side = p.x == ring.pos.x || p.x == ring.pos.x + ring.size.x - 1;
+            let on_horizontal_side = p.y == ring.pos.y || p.y == ring.pos.y + ring.size.y - 1;
+            if on_vertical_side && on_horizontal_side {
+                continue;
+            }
+            let is_wall = |m: &Map, q: IVec2| m.get_tile_at(q).is_none_or(|t| !t.is_passable());
+            if is_wall(map, p) {
+                continue;
+            }
+            let (side_a, side_b) = if on_vertical_side {
+                (p + IVec2::new(0, -1), p + IVec2::new(0, 1))
+            } else {
+                (p + IVec2::new(-1, 0), p + IVec2::new(1, 0))
+            };
+            if is_wall(map, side_a) && is_wall(map, side_b) && map.get_tile_at(p).unwrap().feature.is_none() {
+                map.blit_layer_type_at(MapLayer::Feature, p, "door");
+            }
+        }
+    }
+}
+
+/// Rooms and corridors: the map is split recursively, each leaf gets a room and sibling subtrees
+/// are joined by corridors, so every room is reachable. The player starts in the first room and
+/// every other room may get monsters. Vaults are stamped into leftover rock and dug out to the
+/// nearest floor. Rooms become regions named room_1, room_2, ...
+pub fn generate(dimensions: IVec2, settings: &BspSettings, seed: u64) -> GeneratedMap {
+    let mut rng = GenRng::new(seed);
+    let mut map = Map::new_filled(dimensions, "wall");
+    map.horizontal_wrap = WrapMode::Clamp;
+    map.vertical_wrap = WrapMode::Clamp;
+
+    let tree = split(Rect::new(0, 0, dimensions.x, dimensions.y), 0, settings, &mut rng);
+    let mut rooms = Vec::new();
+    tree.rooms(&mut rooms);
+    for room in rooms.iter() {
+        for p in room.positions() {
+            map.blit_tile_type_at(p, "floor");
+        }
+    }
+    let mut corridors = Vec::new();
+    connect(&tree, &mut corridors, &mut rng);
+    for p in corridors {
+        map.blit_tile_type_at(p, "floor");
+    }
+    place_doors(&mut map, &rooms);
+    for (i, room) in rooms.iter().enumerate() {
+        let region = MapRegion::new_rect(format!("room_{}", i + 1), room.pos, room.size).with_property("kind", "room");
+        map.add_region(if i == 0 { region.with_property("no_spawn", "true") } else { region });
+    }
+
+    let player_spawn = rooms[0].center();
+    let mut actors = Vec::new();
+    for room in rooms.iter() {
+        if !map.allows_spawn(room.pos) {
+            continue;
+        }
+        for _ in 0..rng.range(0, settings.max_monsters_per_room + 1) {
+            let position = room.pos + IVec2::new(rng.range(0, room.size.x), rng.range(0, room.size.y));
+            if !actors.iter().any(|a: &ActorPlacement| a.position == position) {
+                actors.push(ActorPlacement { template: settings.monster_template.clone(), position });
+            }
+        }
+    }
+
+    let mut generated = GeneratedMap {
+        map,
+        player_spawn,
+        actors,
+        items: Vec::new(),
+        lights: Vec::new(),
+    };
+    if !settings.vaults.is_empty() {
+        let mut reserved = rooms.clone();
+        for _ in 0..settings.max_vaults {
+            let vault = &settings.vaults[rng.range(0, settings.vaults.len() as i32) as usize];
+            place_prefab(&mut generated, vault, &mut reserved, &mut rng, "floor",
+                         |t| t.tile_type == "wall" && t.feature.is_none());
+        }
+    }
+    generated
+}
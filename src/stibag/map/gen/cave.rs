@@ -0,0 +1,212 @@
+use bevy::math::IVec2;
+use crate::stibag::map::{Map, WrapMode};
+use crate::stibag::map::gen::{flood_regions, GenRng, GeneratedMap};
+use crate::stibag::map::prefab::{place_prefab, Prefab};
+use crate::stibag::map::region::MapRegion;
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PocketHandling {
+    /// Dig tunnels from every disconnected pocket to the cave around the entry point.
+    Tunnel,
+    /// Turn every pocket that isn't connected to the entry point back into rock.
+    Fill,
+}
+
+pub struct CaveSettings {
+    pub initial_wall_chance: f32,
+    pub smoothing_passes: u32,
+    /// Pockets smaller than this are filled in even when tunnelling.
+    pub min_pocket_size: usize,
+    pub pocket_handling: PocketHandling,
+    /// Prefabs stamped into solid rock and tunnelled to the nearest cave floor.
+    pub vaults: Vec<Prefab>,
+    pub max_vaults: u32,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        CaveSettings {
+            initial_wall_chance: 0.45,
+            smoothing_passes: 5,
+            min_pocket_size: 6,
+            pocket_handling: PocketHandling::Tunnel,
+            vaults: Prefab::builtin_vaults(),
+            max_vaults: 1,
+        }
+    }
+}
+
+struct CaveGrid {
+    size: IVec2,
+    walls: Vec<bool>,
+}
+
+impl CaveGrid {
+    fn idx(&self, p: IVec2) -> usize {
+        (p.y * self.size.x + p.x) as usize
+    }
+
+    fn in_bounds(&self, p: IVec2) -> bool {
+        p.x >= 0 && p.y >= 0 && p.x < self.size.x && p.y < self.size.y
+    }
+
+    fn is_border(&self, p: IVec2) -> bool {
+        p.x == 0 || p.y == 0 || p.x == self.size.x - 1 || p.y == self.size.y - 1
+    }
+
+    /// Outside the grid counts as wall so caves close up at the edges.
+    fn is_wall(&self, p: IVec2) -> bool {
+        !self.in_bounds(p) || self.walls[self.idx(p)]
+    }
+
+    fn set_wall(&mut self, p: IVec2, wall: bool) {
+        if self.in_bounds(p) && !self.is_border(p) {
+            let i = self.idx(p);
+            self.walls[i] = wall;
+        }
+    }
+
+    fn wall_neighbours(&self, p: IVec2) -> usize {
+        let mut n = 0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if (dx != 0 || dy != 0) && self.is_wall(p + IVec2::new(dx, dy)) {
+                    n += 1;
+                }
+            }
+        }
+        n
+    }
+
+    fn smooth(&mut self) {
+        let mut next = self.walls.clone();
+        for y in 0..self.size.y {
+            for x in 0..self.size.x {
+                let p = IVec2::new(x, y);
+                let n = self.wall_neighbours(p);
+                next[self.idx(p)] = if self.is_border(p) || n >= 5 {
+                    true
+                } else if n <= 3 {
+                    false
+                } else {
+                    self.walls[self.idx(p)]
+                };
+            }
+        }
+        self.walls = next;
+    }
+
+    fn dig_tunnel(&mut self, from: IVec2, to: IVec2) {
+        let mut p = from;
+        while p != to {
+            // move along the longer axis first so tunnels stay roughly straight
+            let d = to - p;
+            if d.x.abs() >= d.y.abs() {
+                p.x += d.x.signum();
+            } else {
+                p.y += d.y.signum();
+            }
+            self.set_wall(p, false);
+        }
+    }
+}
+
+/// Closest pair of positions between two sets, by squared distance.
+fn closest_pair(a: &[IVec2], b: &[IVec2]) -> (IVec2, IVec2) {
+    let mut best = (a[0], b[0]);
+    let mut best_d = i32::MAX;
+    for pa in a {
+        for pb in b {
+            let d = pa.distance_squared(*pb);
+            if d < best_d {
+                best_d = d;
+                best = (*pa, *pb);
+            }
+        }
+    }
+    best
+}
+
+/// A natural looking cave from cellular automata smoothing of random noise. Afterwards the cave
+/// is made fully connected: every walkable tile can be reached from the player spawn.
+pub fn generate(dimensions: IVec2, settings: &CaveSettings, seed: u64) -> GeneratedMap {
+    let mut rng = GenRng::new(seed);
+    let mut grid = CaveGrid {
+        size: dimensions,
+        walls: vec![true; (dimensions.x * dimensions.y) as usize],
+    };
+    for y in 1..dimensions.y - 1 {
+        for x in 1..dimensions.x - 1 {
+            let p = IVec2::new(x, y);
+            let wall = rng.chance(settings.initial_wall_chance);
+            grid.set_wall(p, wall);
+        }
+    }
+    for _ in 0..settings.smoothing_passes {
+        grid.smooth();
+    }
+
+    let mut regions = flood_regions(dimensions, |p| !grid.is_wall(p));
+    if regions.is_empty() {
+        // the noise smoothed away completely; open up a small chamber in the middle
+        let c = dimensions / 2;
+        for dy in -2..=2 {
+            for dx in -2..=2 {
+                grid.set_wall(c + IVec2::new(dx, dy), false);
+            }
+        }
+        regions = flood_regions(dimensions, |p| !grid.is_wall(p));
+    }
+
+    // the largest region holds the entry point
+    regions.sort_by_key(|r| std::cmp::Reverse(r.len()));
+    let center = dimensions / 2;
+    let entry = *regions[0].iter().min_by_key(|p| p.distance_squared(center)).unwrap();
+    let mut connected = regions[0].clone();
+    for pocket in regions.iter().skip(1) {
+        if settings.pocket_handling == PocketHandling::Fill || pocket.len() < settings.min_pocket_size {
+            for p in pocket {
+                grid.set_wall(*p, true);
+            }
+        } else {
+            let (from, to) = closest_pair(pocket, &connected);
+            grid.dig_tunnel(from, to);
+            connected.extend(pocket.iter().copied());
+        }
+    }
+
+    let mut map = Map::new_filled(dimensions, "wall");
+    map.horizontal_wrap = WrapMode::Clamp;
+    map.vertical_wrap = WrapMode::Clamp;
+    for y in 0..dimensions.y {
+        for x in 0..dimensions.x {
+            let p = IVec2::new(x, y);
+            if !grid.is_wall(p) {
+                map.blit_tile_type_at(p, "floor");
+            }
+        }
+    }
+
+    let floor: Vec<IVec2> = (0..dimensions.y)
+        .flat_map(|y| (0..dimensions.x).map(move |x| IVec2::new(x, y)))
+        .filter(|p| !grid.is_wall(*p))
+        .collect();
+    map.add_region(MapRegion::from_tiles("cave", floor).with_property("kind", "cave"));
+
+    let mut generated = GeneratedMap {
+        map,
+        player_spawn: entry,
+        actors: Vec::new(),
+        items: Vec::new(),
+        lights: Vec::new(),
+    };
+    if !settings.vaults.is_empty() {
+        let mut reserved = Vec::new();
+        for _ in 0..settings.max_vaults {
+            let vault = &settings.vaults[rng.range(0, settings.vaults.len() as i32) as usize];
+            place_prefab(&mut generated, vault, &mut reserved, &mut rng, "floor", |t| t.tile_type == "wall");
+        }
+    }
+    generated
+}
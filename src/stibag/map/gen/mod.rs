@@ -0,0 +1,146 @@
+use bevy::log::warn;
+use bevy::math::IVec2;
+use bevy_prng::ChaCha8Rng;
+use rand_core::{RngCore, SeedableRng};
+use crate::stibag::map::{ActorPlacement, ItemPlacement, LightPlacement, Map};
+
+pub mod bsp;
+pub mod cave;
+pub mod overworld;
+
+/// A generated map together with everything the generator wants placed on it.
+pub struct GeneratedMap {
+    pub map: Map,
+    pub player_spawn: IVec2,
+    pub actors: Vec<ActorPlacement>,
+    pub items: Vec<ItemPlacement>,
+    pub lights: Vec<LightPlacement>,
+}
+
+impl GeneratedMap {
+    pub fn without_spawns(map: Map) -> Self {
+        GeneratedMap {
+            map,
+            player_spawn: IVec2::new(0, 0),
+            actors: Vec::new(),
+            items: Vec::new(),
+            lights: Vec::new(),
+        }
+    }
+}
+
+/// Runs the generator registered for `template`. The same template, dimensions and seed always
+/// produce the same map.
+pub fn generate(template: &str, dimensions: IVec2, seed: u64) -> GeneratedMap {
+    match template {
+        "default" => GeneratedMap::without_spawns(Map::new_filled(dimensions, "grass")),
+        "dungeon_bsp" => bsp::generate(dimensions, &bsp::BspSettings::default(), seed),
+        "cave" => cave::generate(dimensions, &cave::CaveSettings::default(), seed),
+        "overworld" => overworld::generate(dimensions, &overworld::OverworldSettings::default(), seed),
+        "overworld_streamed" => overworld::generate_streamed(dimensions, &overworld::OverworldSettings::default(), seed),
+        _ => {
+            warn!("Unknown map template {}, using default", template);
+            GeneratedMap::without_spawns(Map::new_filled(dimensions, "grass"))
+        }
+    }
+}
+
+/// Splits the positions inside `dimensions` for which `walkable` holds into 4-connected regions.
+pub fn flood_regions(dimensions: IVec2, walkable: impl Fn(IVec2) -> bool) -> Vec<Vec<IVec2>> {
+    const ORTHOGONAL: [IVec2; 4] = [IVec2::new(1, 0), IVec2::new(-1, 0), IVec2::new(0, 1), IVec2::new(0, -1)];
+    flood_regions_with(dimensions, |_| &ORTHOGONAL, walkable)
+}
+
+/// Like `flood_regions`, with `offsets` giving the offsets to the neighbours of a position.
+pub fn flood_regions_with(dimensions: IVec2, offsets: impl Fn(IVec2) -> &'static [IVec2], walkable: impl Fn(IVec2) -> bool) -> Vec<Vec<IVec2>> {
+    let mut seen = vec![false; (dimensions.x * dimensions.y) as usize];
+    let mut regions = Vec::new();
+    for y in 0..dimensions.y {
+        for x in 0..dimensions.x {
+            let start = IVec2::new(x, y);
+            if seen[(y * dimensions.x + x) as usize] || !walkable(start) {
+                continue;
+            }
+            let mut region = Vec::new();
+            let mut open = vec![start];
+            seen[(y * dimensions.x + x) as usize] = true;
+            while let Some(p) = open.pop() {
+                region.push(p);
+                for d in offsets(p) {
+                    let n = p + *d;
+                    if n.x < 0 || n.y < 0 || n.x >= dimensions.x || n.y >= dimensions.y {
+                        continue;
+                    }
+                    let i = (n.y * dimensions.x + n.x) as usize;
+                    if !seen[i] && walkable(n) {
+                        seen[i] = true;
+                        open.push(n);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+    }
+    regions
+}
+
+/// Seeded random numbers for generators.
+pub struct GenRng(ChaCha8Rng);
+
+impl GenRng {
+    pub fn new(seed: u64) -> Self {
+        GenRng(ChaCha8Rng::seed_from_u64(seed))
+    }
+
+    /// A number in `lo..hi`; returns `lo` when the range is empty.
+    pub fn range(&mut self, lo: i32, hi: i32) -> i32 {
+        if hi <= lo {
+            return lo;
+        }
+        lo + (self.0.next_u32() % (hi - lo) as u32) as i32
+    }
+
+    /// A number in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.0.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rect {
+    pub pos: IVec2,
+    pub size: IVec2,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { pos: IVec2::new(x, y), size: IVec2::new(w, h) }
+    }
+
+    pub fn center(&self) -> IVec2 {
+        self.pos + self.size / 2
+    }
+
+    pub fn contains(&self, p: IVec2) -> bool {
+        p.x >= self.pos.x && p.y >= self.pos.y && p.x < self.pos.x + self.size.x && p.y < self.pos.y + self.size.y
+    }
+
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        self.pos.x < other.pos.x + other.size.x && other.pos.x < self.pos.x + self.size.x
+            && self.pos.y < other.pos.y + other.size.y && other.pos.y < self.pos.y + self.size.y
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item=IVec2> {
+        let r = *self;
+        (r.pos.y..r.pos.y + r.size.y)
+            .flat_map(move |y| (r.pos.x..r.pos.x + r.size.x).map(move |x| IVec2::new(x, y)))
+    }
+}
@@ -0,0 +1,179 @@
+use bevy::math::{IVec2, Vec2};
+use crate::stibag::map::{Map, MapTile};
+use crate::stibag::map::chunk::{ChunkGenerator, ChunkStreaming};
+use crate::stibag::map::gen::GeneratedMap;
+use crate::stibag::map::tiletypes::TileTypeRegistry;
+
+#[derive(Debug, Clone)]
+pub struct OverworldSettings {
+    /// Elevation below this is water.
+    pub sea_level: f32,
+    /// Land up to this much above sea level is sand.
+    pub coast_width: f32,
+    /// Elevation above this is mountains.
+    pub mountain_level: f32,
+    /// Land with more moisture than this is forest.
+    pub forest_moisture: f32,
+    /// Roughly how many tiles across the largest landmasses are.
+    pub feature_size: i32,
+    pub octaves: u32,
+}
+
+impl Default for OverworldSettings {
+    fn default() -> Self {
+        OverworldSettings {
+            sea_level: 0.42,
+            coast_width: 0.04,
+            mountain_level: 0.72,
+            forest_moisture: 0.55,
+            feature_size: 32,
+            octaves: 4,
+        }
+    }
+}
+
+/// Elevation and moisture noise that repeats exactly every `dimensions` tiles, so the overworld
+/// has no seam on a `WrapMode::Repeat` map. Every tile only depends on its position and the seed,
+/// which also makes this usable as a `ChunkGenerator`.
+pub struct OverworldGenerator {
+    pub dimensions: IVec2,
+    pub seed: u64,
+    pub settings: OverworldSettings,
+}
+
+fn hash_to_unit(ix: i32, iy: i32, seed: u64) -> f32 {
+    // splitmix64 over the lattice coordinate and seed
+    let mut h = seed
+        ^ (ix as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (iy as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Smoothly interpolated value noise on a lattice that wraps around after `period` cells.
+fn periodic_value_noise(p: Vec2, period: IVec2, seed: u64) -> f32 {
+    let x0 = p.x.floor() as i32;
+    let y0 = p.y.floor() as i32;
+    let f = p - Vec2::new(x0 as f32, y0 as f32);
+    let s = f * f * (Vec2::splat(3.0) - 2.0 * f);
+    let corner = |dx: i32, dy: i32| {
+        hash_to_unit((x0 + dx).rem_euclid(period.x), (y0 + dy).rem_euclid(period.y), seed)
+    };
+    let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * s.x;
+    let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * s.x;
+    top + (bottom - top) * s.y
+}
+
+impl OverworldGenerator {
+    pub fn new(dimensions: IVec2, seed: u64, settings: OverworldSettings) -> Self {
+        OverworldGenerator { dimensions, seed, settings }
+    }
+
+    /// Fractal noise in `0.0..1.0`; every octave doubles the number of lattice cells across the
+    /// map, so all octaves repeat with the map.
+    fn fbm(&self, position: IVec2, seed: u64) -> f32 {
+        let base_cells = (self.dimensions / self.settings.feature_size.max(1)).max(IVec2::ONE);
+        let uv = position.as_vec2() / self.dimensions.as_vec2();
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut norm = 0.0;
+        for octave in 0..self.settings.octaves {
+            let cells = base_cells * (1 << octave);
+            total += periodic_value_noise(uv * cells.as_vec2(), cells, seed.wrapping_add(octave as u64)) * amplitude;
+            norm += amplitude;
+            amplitude *= 0.5;
+        }
+        total / norm
+    }
+
+    pub fn elevation(&self, position: IVec2) -> f32 {
+        self.fbm(position, self.seed)
+    }
+
+    pub fn moisture(&self, position: IVec2) -> f32 {
+        self.fbm(position, self.seed ^ 0x005E_ED0F_CAFE)
+    }
+
+    pub fn biome_at(&self, position: IVec2) -> &'static str {
+        let s = &self.settings;
+        let e = self.elevation(position);
+        if e < s.sea_level {
+            "water"
+        } else if e < s.sea_level + s.coast_width {
+            "sand"
+        } else if e > s.mountain_level {
+            "mountain"
+        } else if self.moisture(position) > s.forest_moisture {
+            "forest"
+        } else {
+            "grass"
+        }
+    }
+
+    /// The walkable land tile closest to the middle of the map, searching outwards in rings.
+    pub fn find_spawn(&self, tile_types: &TileTypeRegistry) -> IVec2 {
+        let center = self.dimensions / 2;
+        let walkable = |p: IVec2| tile_types.get_or_unknown(self.biome_at(p)).traversal_cost > 0.0 && self.biome_at(p) != "water";
+        for r in 0..self.dimensions.x.max(self.dimensions.y) / 2 {
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    if dx.abs() != r && dy.abs() != r {
+                        continue;
+                    }
+                    let p = center + IVec2::new(dx, dy);
+                    if walkable(p) {
+                        return p;
+                    }
+                }
+            }
+        }
+        center
+    }
+}
+
+impl ChunkGenerator for OverworldGenerator {
+    fn generate_chunk(&self, tile_types: &TileTypeRegistry, origin: IVec2, size: IVec2) -> Vec<MapTile> {
+        let mut tiles = Vec::with_capacity((size.x * size.y) as usize);
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let position = origin + IVec2::new(x, y);
+                let mut t = tile_types.make_tile(self.biome_at(position));
+                t.position = position;
+                tiles.push(t);
+            }
+        }
+        tiles
+    }
+}
+
+/// A wrapping overworld with every tile in memory.
+pub fn generate(dimensions: IVec2, settings: &OverworldSettings, seed: u64) -> GeneratedMap {
+    let generator = OverworldGenerator::new(dimensions, seed, settings.clone());
+    let tile_types = TileTypeRegistry::builtin();
+    let tiles = generator.generate_chunk(&tile_types, IVec2::ZERO, dimensions);
+    let map = Map::from_tiles(dimensions, tiles, tile_types.clone());
+    GeneratedMap {
+        map,
+        player_spawn: generator.find_spawn(&tile_types),
+        actors: Vec::new(),
+        items: Vec::new(),
+        lights: Vec::new(),
+    }
+}
+
+/// The same overworld as `generate`, but streamed in chunks around the possessed actor; meant for
+/// maps too large to keep in memory.
+pub fn generate_streamed(dimensions: IVec2, settings: &OverworldSettings, seed: u64) -> GeneratedMap {
+    let generator = std::sync::Arc::new(OverworldGenerator::new(dimensions, seed, settings.clone()));
+    let player_spawn = generator.find_spawn(&TileTypeRegistry::builtin());
+    let map = Map::new_streamed(dimensions, ChunkStreaming::new(generator));
+    GeneratedMap {
+        map,
+        player_spawn,
+        actors: Vec::new(),
+        items: Vec::new(),
+        lights: Vec::new(),
+    }
+}
@@ -0,0 +1,194 @@
+use bevy::math::{IVec2, IVec3, Vec2};
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::line::symmetric_line;
+
+/// How tiles are laid out and which tiles count as neighbours. Positions are always stored as
+/// (column, row) pairs; the grid decides what they mean.
+#[allow(dead_code)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum GridKind {
+    #[default]
+    Square,
+    /// Pointy-topped hexes in rows, with every odd row shifted right by half a tile. Maps that
+    /// wrap vertically need an even height so the shift lines up across the edge.
+    HexOddRows,
+    /// A square grid drawn as diamonds. It has the neighbours of a square grid; only the
+    /// screen directions differ.
+    Isometric,
+}
+
+const SQUARE_ORTHOGONAL: [IVec2; 4] = [IVec2::new(0, -1), IVec2::new(1, 0), IVec2::new(0, 1), IVec2::new(-1, 0)];
+const SQUARE_DIAGONAL: [IVec2; 4] = [IVec2::new(1, 1), IVec2::new(-1, 1), IVec2::new(-1, -1), IVec2::new(1, -1)];
+const HEX_EVEN_ROW: [IVec2; 6] = [IVec2::new(1, 0), IVec2::new(0, 1), IVec2::new(-1, 1),
+    IVec2::new(-1, 0), IVec2::new(-1, -1), IVec2::new(0, -1)];
+const HEX_ODD_ROW: [IVec2; 6] = [IVec2::new(1, 0), IVec2::new(1, 1), IVec2::new(0, 1),
+    IVec2::new(-1, 0), IVec2::new(0, -1), IVec2::new(1, -1)];
+
+impl GridKind {
+    /// Offsets to the tiles that share an edge with `position`: 4 on square grids, 6 on hexes.
+    pub fn orthogonal_offsets(self, position: IVec2) -> &'static [IVec2] {
+        match self {
+            GridKind::Square | GridKind::Isometric => &SQUARE_ORTHOGONAL,
+            GridKind::HexOddRows if position.y & 1 == 0 => &HEX_EVEN_ROW,
+            GridKind::HexOddRows => &HEX_ODD_ROW,
+        }
+    }
+
+    /// Offsets to every tile an actor can step to from `position`, diagonals included.
+    pub fn neighbour_offsets(self, position: IVec2) -> Vec<IVec2> {
+        match self {
+            GridKind::Square | GridKind::Isometric => SQUARE_ORTHOGONAL.iter().chain(SQUARE_DIAGONAL.iter()).copied().collect(),
+            GridKind::HexOddRows => self.orthogonal_offsets(position).to_vec(),
+        }
+    }
+
+    /// Cube coordinates of a hex position; x + y + z is always 0.
+    fn to_cube(position: IVec2) -> IVec3 {
+        let q = position.x - (position.y - (position.y & 1)) / 2;
+        IVec3::new(q, position.y, -q - position.y)
+    }
+
+    fn from_cube(cube: IVec3) -> IVec2 {
+        IVec2::new(cube.x + (cube.y - (cube.y & 1)) / 2, cube.y)
+    }
+
+    /// Steps between two positions: the most of the x and y distance on square grids, where
+    /// diagonal steps are allowed, and the hex distance on hex grids.
+    pub fn distance(self, from: IVec2, to: IVec2) -> i32 {
+        match self {
+            GridKind::Square | GridKind::Isometric => {
+                let d = (to - from).abs();
+                d.x.max(d.y)
+            }
+            GridKind::HexOddRows => {
+                let d = GridKind::to_cube(to) - GridKind::to_cube(from);
+                (d.x.abs() + d.y.abs() + d.z.abs()) / 2
+            }
+        }
+    }
+
+    /// Straight-line length between the tile centres, in tiles, for light falloff and sight
+    /// radius.
+    pub fn euclidean_distance(self, from: IVec2, to: IVec2) -> f32 {
+        match self {
+            GridKind::Square | GridKind::Isometric => {
+                let d = to - from;
+                ((d.x * d.x + d.y * d.y) as f32).sqrt()
+            }
+            GridKind::HexOddRows => self.layout_position(from).distance(self.layout_position(to)),
+        }
+    }
+
+    /// Where the tile centre is drawn, in tiles, with y pointing up the screen. This follows the
+    /// tilemap layouts the plugin draws with; only directions matter, not the scale.
+    pub fn layout_position(self, position: IVec2) -> Vec2 {
+        let p = position.as_vec2();
+        match self {
+            GridKind::Square => p,
+            GridKind::HexOddRows => Vec2::new(p.x + 0.5 * (position.y & 1) as f32, p.y * 3f32.sqrt() / 2.0),
+            GridKind::Isometric => Vec2::new((p.x + p.y) * 0.5, (p.y - p.x) * 0.5),
+        }
+    }
+
+    /// The neighbour of `position` that lies closest to the screen direction `direction`. Where
+    /// two neighbours are equally close, as going straight up on a hex grid, the one in the
+    /// same column wins, so repeated steps zigzag along the direction.
+    pub fn step_towards(self, position: IVec2, direction: Vec2) -> IVec2 {
+        let direction = direction.normalize_or_zero();
+        let origin = self.layout_position(position);
+        let score = |offset: IVec2| (self.layout_position(position + offset) - origin).normalize_or_zero().dot(direction);
+        let mut best = IVec2::ZERO;
+        let mut best_score = f32::MIN;
+        for offset in self.neighbour_offsets(position) {
+            let s = score(offset);
+            let tie = (s - best_score).abs() < 1e-4;
+            if (!tie && s > best_score) || (tie && offset.x == 0) {
+                best = offset;
+                best_score = s;
+            }
+        }
+        position + best
+    }
+
+    /// The tiles on a straight line from `from` to `to`, both included.
+    pub fn line(self, from: IVec2, to: IVec2) -> Vec<IVec2> {
+        let steps = self.distance(from, to);
+        if steps == 0 {
+            return vec![from];
+        }
+        match self {
+            GridKind::Square | GridKind::Isometric => symmetric_line(from, to),
+            GridKind::HexOddRows => {
+                let (a, b) = (GridKind::to_cube(from).as_vec3(), GridKind::to_cube(to).as_vec3());
+                (0..=steps).map(|i| {
+                    // nudged off the exact edge between two hexes so ties round the same way
+                    let c = a.lerp(b, i as f32 / steps as f32) + bevy::math::Vec3::new(1e-6, 2e-6, -3e-6);
+                    let mut r = c.round();
+                    let diff = (r - c).abs();
+                    if diff.x > diff.y && diff.x > diff.z {
+                        r.x = -r.y - r.z;
+                    } else if diff.y > diff.z {
+                        r.y = -r.x - r.z;
+                    } else {
+                        r.z = -r.x - r.y;
+                    }
+                    GridKind::from_cube(r.as_ivec3())
+                }).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use bevy::math::{IVec2, Vec2};
+    use crate::stibag::map::Map;
+    use crate::stibag::map::grid::GridKind;
+
+    #[test]
+    fn hex_neighbours_are_one_step_away() {
+        let grid = GridKind::HexOddRows;
+        for position in [IVec2::new(5, 4), IVec2::new(5, 5)] {
+            let neighbours: HashSet<IVec2> = grid.neighbour_offsets(position).into_iter().map(|o| position + o).collect();
+            assert_eq!(neighbours.len(), 6);
+            for n in neighbours {
+                assert_eq!(grid.distance(position, n), 1, "{} from {}", n, position);
+                assert!((grid.euclidean_distance(position, n) - 1.0).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn hex_steps() {
+        let mut map = Map::new_filled(IVec2::new(10, 10), "floor");
+        map.grid = GridKind::HexOddRows;
+        assert_eq!(map.step(IVec2::new(5, 4), Vec2::X), IVec2::new(6, 4));
+        assert_eq!(map.step(IVec2::new(5, 4), Vec2::NEG_X), IVec2::new(4, 4));
+        // going straight up zigzags between the two upper neighbours
+        assert_eq!(map.step(IVec2::new(5, 4), Vec2::Y), IVec2::new(5, 5));
+        assert_eq!(map.step(IVec2::new(5, 5), Vec2::Y), IVec2::new(5, 6));
+        assert_eq!(map.step(IVec2::new(5, 4), Vec2::new(1.0, 1.0)), IVec2::new(5, 5));
+        assert_eq!(map.step(IVec2::new(5, 5), Vec2::new(1.0, 1.0)), IVec2::new(6, 6));
+        // off the edge of a wrapping map
+        assert_eq!(map.step(IVec2::new(9, 4), Vec2::X), IVec2::new(0, 4));
+    }
+
+    #[test]
+    fn hex_vision() {
+        let mut map = Map::new_filled(IVec2::new(10, 10), "floor");
+        map.grid = GridKind::HexOddRows;
+        let from = IVec2::new(5, 5);
+        let expected: HashSet<IVec2> = GridKind::HexOddRows.neighbour_offsets(from).into_iter()
+            .map(|o| from + o)
+            .chain([from])
+            .collect();
+        assert_eq!(map.calc_vision(from, 1.01).into_iter().collect::<HashSet<_>>(), expected);
+
+        map.blit_tile_type_at(IVec2::new(6, 5), "wall");
+        let vision = map.calc_vision(from, 2.01);
+        assert!(vision.contains(&IVec2::new(6, 5)));
+        assert!(!vision.contains(&IVec2::new(7, 5)));
+        assert!(vision.contains(&IVec2::new(3, 5)));
+    }
+}
@@ -0,0 +1,502 @@
+//! Imports levels authored in LDtk (https://ldtk.io) as `Map`s.
+//!
+//! Only the parts of the LDtk project format the importer needs are modelled here, read
+//! straight from the project's JSON. Authoring conventions:
+//!
+//! * IntGrid values are named after the tile type they produce ("wall", "water", ...).
+//! * Tilesets use an enum as their tag source; the enum value tagged on a tile is its tile type.
+//! * Everything else about a tile comes from the tile type registry. A tileset tile's custom
+//!   data may override it, one `key=value` per line:
+//!   `transparency=Opaque|Transparent`, `traversal_cost=<f32>` and `tile_visual=<name>`.
+//!   Unknown keys and values are skipped with a warning.
+//! * Layers whose identifier starts with "Feature" or "Overhead" fill those map layers, all
+//!   other tile layers paint the ground.
+//! * Entities named "Light" become map lights, with the fields "color", "intensity" and
+//!   "radius" and the enum fields "falloff" (values named after `LightFalloff`) and "fov"
+//!   (values named after `FovAlgorithm`). Missing fields keep `LightPlacement::new`'s defaults.
+//!   Every other entity becomes an actor spawn, using its "template" field or its identifier.
+//! * Layer offsets are applied in whole tiles. Offsets that aren't a multiple of the grid
+//!   size are rounded down, with a warning.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use bevy::log::warn;
+use bevy::math::IVec2;
+use bevy::prelude::Color;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::stibag::map::{ActorPlacement, LayerTile, LightPlacement, Map, MapLayer, MapTile, Transparency, WrapMode};
+use crate::stibag::map::tiletypes::TileTypeRegistry;
+
+const LIGHT_ENTITY: &str = "Light";
+
+#[derive(Debug)]
+pub enum LdtkImportError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    LevelNotFound(String),
+    Invalid(String),
+}
+
+impl fmt::Display for LdtkImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LdtkImportError::Io(e) => write!(f, "ldtk io error: {}", e),
+            LdtkImportError::Json(e) => write!(f, "ldtk parse error: {}", e),
+            LdtkImportError::LevelNotFound(id) => write!(f, "ldtk level not found: {}", id),
+            LdtkImportError::Invalid(msg) => write!(f, "invalid ldtk level: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LdtkImportError {}
+
+impl From<std::io::Error> for LdtkImportError {
+    fn from(e: std::io::Error) -> Self {
+        LdtkImportError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LdtkImportError {
+    fn from(e: serde_json::Error) -> Self {
+        LdtkImportError::Json(e)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdtkProject {
+    pub defs: LdtkDefs,
+    pub levels: Vec<LdtkLevel>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LdtkDefs {
+    #[serde(default)]
+    pub layers: Vec<LdtkLayerDef>,
+    #[serde(default)]
+    pub tilesets: Vec<LdtkTilesetDef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkLayerDef {
+    pub uid: i32,
+    #[serde(default)]
+    pub int_grid_values: Vec<LdtkIntGridValue>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdtkIntGridValue {
+    pub value: i32,
+    pub identifier: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkTilesetDef {
+    pub uid: i32,
+    #[serde(default)]
+    pub enum_tags: Vec<LdtkEnumTag>,
+    #[serde(default)]
+    pub custom_data: Vec<LdtkTileCustomData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkEnumTag {
+    pub enum_value_id: String,
+    pub tile_ids: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkTileCustomData {
+    pub tile_id: i32,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkLevel {
+    pub identifier: String,
+    /// Missing when the project saves levels in separate files.
+    pub layer_instances: Option<Vec<LdtkLayer>>,
+    pub external_rel_path: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+pub enum LdtkLayerType {
+    IntGrid,
+    Entities,
+    Tiles,
+    AutoLayer,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkLayer {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    #[serde(rename = "__type")]
+    pub layer_type: LdtkLayerType,
+    #[serde(rename = "__cWid")]
+    pub c_wid: i32,
+    #[serde(rename = "__cHei")]
+    pub c_hei: i32,
+    #[serde(rename = "__gridSize")]
+    pub grid_size: i32,
+    #[serde(rename = "__pxTotalOffsetX", default)]
+    pub px_total_offset_x: i32,
+    #[serde(rename = "__pxTotalOffsetY", default)]
+    pub px_total_offset_y: i32,
+    #[serde(rename = "__tilesetDefUid")]
+    pub tileset_def_uid: Option<i32>,
+    pub layer_def_uid: i32,
+    #[serde(default)]
+    pub int_grid_csv: Vec<i32>,
+    #[serde(default)]
+    pub grid_tiles: Vec<LdtkTile>,
+    #[serde(default)]
+    pub auto_layer_tiles: Vec<LdtkTile>,
+    #[serde(default)]
+    pub entity_instances: Vec<LdtkEntity>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdtkTile {
+    /// Pixel position in the layer.
+    pub px: [i32; 2],
+    /// Tile id in the tileset.
+    pub t: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LdtkEntity {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    #[serde(rename = "__grid")]
+    pub grid: [i32; 2],
+    #[serde(default)]
+    pub field_instances: Vec<LdtkField>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LdtkField {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    #[serde(rename = "__value")]
+    pub value: Value,
+}
+
+/// Everything a single LDtk level turns into: the terrain plus what should be spawned on it.
+pub struct LdtkLevelImport {
+    pub map: Map,
+    pub lights: Vec<LightPlacement>,
+    pub actors: Vec<ActorPlacement>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TileDesc {
+    tile_type: String,
+    tile_visual: Option<String>,
+    transparency: Option<Transparency>,
+    traversal_cost: Option<f32>,
+}
+
+impl TileDesc {
+    fn new(tile_type: &str) -> Self {
+        TileDesc {
+            tile_type: tile_type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn apply_custom_data(&mut self, data: &str) {
+        for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("Skipping custom data line {:?} of tile type {}, expected key=value", line, self.tile_type);
+                continue;
+            };
+            let value = value.trim();
+            match (key.trim(), value) {
+                ("transparency", "Opaque") => self.transparency = Some(Transparency::Opaque),
+                ("transparency", "Transparent") => self.transparency = Some(Transparency::Transparent),
+                ("traversal_cost", cost) if cost.parse::<f32>().is_ok() => self.traversal_cost = cost.parse().ok(),
+                ("tile_visual", visual) => self.tile_visual = Some(visual.to_string()),
+                _ => warn!("Skipping custom data {:?} of tile type {}", line, self.tile_type),
+            }
+        }
+    }
+
+    fn to_map_tile(&self, tile_types: &TileTypeRegistry) -> MapTile {
+        let mut tile = tile_types.make_tile(&self.tile_type);
+        if let Some(visual) = self.tile_visual.as_ref() {
+            tile.tile_visual = visual.clone();
+        }
+        if let Some(transparency) = self.transparency {
+            tile.transparency = transparency;
+        }
+        if let Some(cost) = self.traversal_cost {
+            tile.traversal_cost = cost;
+        }
+        tile
+    }
+
+    fn to_layer_tile(&self, tile_types: &TileTypeRegistry) -> LayerTile {
+        let mut tile = tile_types.make_layer_tile(&self.tile_type);
+        if let Some(visual) = self.tile_visual.as_ref() {
+            tile.tile_visual = visual.clone();
+        }
+        if let Some(transparency) = self.transparency {
+            tile.transparency = transparency;
+        }
+        if let Some(cost) = self.traversal_cost {
+            tile.traversal_cost = cost;
+        }
+        tile
+    }
+}
+
+fn target_layer(layer: &LdtkLayer) -> MapLayer {
+    if layer.identifier.starts_with("Feature") {
+        MapLayer::Feature
+    } else if layer.identifier.starts_with("Overhead") {
+        MapLayer::Overhead
+    } else {
+        MapLayer::Ground
+    }
+}
+
+/// The layer's pixel offset in whole tiles.
+fn layer_offset(layer: &LdtkLayer) -> IVec2 {
+    let offset = IVec2::new(layer.px_total_offset_x, layer.px_total_offset_y);
+    if offset.x % layer.grid_size != 0 || offset.y % layer.grid_size != 0 {
+        warn!("Layer {} is offset by {} pixels, which is not a whole number of {} pixel tiles; rounding down",
+              layer.identifier, offset, layer.grid_size);
+    }
+    IVec2::new(offset.x.div_euclid(layer.grid_size), offset.y.div_euclid(layer.grid_size))
+}
+
+fn in_level(map: &Map, position: IVec2) -> bool {
+    position.x >= 0 && position.y >= 0 && position.x < map.width as i32 && position.y < map.height as i32
+}
+
+/// Tile id -> tile description for every tile of a tileset that carries an enum tag.
+fn tileset_descs(tileset: &LdtkTilesetDef) -> HashMap<i32, TileDesc> {
+    let mut descs = HashMap::new();
+    for tag in tileset.enum_tags.iter() {
+        for tile_id in tag.tile_ids.iter() {
+            descs.insert(*tile_id, TileDesc::new(&tag.enum_value_id));
+        }
+    }
+    for custom in tileset.custom_data.iter() {
+        if let Some(desc) = descs.get_mut(&custom.tile_id) {
+            desc.apply_custom_data(&custom.data);
+        }
+    }
+    descs
+}
+
+pub fn load_project(path: impl AsRef<Path>) -> Result<LdtkProject, LdtkImportError> {
+    let s = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&s)?)
+}
+
+/// Imports the level named `level_identifier` from the LDtk project at `path`.
+/// Levels saved as separate files are resolved relative to the project file.
+pub fn import_level(path: impl AsRef<Path>, level_identifier: &str) -> Result<LdtkLevelImport, LdtkImportError> {
+    let path = path.as_ref();
+    let project = load_project(path)?;
+    let level = project.levels.iter()
+        .find(|l| l.identifier == level_identifier)
+        .ok_or_else(|| LdtkImportError::LevelNotFound(level_identifier.to_string()))?;
+    if level.layer_instances.is_none() {
+        if let Some(rel) = level.external_rel_path.as_ref() {
+            let level_path: PathBuf = path.parent().unwrap_or(Path::new(".")).join(rel);
+            let external: LdtkLevel = serde_json::from_str(&fs::read_to_string(level_path)?)?;
+            return convert_level(&project, &external);
+        }
+    }
+    convert_level(&project, level)
+}
+
+pub fn convert_level(project: &LdtkProject, level: &LdtkLevel) -> Result<LdtkLevelImport, LdtkImportError> {
+    let layers = level.layer_instances.as_ref()
+        .ok_or_else(|| LdtkImportError::Invalid(format!("level {} has no layer instances", level.identifier)))?;
+    let grid_layer = layers.iter()
+        .find(|l| l.layer_type != LdtkLayerType::Entities)
+        .ok_or_else(|| LdtkImportError::Invalid(format!("level {} has no tile layers", level.identifier)))?;
+    let dimensions = IVec2::new(grid_layer.c_wid, grid_layer.c_hei);
+    if dimensions.x <= 0 || dimensions.y <= 0 {
+        return Err(LdtkImportError::Invalid(format!("level {} has no tiles", level.identifier)));
+    }
+
+    let mut map = Map::new_from_template("default", dimensions);
+    map.horizontal_wrap = WrapMode::Clamp;
+    map.vertical_wrap = WrapMode::Clamp;
+    let mut lights = Vec::new();
+    let mut actors = Vec::new();
+
+    // LDtk lists layers top-most first; paint bottom-up so upper layers win.
+    for layer in layers.iter().rev() {
+        if layer.c_wid != dimensions.x || layer.c_hei != dimensions.y {
+            return Err(LdtkImportError::Invalid(format!("layer {} does not match the level grid", layer.identifier)));
+        }
+        if layer.grid_size <= 0 {
+            return Err(LdtkImportError::Invalid(format!("layer {} has a grid size of {}", layer.identifier, layer.grid_size)));
+        }
+        match layer.layer_type {
+            LdtkLayerType::IntGrid => {
+                import_int_grid(project, layer, &mut map);
+                import_tiles(project, layer, &mut map);
+            }
+            LdtkLayerType::Tiles | LdtkLayerType::AutoLayer => import_tiles(project, layer, &mut map),
+            LdtkLayerType::Entities => import_entities(layer, &map, &mut lights, &mut actors),
+        }
+    }
+
+    Ok(LdtkLevelImport { map, lights, actors })
+}
+
+fn import_int_grid(project: &LdtkProject, layer: &LdtkLayer, map: &mut Map) {
+    let Some(layer_def) = project.defs.layers.iter().find(|d| d.uid == layer.layer_def_uid) else {
+        return;
+    };
+    let names: HashMap<i32, &str> = layer_def.int_grid_values.iter()
+        .filter_map(|v| v.identifier.as_deref().map(|id| (v.value, id)))
+        .collect();
+    let offset = layer_offset(layer);
+    for (i, value) in layer.int_grid_csv.iter().enumerate() {
+        if let Some(name) = names.get(value) {
+            let pos = IVec2::new(i as i32 % layer.c_wid, i as i32 / layer.c_wid) + offset;
+            if in_level(map, pos) {
+                map.blit_layer_type_at(target_layer(layer), pos, name);
+            }
+        }
+    }
+}
+
+fn import_tiles(project: &LdtkProject, layer: &LdtkLayer, map: &mut Map) {
+    let Some(tileset) = layer.tileset_def_uid.and_then(|uid| project.defs.tilesets.iter().find(|t| t.uid == uid)) else {
+        return;
+    };
+    let descs = tileset_descs(tileset);
+    let map_layer = target_layer(layer);
+    let offset = layer_offset(layer);
+    for tile in layer.grid_tiles.iter().chain(layer.auto_layer_tiles.iter()) {
+        let Some(desc) = descs.get(&tile.t) else {
+            continue;
+        };
+        let pos = IVec2::new(tile.px[0].div_euclid(layer.grid_size), tile.px[1].div_euclid(layer.grid_size)) + offset;
+        if !in_level(map, pos) {
+            continue;
+        }
+        match map_layer {
+            MapLayer::Ground => {
+                let tile = desc.to_map_tile(&map.tile_types);
+                map.blit_tile_at(pos, tile);
+            }
+            MapLayer::Feature => {
+                let tile = desc.to_layer_tile(&map.tile_types);
+                if let Some(t) = map.get_tile_at_mut(pos) {
+                    t.feature = Some(tile);
+                }
+            }
+            MapLayer::Overhead => {
+                let tile = desc.to_layer_tile(&map.tile_types);
+                if let Some(t) = map.get_tile_at_mut(pos) {
+                    t.overhead = Some(tile);
+                }
+            }
+        }
+    }
+}
+
+fn import_entities(layer: &LdtkLayer, map: &Map, lights: &mut Vec<LightPlacement>, actors: &mut Vec<ActorPlacement>) {
+    let offset = layer_offset(layer);
+    for entity in layer.entity_instances.iter() {
+        let position = IVec2::new(entity.grid[0], entity.grid[1]) + offset;
+        if !in_level(map, position) {
+            warn!("Skipping entity {} at {}, it is outside the level", entity.identifier, position);
+            continue;
+        }
+        if entity.identifier == LIGHT_ENTITY {
+            let mut light = LightPlacement::new(position, Color::WHITE, 1.0);
+            for field in entity.field_instances.iter() {
+                match (field.identifier.as_str(), &field.value) {
+                    ("color", Value::String(hex)) => match Color::hex(hex) {
+                        Ok(color) => light.color = color,
+                        Err(e) => warn!("Light at {} has a bad color {}: {:?}", position, hex, e),
+                    },
+                    ("intensity", Value::Number(v)) => light.intensity = v.as_f64().unwrap_or(1.0) as f32,
+                    ("radius", Value::Number(v)) => light.radius = v.as_f64().unwrap_or(light.radius as f64) as f32,
+                    ("falloff", Value::String(_)) => match serde_json::from_value(field.value.clone()) {
+                        Ok(falloff) => light.falloff = falloff,
+                        Err(_) => warn!("Light at {} has an unknown falloff {}", position, field.value),
+                    },
+                    ("fov", Value::String(_)) => match serde_json::from_value(field.value.clone()) {
+                        Ok(fov) => light.fov = fov,
+                        Err(_) => warn!("Light at {} has an unknown fov {}", position, field.value),
+                    },
+                    _ => {}
+                }
+            }
+            lights.push(light);
+        } else {
+            let template = entity.field_instances.iter()
+                .find_map(|f| match (f.identifier.as_str(), &f.value) {
+                    ("template", Value::String(s)) => Some(s.clone()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| entity.identifier.to_lowercase());
+            actors.push(ActorPlacement { template, position });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use bevy::math::IVec2;
+    use bevy::prelude::Color;
+    use crate::stibag::map::{LightFalloff, Transparency};
+    use crate::stibag::map::fov::FovAlgorithm;
+    use crate::stibag::map::ldtk::import_level;
+
+    #[test]
+    fn imports_fixture_level() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("cellar.ldtk");
+        let import = import_level(path, "Cellar").unwrap();
+        let map = &import.map;
+        assert_eq!((map.width, map.height), (4, 3));
+        let tile = |x, y| map.get_tile_at(IVec2::new(x, y)).unwrap();
+        assert_eq!(tile(0, 0).tile_type, "floor");
+        assert_eq!(tile(3, 1).tile_type, "wall");
+        assert_eq!(tile(3, 1).transparency, Transparency::Opaque);
+
+        // tileset tiles are typed by their enum tag and overridden by their custom data
+        assert_eq!(tile(1, 2).tile_type, "sand");
+        assert_eq!(tile(1, 2).traversal_cost, 2.5);
+        assert_eq!(tile(1, 2).transparency, Transparency::Opaque);
+
+        // the feature layer is offset by one tile
+        assert_eq!(tile(2, 1).feature.as_ref().map(|f| f.tile_type.as_str()), Some("tree"));
+        assert!(tile(1, 1).feature.is_none());
+        assert_eq!(tile(1, 0).overhead.as_ref().map(|o| o.tile_type.as_str()), Some("roof"));
+        assert!(tile(1, 0).feature.is_none());
+
+        assert_eq!(import.lights.len(), 1);
+        let light = &import.lights[0];
+        assert_eq!(light.position, IVec2::new(1, 1));
+        assert_eq!(light.color, Color::rgb_u8(255, 128, 0));
+        assert_eq!(light.intensity, 2.0);
+        assert_eq!(light.radius, 6.0);
+        assert_eq!(light.falloff, LightFalloff::Linear);
+        assert_eq!(light.fov, FovAlgorithm::SymmetricShadowcasting);
+
+        let actors: Vec<(&str, IVec2)> = import.actors.iter().map(|a| (a.template.as_str(), a.position)).collect();
+        assert_eq!(actors, vec![("player", IVec2::new(0, 0)), ("goblin", IVec2::new(2, 1))]);
+    }
+}
@@ -0,0 +1,128 @@
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+
+/// How the tiles between two positions on a square grid are chosen.
+#[allow(dead_code)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum LineAlgorithm {
+    /// Classic Bresenham. Where the ideal line passes exactly between two tiles the choice
+    /// depends on the direction, so the line from `a` to `b` may differ from the one back.
+    Bresenham,
+    /// Bresenham with ties broken the same way in both directions: the line from `b` to `a` is
+    /// the line from `a` to `b` reversed, so sight and aim agree both ways.
+    #[default]
+    Symmetric,
+}
+
+impl LineAlgorithm {
+    /// The tiles from `from` to `to`, both included, in order.
+    pub fn line(self, from: IVec2, to: IVec2) -> Vec<IVec2> {
+        match self {
+            LineAlgorithm::Bresenham => bresenham(from, to),
+            LineAlgorithm::Symmetric => symmetric_line(from, to),
+        }
+    }
+}
+
+/// All-octant Bresenham: steps along the longer axis and moves along the shorter one whenever
+/// the error passes half a tile.
+pub fn bresenham(from: IVec2, to: IVec2) -> Vec<IVec2> {
+    let d = to - from;
+    let step = d.signum();
+    let (dx, dy) = (d.x.abs(), -d.y.abs());
+    let mut err = dx + dy;
+    let mut p = from;
+    let mut line = Vec::with_capacity((dx.max(-dy) + 1) as usize);
+    loop {
+        line.push(p);
+        if p == to {
+            return line;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            p.x += step.x;
+        }
+        if e2 <= dx {
+            err += dx;
+            p.y += step.y;
+        }
+    }
+}
+
+/// Bresenham drawn from the lower of the two ends, so both directions make the same tiles.
+pub fn symmetric_line(from: IVec2, to: IVec2) -> Vec<IVec2> {
+    if (from.y, from.x) <= (to.y, to.x) {
+        bresenham(from, to)
+    } else {
+        let mut line = bresenham(to, from);
+        line.reverse();
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use crate::stibag::map::Map;
+    use crate::stibag::map::line::{bresenham, symmetric_line, LineAlgorithm};
+
+    #[test]
+    fn lines_in_every_octant() {
+        // none of these passes exactly between two tiles, so each is the first one mirrored
+        let table = [
+            ((5, 2), [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (5, 2)]),
+            ((2, 5), [(0, 0), (0, 1), (1, 2), (1, 3), (2, 4), (2, 5)]),
+            ((-2, 5), [(0, 0), (0, 1), (-1, 2), (-1, 3), (-2, 4), (-2, 5)]),
+            ((-5, 2), [(0, 0), (-1, 0), (-2, 1), (-3, 1), (-4, 2), (-5, 2)]),
+            ((-5, -2), [(0, 0), (-1, 0), (-2, -1), (-3, -1), (-4, -2), (-5, -2)]),
+            ((-2, -5), [(0, 0), (0, -1), (-1, -2), (-1, -3), (-2, -4), (-2, -5)]),
+            ((2, -5), [(0, 0), (0, -1), (1, -2), (1, -3), (2, -4), (2, -5)]),
+            ((5, -2), [(0, 0), (1, 0), (2, -1), (3, -1), (4, -2), (5, -2)]),
+        ];
+        let from = IVec2::new(3, -1);
+        for (end, tiles) in table {
+            let to = from + IVec2::new(end.0, end.1);
+            let expected: Vec<IVec2> = tiles.iter().map(|(x, y)| from + IVec2::new(*x, *y)).collect();
+            let mut reversed = expected.clone();
+            reversed.reverse();
+            for algorithm in [LineAlgorithm::Bresenham, LineAlgorithm::Symmetric] {
+                assert_eq!(algorithm.line(from, to), expected, "{:?} to {:?}", algorithm, end);
+                assert_eq!(algorithm.line(to, from), reversed, "{:?} from {:?}", algorithm, end);
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_lines_match_both_ways() {
+        let from = IVec2::new(2, 1);
+        for dy in -6..=6 {
+            for dx in -6..=6 {
+                let to = from + IVec2::new(dx, dy);
+                let mut back = symmetric_line(to, from);
+                back.reverse();
+                assert_eq!(symmetric_line(from, to), back, "to {}", to);
+                for line in [bresenham(from, to), back] {
+                    assert_eq!((line[0], line[line.len() - 1]), (from, to));
+                    assert_eq!(line.len() as i32, dx.abs().max(dy.abs()) + 1, "to {}", to);
+                    assert!(line.windows(2).all(|w| (w[1] - w[0]).abs().max_element() == 1), "to {}: {:?}", to, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn walls_are_seen_but_not_seen_through() {
+        let mut map = Map::new_filled(IVec2::new(10, 10), "floor");
+        map.blit_tile_type_at(IVec2::new(5, 3), "wall");
+        map.blit_tile_type_at(IVec2::new(1, 1), "wall");
+        let from = IVec2::new(2, 3);
+        assert!(map.can_see(from, IVec2::new(5, 3)));
+        assert!(map.can_see(IVec2::new(5, 3), from));
+        // halfway round the wrapping map, so both directions must take the same tiles
+        assert!(!map.can_see(from, IVec2::new(7, 3)));
+        assert!(!map.can_see(IVec2::new(7, 3), from));
+        // a wall standing on the start tile doesn't block either
+        assert!(map.can_see(IVec2::new(1, 1), IVec2::new(4, 1)));
+    }
+}
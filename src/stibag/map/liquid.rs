@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use bevy::math::IVec2;
+use bevy::prelude::Color;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::{Map, MapTile};
+
+/// Depth of a completely filled tile.
+pub const MAX_LIQUID_DEPTH: u8 = 8;
+/// Liquid this deep can only be crossed by actors that swim.
+pub const DEEP_LIQUID_DEPTH: u8 = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum LiquidKind {
+    Water,
+    Lava,
+}
+
+impl LiquidKind {
+    /// The kind with the given lower-case name, as scripts write it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "water" => Some(LiquidKind::Water),
+            "lava" => Some(LiquidKind::Lava),
+            _ => None,
+        }
+    }
+
+    /// The tile visual drawn in place of the ground.
+    pub fn visual(self, depth: u8) -> &'static str {
+        match self {
+            LiquidKind::Water if depth >= DEEP_LIQUID_DEPTH => "water_deep",
+            LiquidKind::Water => "water",
+            LiquidKind::Lava => "lava",
+        }
+    }
+
+    /// Flow only happens on steps that are a multiple of this, so lava creeps along slowly.
+    pub fn flow_interval(self) -> u64 {
+        match self {
+            LiquidKind::Water => 1,
+            LiquidKind::Lava => 4,
+        }
+    }
+
+    /// Damage dealt each tick to an actor standing in the liquid.
+    pub fn damage(self) -> i32 {
+        match self {
+            LiquidKind::Water => 0,
+            LiquidKind::Lava => 2,
+        }
+    }
+
+    /// Colour and intensity of the light a tile of this liquid gives off, if any.
+    pub fn light(self) -> Option<(Color, f32)> {
+        match self {
+            LiquidKind::Water => None,
+            LiquidKind::Lava => Some((Color::rgb(1.0, 0.4, 0.1), 0.5)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Liquid {
+    pub kind: LiquidKind,
+    pub depth: u8,
+    /// Springs feed the flow without ever running dry.
+    #[serde(default)]
+    pub source: bool,
+}
+
+impl Liquid {
+    pub fn new(kind: LiquidKind, depth: u8) -> Self {
+        Liquid { kind, depth: depth.min(MAX_LIQUID_DEPTH), source: false }
+    }
+
+    pub fn is_deep(&self) -> bool {
+        self.depth >= DEEP_LIQUID_DEPTH
+    }
+}
+
+/// Liquid that comes with the tile type, like a lake, stays put: it neither drains nor fills up.
+fn is_standing(map: &Map, tile: &MapTile) -> bool {
+    map.tile_types.get(&tile.tile_type).is_some_and(|def| def.liquid.is_some())
+}
+
+/// How much liquid of `kind` the tile at `position` holds, or None if it can't take any.
+fn capacity_depth(map: &Map, position: IVec2, kind: LiquidKind) -> Option<u8> {
+    let tile = map.get_tile_at(position)?;
+    if !tile.is_passable() || is_standing(map, tile) {
+        return None;
+    }
+    match tile.liquid {
+        None => Some(0),
+        Some(l) if l.kind == kind => Some(l.depth),
+        Some(_) => None,
+    }
+}
+
+/// One step of liquid flow over the loaded part of the map: every tile hands one unit to each
+/// lower edge neighbour it is more than one unit above, so liquid spreads out until it is level.
+/// Liquid is never created or lost except at sources. Returns the positions whose liquid changed.
+pub fn flow_liquids(map: &mut Map, step: u64) -> Vec<IVec2> {
+    let mut flowing: Vec<(IVec2, Liquid)> = map.tiles()
+        .filter(|t| !is_standing(map, t))
+        .filter_map(|t| t.liquid.map(|l| (t.position, l)))
+        .filter(|(_, l)| step.is_multiple_of(l.kind.flow_interval()))
+        .collect();
+    // keep the outcome independent of the chunk iteration order
+    flowing.sort_by_key(|(p, _)| (p.y, p.x));
+
+    let mut deltas: HashMap<IVec2, (LiquidKind, i32)> = HashMap::new();
+    for (position, liquid) in flowing {
+        let mut budget = if liquid.source { i32::MAX } else { liquid.depth as i32 - 1 };
+        for n in map.orthogonal_neighbours(position) {
+            if budget <= 0 {
+                break;
+            }
+            let Some(depth) = capacity_depth(map, n, liquid.kind) else {
+                continue;
+            };
+            // another kind may already be flowing in this step
+            if deltas.get(&n).is_some_and(|(k, _)| *k != liquid.kind) {
+                continue;
+            }
+            // several tiles may flow into the same neighbour, which must not overflow
+            let received = deltas.get(&n).map_or(0, |(_, d)| *d);
+            if (depth as i32) + 1 < liquid.depth as i32 && (depth as i32) + received < MAX_LIQUID_DEPTH as i32 {
+                deltas.entry(n).or_insert((liquid.kind, 0)).1 += 1;
+                if !liquid.source {
+                    deltas.entry(position).or_insert((liquid.kind, 0)).1 -= 1;
+                }
+                budget -= 1;
+            }
+        }
+    }
+
+    let mut changed: Vec<IVec2> = deltas.into_iter()
+        .filter(|(_, (_, d))| *d != 0)
+        .filter_map(|(position, (kind, d))| {
+            let tile = map.get_tile_at_mut(position)?;
+            let depth = tile.liquid.map_or(0, |l| l.depth as i32) + d;
+            tile.liquid = match tile.liquid {
+                _ if depth <= 0 => None,
+                Some(l) => Some(Liquid { depth: depth as u8, ..l }),
+                None => Some(Liquid::new(kind, depth as u8)),
+            };
+            Some(position)
+        })
+        .collect();
+    changed.sort_by_key(|p| (p.y, p.x));
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use crate::stibag::map::{Map, WrapMode};
+    use crate::stibag::map::liquid::{flow_liquids, Liquid, LiquidKind, MAX_LIQUID_DEPTH};
+
+    fn depths(map: &Map) -> Vec<u8> {
+        (0..map.width as i32).map(|x| map.get_tile_at(IVec2::new(x, 0)).unwrap().liquid.map_or(0, |l| l.depth)).collect()
+    }
+
+    #[test]
+    fn water_levels_out_without_loss() {
+        let mut map = Map::new_filled(IVec2::new(7, 1), "floor");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        map.get_tile_at_mut(IVec2::new(3, 0)).unwrap().liquid = Some(Liquid::new(LiquidKind::Water, 8));
+        assert!(!map.get_tile_at(IVec2::new(3, 0)).unwrap().can_enter(false));
+        assert!(map.get_tile_at(IVec2::new(3, 0)).unwrap().can_enter(true));
+
+        let mut step = 0;
+        while !flow_liquids(&mut map, step).is_empty() {
+            step += 1;
+            assert_eq!(depths(&map).iter().map(|d| *d as u32).sum::<u32>(), 8, "liquid lost at step {}", step);
+            assert!(step < 100, "water never settled: {:?}", depths(&map));
+        }
+        let depths = depths(&map);
+        // flow stops once no tile is more than one unit above its neighbour
+        assert!(depths.windows(2).all(|w| w[0].abs_diff(w[1]) <= 1), "{:?}", depths);
+        assert!(map.tiles().all(|t| t.can_enter(false)));
+    }
+
+    #[test]
+    fn converging_flow_keeps_all_liquid() {
+        let mut map = Map::new_filled(IVec2::new(3, 3), "floor");
+        map.horizontal_wrap = WrapMode::Clamp;
+        map.vertical_wrap = WrapMode::Clamp;
+        // the four full tiles around the middle all flow into it in the same step
+        for tile in map.tiles_mut() {
+            let depth = if tile.position == IVec2::new(1, 1) { 6 } else { MAX_LIQUID_DEPTH };
+            tile.liquid = Some(Liquid::new(LiquidKind::Water, depth));
+        }
+        let total = |map: &Map| map.tiles().filter_map(|t| t.liquid).map(|l| l.depth as u32).sum::<u32>();
+        let start = total(&map);
+
+        assert!(!flow_liquids(&mut map, 0).is_empty());
+        // the middle only took what fit
+        assert_eq!(map.get_tile_at(IVec2::new(1, 1)).unwrap().liquid.unwrap().depth, MAX_LIQUID_DEPTH);
+        let mut step = 1;
+        loop {
+            assert_eq!(total(&map), start, "liquid lost at step {}", step);
+            assert!(map.tiles().all(|t| t.liquid.is_some_and(|l| l.depth <= MAX_LIQUID_DEPTH)));
+            assert!(step < 100, "water never settled");
+            if flow_liquids(&mut map, step).is_empty() {
+                break;
+            }
+            step += 1;
+        }
+    }
+}
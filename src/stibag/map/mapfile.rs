@@ -0,0 +1,353 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use bevy::math::{IVec2, Vec3};
+use bevy::prelude::Color;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+use crate::stibag::core::ItemContainer;
+use crate::stibag::map::{LayerTile, LightFalloff, LightPlacement, Map, MapLink, MapTile, Transparency, WrapMode, DEFAULT_LIGHT_RADIUS};
+use crate::stibag::map::region::RegionRecord;
+use crate::stibag::map::fov::FovAlgorithm;
+use crate::stibag::map::liquid::Liquid;
+use crate::stibag::map::gas::Gas;
+use crate::stibag::map::grid::GridKind;
+use crate::stibag::map::memory::RememberedTile;
+use crate::stibag::map::tiletypes::TileTypeRegistry;
+
+#[derive(Debug)]
+pub enum MapFileError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MapFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFileError::Io(e) => write!(f, "map file io error: {}", e),
+            MapFileError::Parse(e) => write!(f, "map file parse error: {}", e),
+            MapFileError::Serialize(e) => write!(f, "map file serialize error: {}", e),
+            MapFileError::Invalid(msg) => write!(f, "invalid map file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MapFileError {}
+
+impl From<std::io::Error> for MapFileError {
+    fn from(e: std::io::Error) -> Self {
+        MapFileError::Io(e)
+    }
+}
+
+impl From<ron::error::SpannedError> for MapFileError {
+    fn from(e: ron::error::SpannedError) -> Self {
+        MapFileError::Parse(e)
+    }
+}
+
+impl From<ron::Error> for MapFileError {
+    fn from(e: ron::Error) -> Self {
+        MapFileError::Serialize(e)
+    }
+}
+
+/// The authored part of a `MapTile`. Lighting and items are runtime state and are not stored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapTileRecord {
+    pub tile_type: String,
+    pub tile_visual: String,
+    pub transparency: Transparency,
+    pub traversal_cost: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature: Option<LayerTile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overhead: Option<LayerTile>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub damage: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liquid: Option<Liquid>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fire: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas: Option<Gas>,
+}
+
+fn is_zero(v: &u32) -> bool {
+    *v == 0
+}
+
+impl MapTileRecord {
+    pub fn from_tile(t: &MapTile) -> Self {
+        MapTileRecord {
+            tile_type: t.tile_type.clone(),
+            tile_visual: t.tile_visual.clone(),
+            transparency: t.transparency,
+            traversal_cost: t.traversal_cost,
+            feature: t.feature.clone(),
+            overhead: t.overhead.clone(),
+            damage: t.damage,
+            liquid: t.liquid,
+            fire: t.fire,
+            gas: t.gas,
+        }
+    }
+
+    pub fn into_tile(self, position: IVec2) -> MapTile {
+        MapTile {
+            tile_type: self.tile_type,
+            tile_visual: self.tile_visual,
+            position,
+            contained_items: ItemContainer::new(),
+            transparency: self.transparency,
+            light_color: Color::BLACK,
+            light_amount: 0.0,
+            light: Vec3::ZERO,
+            traversal_cost: self.traversal_cost,
+            lighting: Vec::new(),
+            feature: self.feature,
+            overhead: self.overhead,
+            damage: self.damage,
+            liquid: self.liquid,
+            fire: self.fire,
+            gas: self.gas,
+        }
+    }
+}
+
+fn default_light_radius() -> f32 {
+    DEFAULT_LIGHT_RADIUS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LightRecord {
+    pub position: (i32, i32),
+    pub color: [f32; 4],
+    pub intensity: f32,
+    #[serde(default = "default_light_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub falloff: LightFalloff,
+    #[serde(default)]
+    pub fov: FovAlgorithm,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkRecord {
+    pub position: (i32, i32),
+    pub map: String,
+    pub target: (i32, i32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockRecord {
+    pub position: (i32, i32),
+    pub key: String,
+}
+
+/// A tile the player remembers, with how it looked when last seen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExploredRecord {
+    pub position: (i32, i32),
+    pub tile: RememberedTile,
+}
+
+/// On-disk representation of a `Map`. Tiles are stored row by row, so a tile's position is
+/// implied by its index in `tiles`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapFile {
+    pub width: u32,
+    pub height: u32,
+    pub horizontal_wrap: WrapMode,
+    pub vertical_wrap: WrapMode,
+    #[serde(default)]
+    pub grid: GridKind,
+    pub tiles: Vec<MapTileRecord>,
+    #[serde(default)]
+    pub lights: Vec<LightRecord>,
+    #[serde(default)]
+    pub links: Vec<LinkRecord>,
+    #[serde(default)]
+    pub locks: Vec<LockRecord>,
+    #[serde(default)]
+    pub regions: Vec<RegionRecord>,
+    /// What the player remembers of the map, in saves; map definitions leave it out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub explored: Vec<ExploredRecord>,
+}
+
+impl MapFile {
+    /// Streamed maps only ever have part of their tiles in memory and can't be saved as a whole.
+    pub fn from_map(map: &Map, lights: &[LightPlacement]) -> Result<Self, MapFileError> {
+        if map.is_streamed() {
+            return Err(MapFileError::Invalid("streamed maps are stored chunk by chunk".to_string()));
+        }
+        let mut tiles = Vec::with_capacity(map.width as usize * map.height as usize);
+        for y in 0..map.height as i32 {
+            for x in 0..map.width as i32 {
+                tiles.push(MapTileRecord::from_tile(map.get_tile_at(IVec2::new(x, y)).unwrap()));
+            }
+        }
+        Ok(MapFile {
+            width: map.width,
+            height: map.height,
+            horizontal_wrap: map.horizontal_wrap,
+            vertical_wrap: map.vertical_wrap,
+            grid: map.grid,
+            tiles,
+            lights: lights.iter().map(|l| LightRecord {
+                position: (l.position.x, l.position.y),
+                color: l.color.as_rgba_f32(),
+                intensity: l.intensity,
+                radius: l.radius,
+                falloff: l.falloff.clone(),
+                fov: l.fov,
+            }).collect(),
+            links: {
+                let mut links: Vec<LinkRecord> = map.links.iter().map(|(p, l)| LinkRecord {
+                    position: (p.x, p.y),
+                    map: l.map.clone(),
+                    target: (l.position.x, l.position.y),
+                }).collect();
+                links.sort_by_key(|l| (l.position.1, l.position.0));
+                links
+            },
+            locks: {
+                let mut locks: Vec<LockRecord> = map.locks.iter().map(|(p, key)| LockRecord {
+                    position: (p.x, p.y),
+                    key: key.clone(),
+                }).collect();
+                locks.sort_by_key(|l| (l.position.1, l.position.0));
+                locks
+            },
+            regions: map.regions.iter().map(RegionRecord::from_region).collect(),
+            explored: Vec::new(),
+        })
+    }
+
+    pub fn into_map(self) -> Result<(Map, Vec<LightPlacement>), MapFileError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(MapFileError::Invalid(format!("a {}x{} map has no tiles", self.width, self.height)));
+        }
+        let expected = self.width as usize * self.height as usize;
+        if self.tiles.len() != expected {
+            return Err(MapFileError::Invalid(format!("expected {} tiles for a {}x{} map, got {}",
+                                                     expected, self.width, self.height, self.tiles.len())));
+        }
+        let width = self.width as usize;
+        let tiles: Vec<MapTile> = self.tiles.into_iter().enumerate()
+            .map(|(i, rec)| rec.into_tile(IVec2::new((i % width) as i32, (i / width) as i32)))
+            .collect();
+        let mut lights = Vec::with_capacity(self.lights.len());
+        for l in self.lights {
+            if l.position.0 < 0 || l.position.1 < 0 || l.position.0 >= self.width as i32 || l.position.1 >= self.height as i32 {
+                return Err(MapFileError::Invalid(format!("light at {:?} is outside the map", l.position)));
+            }
+            lights.push(LightPlacement {
+                position: IVec2::new(l.position.0, l.position.1),
+                color: Color::rgba(l.color[0], l.color[1], l.color[2], l.color[3]),
+                intensity: l.intensity,
+                radius: l.radius,
+                falloff: l.falloff,
+                fov: l.fov,
+            });
+        }
+        let mut map = Map::from_tiles(IVec2::new(self.width as i32, self.height as i32), tiles, TileTypeRegistry::builtin());
+        map.horizontal_wrap = self.horizontal_wrap;
+        map.vertical_wrap = self.vertical_wrap;
+        map.grid = self.grid;
+        for l in self.links {
+            if l.position.0 < 0 || l.position.1 < 0 || l.position.0 >= self.width as i32 || l.position.1 >= self.height as i32 {
+                return Err(MapFileError::Invalid(format!("link at {:?} is outside the map", l.position)));
+            }
+            map.links.insert(IVec2::new(l.position.0, l.position.1), MapLink {
+                map: l.map,
+                position: IVec2::new(l.target.0, l.target.1),
+            });
+        }
+        for l in self.locks {
+            if l.position.0 < 0 || l.position.1 < 0 || l.position.0 >= self.width as i32 || l.position.1 >= self.height as i32 {
+                return Err(MapFileError::Invalid(format!("lock at {:?} is outside the map", l.position)));
+            }
+            map.locks.insert(IVec2::new(l.position.0, l.position.1), l.key);
+        }
+        map.regions = self.regions.into_iter().map(RegionRecord::into_region).collect();
+        Ok((map, lights))
+    }
+
+    pub fn from_ron_str(s: &str) -> Result<Self, MapFileError> {
+        Ok(ron::from_str(s)?)
+    }
+
+    pub fn to_ron_string(&self) -> Result<String, MapFileError> {
+        Ok(ron::ser::to_string_pretty(self, PrettyConfig::new())?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MapFileError> {
+        let s = fs::read_to_string(path)?;
+        Self::from_ron_str(&s)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MapFileError> {
+        fs::write(path, self.to_ron_string()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use bevy::prelude::Color;
+    use crate::stibag::map::{LightFalloff, LightPlacement, Map, MapLayer, MapLink, WrapMode};
+    use crate::stibag::map::fov::FovAlgorithm;
+    use crate::stibag::map::gas::{Gas, GasKind};
+    use crate::stibag::map::grid::GridKind;
+    use crate::stibag::map::liquid::{Liquid, LiquidKind};
+    use crate::stibag::map::mapfile::{MapFile, MapTileRecord};
+    use crate::stibag::map::region::MapRegion;
+
+    #[test]
+    fn round_trip_is_lossless() {
+        let mut map = Map::new_filled(IVec2::new(4, 3), "floor");
+        map.horizontal_wrap = WrapMode::Repeat;
+        map.vertical_wrap = WrapMode::Clamp;
+        map.grid = GridKind::HexOddRows;
+        map.blit_tile_type_at(IVec2::new(3, 0), "wall");
+        map.blit_layer_type_at(MapLayer::Feature, IVec2::new(1, 1), "door");
+        map.blit_layer_type_at(MapLayer::Overhead, IVec2::new(2, 1), "roof");
+        map.damage_tile_at(IVec2::new(3, 0), 10);
+        let tile = map.get_tile_at_mut(IVec2::new(0, 2)).unwrap();
+        tile.liquid = Some(Liquid { source: true, ..Liquid::new(LiquidKind::Water, 3) });
+        map.get_tile_at_mut(IVec2::new(1, 2)).unwrap().fire = 2;
+        map.get_tile_at_mut(IVec2::new(2, 2)).unwrap().gas = Some(Gas { kind: GasKind::Smoke, concentration: 1.5 });
+        map.links.insert(IVec2::new(3, 2), MapLink { map: "dungeon_1".to_string(), position: IVec2::new(5, 5) });
+        map.lock_door(IVec2::new(1, 1), "brass key");
+        map.add_region(MapRegion::new_rect("house", IVec2::new(1, 1), IVec2::new(2, 1)).with_property("kind", "house"));
+        map.add_region(MapRegion::from_tiles("pond", [IVec2::new(0, 2), IVec2::new(1, 2)]));
+        let lights = vec![LightPlacement {
+            position: IVec2::new(2, 1),
+            color: Color::rgba(1.0, 0.5, 0.25, 1.0),
+            intensity: 0.75,
+            radius: 6.0,
+            falloff: LightFalloff::Custom(vec![1.0, 0.25, 0.0]),
+            fov: FovAlgorithm::SymmetricShadowcasting,
+        }];
+
+        let ron = MapFile::from_map(&map, &lights).unwrap().to_ron_string().unwrap();
+        let (loaded, loaded_lights) = MapFile::from_ron_str(&ron).unwrap().into_map().unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (map.width, map.height));
+        assert_eq!((loaded.horizontal_wrap, loaded.vertical_wrap, loaded.grid), (map.horizontal_wrap, map.vertical_wrap, map.grid));
+        for tile in map.tiles() {
+            let other = loaded.get_tile_at(tile.position).unwrap();
+            assert_eq!(MapTileRecord::from_tile(other), MapTileRecord::from_tile(tile), "tile at {}", tile.position);
+        }
+        assert_eq!(loaded.get_tile_at(IVec2::new(3, 0)).unwrap().damage, 10);
+        assert_eq!(loaded.links, map.links);
+        assert_eq!(loaded.locks, map.locks);
+        assert_eq!(loaded.regions, map.regions);
+        assert_eq!(loaded_lights, lights);
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::{Map, MapLayer, MapTile};
+
+/// How a tile looked the last time an actor saw it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RememberedTile {
+    pub ground: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overhead: Option<String>,
+}
+
+impl RememberedTile {
+    pub fn of(tile: &MapTile) -> Self {
+        RememberedTile {
+            ground: tile.ground_visual().to_string(),
+            feature: tile.layer_visual(MapLayer::Feature).map(str::to_string),
+            overhead: tile.layer_visual(MapLayer::Overhead).map(str::to_string),
+        }
+    }
+
+    pub fn layer_visual(&self, layer: MapLayer) -> Option<&str> {
+        match layer {
+            MapLayer::Ground => Some(self.ground.as_str()),
+            MapLayer::Feature => self.feature.as_deref(),
+            MapLayer::Overhead => self.overhead.as_deref(),
+        }
+    }
+}
+
+/// The tiles one actor has seen, per map name, so the memory of a level is still there when the
+/// actor comes back to it.
+#[derive(Debug, Default, Clone)]
+pub struct TileMemory {
+    maps: HashMap<String, HashMap<IVec2, RememberedTile>>,
+}
+
+impl TileMemory {
+    /// Records how the tiles at `positions` on `map` look now.
+    pub fn remember(&mut self, map_name: &str, map: &Map, positions: &[IVec2]) {
+        let remembered = self.maps.entry(map_name.to_string()).or_default();
+        for position in positions {
+            if let Some(tile) = map.get_tile_at(*position) {
+                remembered.insert(tile.position, RememberedTile::of(tile));
+            }
+        }
+    }
+
+    pub fn recall(&self, map_name: &str, position: IVec2) -> Option<&RememberedTile> {
+        self.maps.get(map_name)?.get(&position)
+    }
+
+    /// Everything remembered of one map, in row order.
+    pub fn tiles(&self, map_name: &str) -> Vec<(IVec2, &RememberedTile)> {
+        let mut tiles: Vec<(IVec2, &RememberedTile)> = self.maps.get(map_name)
+            .map(|m| m.iter().map(|(p, t)| (*p, t)).collect())
+            .unwrap_or_default();
+        tiles.sort_by_key(|(p, _)| (p.y, p.x));
+        tiles
+    }
+
+    pub fn forget_map(&mut self, map_name: &str) {
+        self.maps.remove(map_name);
+    }
+
+    /// Replaces what is remembered of one map, as when loading it from a save.
+    pub fn replace_map(&mut self, map_name: &str, tiles: impl IntoIterator<Item=(IVec2, RememberedTile)>) {
+        self.maps.insert(map_name.to_string(), tiles.into_iter().collect());
+    }
+}
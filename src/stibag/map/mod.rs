@@ -1,28 +1,66 @@
 use crate::stibag::core::{ActorId, LightId};
-use bevy::math::IVec2;
+use bevy::log::error;
+use bevy::math::{IVec2, Vec2, Vec3};
 use bevy::prelude::Color;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use bevy_ecs_tilemap::prelude::TileTextureIndex;
 use crate::stibag::core::ItemContainer;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::tiletypes::TileTypeRegistry;
+use crate::stibag::map::chunk::{ChunkChange, ChunkStreaming, MapChunk, DEFAULT_CHUNK_SIZE};
+use crate::stibag::map::region::MapRegion;
+use crate::stibag::map::liquid::Liquid;
+use crate::stibag::map::gas::Gas;
+use crate::stibag::map::grid::GridKind;
+use crate::stibag::map::fov::{FovAlgorithm, FOVQuery};
+use crate::stibag::map::line::LineAlgorithm;
+
+pub mod mapfile;
+pub mod ldtk;
+pub mod tiletypes;
+pub mod chunk;
+pub mod gen;
+pub mod prefab;
+pub mod region;
+pub mod ascii;
+pub mod validate;
+pub mod liquid;
+pub mod fire;
+pub mod gas;
+pub mod grid;
+pub mod fov;
+pub mod memory;
+pub mod line;
 
 type TileTypeId = String;
 type TileVisualId = String;
 
 
 #[allow(dead_code)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum WrapMode {
     Clamp,
     Repeat,
     Mirror,
 }
 
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Transparency {
     #[default]
     Opaque,
     Transparent,
 }
 
+/// The ground layer is the `MapTile` itself; features (doors, furniture, trees) sit on top of it and
+/// overheads (roofs, canopies) are drawn above everything, including actors.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum MapLayer {
+    Ground,
+    Feature,
+    Overhead,
+}
+
 #[derive(Debug, Default)]
 pub enum LightContributionType {
     #[default]
@@ -30,6 +68,7 @@ pub enum LightContributionType {
     Emitter(LightId),
 }
 
+/// Light reaching a tile from one source: the source's colour and how strong it is at the tile.
 #[derive(Debug, Default)]
 pub struct LightContribution {
     pub light_contribution_type: LightContributionType,
@@ -53,6 +92,63 @@ impl LightContribution {
             intensity,
         }
     }
+
+    /// The contribution as linear RGB; not clamped, so bright lights go past 1.
+    pub fn hdr(&self) -> Vec3 {
+        let [r, g, b, _] = self.color.as_linear_rgba_f32();
+        Vec3::new(r, g, b) * self.intensity
+    }
+}
+
+/// How a light dims between its position and its radius, where it always reaches zero.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum LightFalloff {
+    /// Intensity over distance, at full intensity within one tile and cut off at the radius.
+    /// This is how lights have always faded, so it is the default.
+    #[default]
+    InverseDistance,
+    /// From full intensity at the light straight down to nothing at the radius.
+    Linear,
+    /// Intensity over distance squared, at full intensity within one tile and eased to zero
+    /// towards the radius so the light doesn't stop at a hard edge.
+    InverseSquare,
+    /// Intensity at evenly spaced distances from the light (first) to the radius (last),
+    /// interpolated in between.
+    Custom(Vec<f32>),
+}
+
+impl LightFalloff {
+    /// The share of a light's intensity left at `distance`, for a light reaching to `radius`.
+    pub fn attenuation(&self, distance: f32, radius: f32) -> f32 {
+        if radius <= 0.0 || distance >= radius {
+            return 0.0;
+        }
+        let t = distance / radius;
+        match self {
+            LightFalloff::InverseDistance => 1.0 / distance.max(1.0),
+            LightFalloff::Linear => 1.0 - t,
+            LightFalloff::InverseSquare => {
+                let window = (1.0 - t.powi(4)).powi(2);
+                window / distance.max(1.0).powi(2)
+            }
+            LightFalloff::Custom(points) => match points.len() {
+                0 => 0.0,
+                1 => points[0],
+                n => {
+                    let x = t * (n - 1) as f32;
+                    let i = (x as usize).min(n - 2);
+                    points[i] + (points[i + 1] - points[i]) * (x - i as f32)
+                }
+            }
+        }
+    }
+}
+
+/// Maps an HDR light value to a display brightness per channel, from 0 up to but never reaching
+/// 1, so bright light saturates smoothly instead of clipping.
+pub fn tone_map(hdr: Vec3) -> Vec3 {
+    Vec3::ONE - (-hdr).exp()
 }
 
 pub struct LightEmitter {
@@ -61,184 +157,261 @@ pub struct LightEmitter {
     pub position: IVec2,
     pub color: Color,
     pub intensity: f32,
+    /// How the tiles the light reaches are worked out.
+    pub fov: FovAlgorithm,
+    /// Distance at which the light has faded out completely.
+    pub radius: f32,
+    pub falloff: LightFalloff,
+}
+
+impl LightEmitter {
+    /// The light as a map would place it, for saving.
+    pub fn placement(&self) -> LightPlacement {
+        LightPlacement {
+            position: self.position,
+            color: self.color,
+            intensity: self.intensity,
+            radius: self.radius,
+            falloff: self.falloff.clone(),
+            fov: self.fov,
+        }
+    }
+}
+
+/// How far the light of a newly spawned emitter reaches.
+pub const DEFAULT_LIGHT_RADIUS: f32 = 30.0;
+
+/// A light that is part of a map definition rather than carried by an actor.
+/// These are spawned into the World as `LightEmitter`s when the map is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightPlacement {
+    pub position: IVec2,
+    pub color: Color,
+    pub intensity: f32,
+    pub radius: f32,
+    pub falloff: LightFalloff,
+    pub fov: FovAlgorithm,
+}
+
+impl LightPlacement {
+    /// A light with the default radius, falloff and field of view.
+    pub fn new(position: IVec2, color: Color, intensity: f32) -> Self {
+        LightPlacement {
+            position,
+            color,
+            intensity,
+            radius: DEFAULT_LIGHT_RADIUS,
+            falloff: LightFalloff::default(),
+            fov: FovAlgorithm::default(),
+        }
+    }
+}
+
+/// An actor that a map definition wants spawned; `template` is passed to `World::spawn_actor_from_template`.
+#[derive(Debug, Clone)]
+pub struct ActorPlacement {
+    pub template: String,
+    pub position: IVec2,
+}
+
+/// Where stepping onto a linked tile, such as stairs, takes the possessed actor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapLink {
+    pub map: String,
+    pub position: IVec2,
+}
+
+/// An item that a map definition wants lying on a tile; `template` names the item to create.
+#[derive(Debug, Clone)]
+pub struct ItemPlacement {
+    pub template: String,
+    pub position: IVec2,
+}
+
+/// A tile on the feature or overhead layer of a `MapTile`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerTile {
+    pub tile_type: TileTypeId,
+    pub tile_visual: TileVisualId,
+    pub transparency: Transparency,
+    pub traversal_cost: f32,
 }
 
 pub struct MapTile {
     pub tile_type: TileTypeId,
     pub tile_visual: TileVisualId,
+    /// Hue of the emitter light on the tile, brightest channel at 1; black if no emitter reaches it.
     pub light_color: Color,
+    /// Total intensity of the emitter light on the tile.
     pub light_amount: f32,
+    /// Linear RGB light on the tile, ambient and emitters added up and not clamped; see `tone_map`.
+    pub light: Vec3,
     pub position: IVec2,
     pub contained_items: ItemContainer,
     pub transparency: Transparency,
     pub traversal_cost: f32,
     pub lighting: Vec<LightContribution>,
+    pub feature: Option<LayerTile>,
+    pub overhead: Option<LayerTile>,
+    /// Damage dealt to the tile's topmost destructible layer; see `Map::damage_tile_at`.
+    pub damage: u32,
+    /// Liquid covering the ground, drawn instead of it.
+    pub liquid: Option<Liquid>,
+    /// Turns the tile keeps burning, 0 if it isn't on fire.
+    pub fire: u32,
+    /// Gas hanging over the tile; thick gas blocks sight.
+    pub gas: Option<Gas>,
+}
+
+/// Outcome of `Map::damage_tile_at`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TileDamage {
+    Indestructible,
+    Damaged { remaining: u32 },
+    Destroyed,
 }
 
 
 impl MapTile {
     pub fn clone_without_inventory(&self) -> Self {
         MapTile {
-            tile_type: "grass".to_string(),
-            tile_visual: "grass".to_string(),
+            tile_type: self.tile_type.clone(),
+            tile_visual: self.tile_visual.clone(),
             position: IVec2::new(0, 0),
             contained_items: ItemContainer::new(),
             transparency: self.transparency,
             traversal_cost: self.traversal_cost,
-            light_color: self.light_color.clone(), // the combined color of lights that have contributed to this tile
+            light_color: self.light_color, // the combined color of lights that have contributed to this tile
             light_amount: self.light_amount,
+            light: self.light,
             lighting: Vec::new(), // all light contributions to this tile
+            feature: self.feature.clone(),
+            overhead: self.overhead.clone(),
+            damage: self.damage,
+            liquid: self.liquid,
+            fire: self.fire,
+            gas: self.gas,
         }
     }
 
-    pub fn get_texture_index(&self) -> TileTextureIndex {
-        match self.tile_visual.as_str() {
-            "grass" => TileTextureIndex(5),
-            "wall" => TileTextureIndex(32 * 2 + 15),
-            "water" => TileTextureIndex(3),
-            "sand" => TileTextureIndex(7),
-            _ => TileTextureIndex(8 * 32 + 32),
-        }
+    /// Blocks sight if either the ground or the feature does. Overheads never block sight,
+    /// they are only hidden from view.
+    pub fn is_opaque(&self) -> bool {
+        self.transparency == Transparency::Opaque
+            || self.feature.as_ref().is_some_and(|f| f.transparency == Transparency::Opaque)
     }
 
-    pub fn get_color(&self) -> bevy::render::color::Color {
-        if self.position.x == 0 && self.position.y == 0 {
-            return bevy::render::color::Color::rgb(1.0, 0.0, 0.0);
-        }
-        match self.tile_visual.as_str() {
-            "grass" => bevy::render::color::Color::rgb(0.0, 1.0, 0.0),
-            "wall" => bevy::render::color::Color::rgb(0.5, 0.5, 0.5),
-            "water" => bevy::render::color::Color::rgb(1.0, 0.0, 1.0),
-            "sand" => bevy::render::color::Color::rgb(1.0, 1.0, 0.0),
-            _ => bevy::render::color::Color::rgb(1.0, 1.0, 1.0),
-        }
+    /// How much of the sight through the tile its gas takes away, from 0 to 1, in the steps
+    /// of `Gas::sight_opacity`.
+    pub fn gas_opacity(&self) -> f32 {
+        self.gas.map_or(0.0, |g| g.sight_opacity())
     }
-}
 
-trait FOVQuery {
-    fn is_blocked(&self, x: i32, y: i32) -> bool;
-    fn radius(&self, x: f32, y: f32) -> f32;
-}
+    /// The cost of entering this tile; negative means impassable. A feature can only make a tile
+    /// harder to cross, never easier.
+    pub fn effective_traversal_cost(&self) -> f32 {
+        match self.feature.as_ref() {
+            Some(f) if f.traversal_cost < 0.0 || self.traversal_cost < 0.0 => -1.0,
+            Some(f) => self.traversal_cost.max(f.traversal_cost),
+            None => self.traversal_cost,
+        }
+    }
 
-impl FOVQuery for Map {
-    fn is_blocked(&self, x: i32, y: i32) -> bool {
-        let tile = self.get_tile_at(IVec2::new(x, y)).unwrap();
-        tile.transparency == Transparency::Opaque
+    pub fn is_passable(&self) -> bool {
+        self.effective_traversal_cost() > 0.0
     }
 
-    fn radius(&self, x: f32, y: f32) -> f32 {
-        (x * x + y * y).sqrt()
+    /// Like `is_passable`, but deep liquid also stops actors that can't swim.
+    pub fn can_enter(&self, can_swim: bool) -> bool {
+        self.is_passable() && (can_swim || !self.liquid.is_some_and(|l| l.is_deep()))
     }
-}
 
-struct FOVCalc<'a> {
-    pub startx: i32,
-    pub starty: i32,
-    pub width: usize,
-    pub height: usize,
-    pub radius: f32,
-    pub map_query: &'a dyn FOVQuery,
-    pub results: Vec<IVec2>,
-}
+    /// The ground's visual, or that of the fire, gas or liquid covering it.
+    pub fn ground_visual(&self) -> &str {
+        if self.fire > 0 {
+            return "fire";
+        }
+        if let Some(gas) = self.gas.filter(|g| g.is_visible()) {
+            return gas.kind.visual();
+        }
+        match self.liquid {
+            Some(l) => l.kind.visual(l.depth),
+            None => self.tile_visual.as_str(),
+        }
+    }
 
-impl<'a> FOVCalc<'a> {
-    pub fn start_new(startx: i32, starty: i32, radius: f32, map_width: usize, map_height: usize,
-                     map_query: &'a dyn FOVQuery) -> Self {
-        FOVCalc {
-            startx,
-            starty,
-            width: map_width,
-            height: map_height,
-            radius,
-            map_query,
-            results: Vec::new(),
-        }
-    }
-
-    pub fn calculate(&mut self) {
-        self.results = Vec::new();
-        let diagonals: [IVec2; 4] = [
-            IVec2::new(-1, -1),
-            IVec2::new(-1, 1),
-            IVec2::new(1, -1),
-            IVec2::new(1, 1),
-        ];
-        self.results.push(IVec2::new(self.startx, self.starty));
-        for d in diagonals {
-            self.castlight(1, 1.0, 0.0, 0, d.x, d.y, 0);
-            self.castlight(1, 1.0, 0.0, d.x, 0, 0, d.y);
+    pub fn layer_visual(&self, layer: MapLayer) -> Option<&str> {
+        match layer {
+            MapLayer::Ground => Some(self.ground_visual()),
+            MapLayer::Feature => self.feature.as_ref().map(|f| f.tile_visual.as_str()),
+            MapLayer::Overhead => self.overhead.as_ref().map(|o| o.tile_visual.as_str()),
         }
     }
-    fn castlight(&mut self, row: i32, mut start: f32, end: f32, xx: i32, xy: i32, yx: i32, yy: i32) {
-        let radius = 30;
 
-        let mut newstart: f32 = 0.0;
-        if start < end {
-            return;
+    /// Works `light`, `light_color` and `light_amount` out from the contributions in `lighting`.
+    /// Colours add up, as light does.
+    pub fn resolve_lighting(&mut self) {
+        let mut light = Vec3::ZERO;
+        let mut emitted = Vec3::ZERO;
+        let mut amount = 0.0;
+        for contribution in self.lighting.iter() {
+            let hdr = contribution.hdr();
+            light += hdr;
+            if let LightContributionType::Emitter(_) = contribution.light_contribution_type {
+                emitted += hdr;
+                amount += contribution.intensity;
+            }
         }
-        let mut blocked = false;
-        let mut distance = row;
-        while distance <= radius && !blocked {
-            let delta_y = -distance;
-            for delta_x in -distance..=0 {
-                let current_x = self.startx + xx * delta_x + xy * delta_y;
-                let current_y = self.starty + yx * delta_x + yy * delta_y;
-                let left_slope = (delta_x as f32 - 0.5) / (delta_y as f32 + 0.5);
-                let right_slope = (delta_x as f32 + 0.5) / (delta_y as f32 - 0.5);
-
-                if !(current_x >= 0 && current_y >= 0 && current_x < self.width as i32 && current_y < self.height as i32) || start < right_slope {
-                    continue;
-                } else if end > left_slope {
-                    break;
-                }
+        let hue = if emitted.max_element() > 0.0 { emitted / emitted.max_element() } else { Vec3::ZERO };
+        self.light_color = Color::rgb_linear(hue.x, hue.y, hue.z);
+        self.light_amount = amount;
+        self.light = light;
+    }
 
-                if self.map_query.radius(delta_x as f32, delta_y as f32) <= self.radius {
-                    let pos = IVec2::new(current_x, current_y);
-                    self.results.push(pos);
-                }
+    pub fn get_texture_index(&self, tile_types: &TileTypeRegistry) -> TileTextureIndex {
+        tile_types.texture_index(self.ground_visual())
+    }
 
-                if blocked {
-                    if self.map_query.is_blocked(current_x, current_y) {
-                        newstart = right_slope;
-                        continue;
-                    } else {
-                        blocked = false;
-                        start = newstart;
-                    }
-                } else {
-                    if self.map_query.is_blocked(current_x, current_y) && distance < self.radius as i32 {
-                        blocked = true;
-                        self.castlight(row + 1, start, left_slope, xx, xy, yx, yy);
-                        newstart = right_slope;
-                    }
-                }
-            }
-            distance += 1;
+    pub fn get_color(&self, tile_types: &TileTypeRegistry) -> bevy::render::color::Color {
+        if self.position.x == 0 && self.position.y == 0 {
+            return bevy::render::color::Color::rgb(1.0, 0.0, 0.0);
         }
+        tile_types.tint(self.ground_visual())
     }
 }
 
+/// Tiles are kept in chunks of `chunk_size` x `chunk_size`. Ordinary maps have all their chunks
+/// loaded; streamed maps only keep the chunks around the possessed actor in memory, see
+/// `stream_around`.
 pub struct Map {
-    pub tiles: Vec<MapTile>,
+    chunks: HashMap<IVec2, MapChunk>,
+    pub chunk_size: u32,
     pub width: u32,
     pub height: u32,
     pub horizontal_wrap: WrapMode,
     pub vertical_wrap: WrapMode,
+    /// Tile layout; decides neighbours, distances, lines and which way screen directions step.
+    pub grid: GridKind,
+    pub tile_types: Arc<TileTypeRegistry>,
+    pub streaming: Option<ChunkStreaming>,
+    /// Tiles that lead to another map, keyed by position.
+    pub links: HashMap<IVec2, MapLink>,
+    /// Locked doors, keyed by position, with the name of the key item that opens them.
+    pub locks: HashMap<IVec2, String>,
+    /// Named areas of the map; they may overlap.
+    pub regions: Vec<MapRegion>,
 }
 
 impl Map {
-    pub fn new_from_template(_template: impl Into<String>, dimensions: IVec2) -> Self {
-        let new_t = MapTile {
-            tile_type: "grass".to_string(),
-            tile_visual: "grass".to_string(),
-            position: IVec2::new(0, 0),
-            contained_items: ItemContainer::new(),
-            transparency: Transparency::Transparent,
-            light_amount: 0.0,
-            light_color: Color::BLACK,
-            traversal_cost: 1.0,
-            lighting: Vec::new(),
-        };
+    /// Generates a map from a named template with seed 0; see `gen::generate` for the templates
+    /// and for getting at the spawn points a template produces.
+    pub fn new_from_template(template: impl Into<String>, dimensions: IVec2) -> Self {
+        gen::generate(&template.into(), dimensions, 0).map
+    }
+
+    pub fn new_filled(dimensions: IVec2, tile_type: &str) -> Self {
+        let tile_types = TileTypeRegistry::builtin();
+        let new_t = tile_types.make_tile(tile_type);
         let mut tiles = Vec::with_capacity((dimensions.x * dimensions.y) as usize);
         for y in 0..dimensions.y {
             for x in 0..dimensions.x {
@@ -247,112 +420,535 @@ impl Map {
                 tiles.push(push_t);
             }
         }
+        Self::from_tiles(dimensions, tiles, tile_types)
+    }
+
+    /// Builds a fully loaded map from tiles given row by row. Maps have at least one tile on
+    /// each axis.
+    pub fn from_tiles(dimensions: IVec2, tiles: Vec<MapTile>, tile_types: Arc<TileTypeRegistry>) -> Self {
+        assert!(dimensions.x > 0 && dimensions.y > 0, "Map size {} has no tiles", dimensions);
+        assert_eq!(tiles.len(), (dimensions.x * dimensions.y) as usize);
+        let mut map = Map {
+            chunks: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            width: dimensions.x as u32,
+            height: dimensions.y as u32,
+            horizontal_wrap: WrapMode::Repeat,
+            vertical_wrap: WrapMode::Repeat,
+            grid: GridKind::Square,
+            tile_types,
+            streaming: None,
+            links: HashMap::new(),
+            locks: HashMap::new(),
+            regions: Vec::new(),
+        };
+        let mut rows: Vec<Option<MapTile>> = tiles.into_iter().map(Some).collect();
+        for coord in map.all_chunk_coords() {
+            let (origin, size) = map.chunk_bounds(coord);
+            let mut chunk_tiles = Vec::with_capacity((size.x * size.y) as usize);
+            for y in origin.y..origin.y + size.y {
+                for x in origin.x..origin.x + size.x {
+                    chunk_tiles.push(rows[(y * dimensions.x + x) as usize].take().unwrap());
+                }
+            }
+            map.chunks.insert(coord, MapChunk { coord, origin, size, tiles: chunk_tiles });
+        }
+        map
+    }
+
+    /// A map whose chunks are generated (or loaded from `streaming.chunk_dir`) on demand.
+    /// Nothing is loaded until the first call to `stream_around`.
+    pub fn new_streamed(dimensions: IVec2, streaming: ChunkStreaming) -> Self {
+        assert!(dimensions.x > 0 && dimensions.y > 0, "Map size {} has no tiles", dimensions);
         Map {
-            tiles,
+            chunks: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
             width: dimensions.x as u32,
             height: dimensions.y as u32,
             horizontal_wrap: WrapMode::Repeat,
             vertical_wrap: WrapMode::Repeat,
+            grid: GridKind::Square,
+            tile_types: TileTypeRegistry::builtin(),
+            streaming: Some(streaming),
+            links: HashMap::new(),
+            locks: HashMap::new(),
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.streaming.is_some()
+    }
+
+    pub fn chunk_count(&self) -> IVec2 {
+        IVec2::new(self.width.div_ceil(self.chunk_size) as i32,
+                   self.height.div_ceil(self.chunk_size) as i32)
+    }
+
+    fn all_chunk_coords(&self) -> Vec<IVec2> {
+        let count = self.chunk_count();
+        let mut coords = Vec::with_capacity((count.x * count.y) as usize);
+        for cy in 0..count.y {
+            for cx in 0..count.x {
+                coords.push(IVec2::new(cx, cy));
+            }
+        }
+        coords
+    }
+
+    pub fn chunk_coord_of(&self, position: IVec2) -> IVec2 {
+        IVec2::new(position.x.div_euclid(self.chunk_size as i32), position.y.div_euclid(self.chunk_size as i32))
+    }
+
+    /// Top-left tile and size of a chunk, clipped to the map.
+    pub fn chunk_bounds(&self, coord: IVec2) -> (IVec2, IVec2) {
+        let origin = coord * self.chunk_size as i32;
+        let size = IVec2::new((self.chunk_size as i32).min(self.width as i32 - origin.x),
+                              (self.chunk_size as i32).min(self.height as i32 - origin.y));
+        (origin, size)
+    }
+
+    pub fn get_chunk(&self, coord: IVec2) -> Option<&MapChunk> {
+        self.chunks.get(&coord)
+    }
+
+    pub fn loaded_chunk_coords(&self) -> Vec<IVec2> {
+        let mut coords: Vec<IVec2> = self.chunks.keys().copied().collect();
+        coords.sort_by_key(|c| (c.y, c.x));
+        coords
+    }
+
+    pub fn tiles(&self) -> impl Iterator<Item=&MapTile> {
+        self.chunks.values().flat_map(|c| c.tiles.iter())
+    }
+
+    pub fn tiles_mut(&mut self) -> impl Iterator<Item=&mut MapTile> {
+        self.chunks.values_mut().flat_map(|c| c.tiles.iter_mut())
+    }
+
+    /// Loads every chunk within `streaming.load_radius` chunks of `center` and unloads those
+    /// further than `streaming.unload_radius`. Chunks with items lying on them stay loaded, as
+    /// chunk files only hold tiles. Does nothing for maps that aren't streamed.
+    pub fn stream_around(&mut self, center: IVec2) -> Vec<ChunkChange> {
+        let Some(streaming) = self.streaming.as_ref() else {
+            return Vec::new();
+        };
+        let (load_radius, unload_radius) = (streaming.load_radius, streaming.unload_radius);
+        let count = self.chunk_count();
+        let center_chunk = self.chunk_coord_of(center);
+        let mut changes = Vec::new();
+
+        let mut wanted = Vec::new();
+        for dy in -load_radius..=load_radius {
+            for dx in -load_radius..=load_radius {
+                let mut c = center_chunk + IVec2::new(dx, dy);
+                if self.horizontal_wrap == WrapMode::Repeat {
+                    c.x = c.x.rem_euclid(count.x);
+                }
+                if self.vertical_wrap == WrapMode::Repeat {
+                    c.y = c.y.rem_euclid(count.y);
+                }
+                if c.x >= 0 && c.y >= 0 && c.x < count.x && c.y < count.y && !wanted.contains(&c) {
+                    wanted.push(c);
+                }
+            }
+        }
+
+        let far: Vec<IVec2> = self.chunks.keys().copied()
+            .filter(|c| self.chunk_distance(*c, center_chunk) > unload_radius)
+            .filter(|c| self.chunks[c].tiles.iter().all(|t| t.contained_items.is_empty()))
+            .collect();
+        for coord in far {
+            let chunk = self.chunks.remove(&coord).unwrap();
+            self.streaming.as_ref().unwrap().store(&chunk);
+            changes.push(ChunkChange::Unloaded(coord));
+        }
+
+        for coord in wanted {
+            if self.chunks.contains_key(&coord) {
+                continue;
+            }
+            let (origin, size) = self.chunk_bounds(coord);
+            let streaming = self.streaming.as_ref().unwrap();
+            let tiles = streaming.load_stored(coord, origin, size)
+                .unwrap_or_else(|| streaming.generator.generate_chunk(&self.tile_types, origin, size));
+            self.chunks.insert(coord, MapChunk { coord, origin, size, tiles });
+            changes.push(ChunkChange::Loaded(coord));
+        }
+        changes
+    }
+
+    /// Chebyshev distance between two chunks, going around the seam on repeating axes.
+    fn chunk_distance(&self, a: IVec2, b: IVec2) -> i32 {
+        let count = self.chunk_count();
+        let mut d = (a - b).abs();
+        if self.horizontal_wrap == WrapMode::Repeat {
+            d.x = d.x.min(count.x - d.x);
+        }
+        if self.vertical_wrap == WrapMode::Repeat {
+            d.y = d.y.min(count.y - d.y);
         }
+        d.x.max(d.y)
     }
 
+    pub fn blit_tile_type_at(&mut self, position: IVec2, tile_type: &str) {
+        let tile = self.tile_types.make_tile(tile_type);
+        self.blit_tile_at(position, tile);
+    }
+
+    /// Copies the ground of `tile` onto the map. Positions past a Clamp edge are ignored.
     pub fn blit_tile_at(&mut self, position: IVec2, mut tile: MapTile) {
-        assert!(position.x < self.width as i32);
-        assert!(position.y < self.height as i32);
+        let Some(position) = self.resolve_position(position) else {
+            error!("Can't blit tile {} at {}, it is off the map", tile.tile_type, position);
+            return;
+        };
         tile.position = position;
 
-        let t: &mut MapTile = self.get_tile_at_mut(position);
+        let Some(t) = self.get_tile_at_mut(position) else {
+            error!("Can't blit tile {} at {}, its chunk is not loaded", tile.tile_type, position);
+            return;
+        };
         assert_eq!(t.position, position, "Tile position mismatch: wanted {} got {}", position, t.position);
         t.tile_type = tile.tile_type.clone();
         t.tile_visual = tile.tile_visual.clone();
         t.transparency = tile.transparency;
         t.traversal_cost = tile.traversal_cost;
+        t.damage = tile.damage;
+        t.liquid = tile.liquid;
+        t.fire = tile.fire;
+        t.gas = tile.gas;
     }
 
-    pub fn get_tile_at_mut(&mut self, position: IVec2) -> &mut MapTile {
-        let t = &mut self.tiles[(position.y * (self.width as i32) + position.x) as usize];
-        t
+    /// Places a tile of the given type on a layer. Blitting onto `MapLayer::Ground` replaces the
+    /// ground and leaves the other layers alone.
+    pub fn blit_layer_type_at(&mut self, layer: MapLayer, position: IVec2, tile_type: &str) {
+        match layer {
+            MapLayer::Ground => self.blit_tile_type_at(position, tile_type),
+            MapLayer::Feature => {
+                let lt = self.tile_types.make_layer_tile(tile_type);
+                match self.get_tile_at_mut(position) {
+                    Some(t) => t.feature = Some(lt),
+                    None => error!("Can't blit {} at {}, its chunk is not loaded", tile_type, position),
+                }
+            }
+            MapLayer::Overhead => {
+                let lt = self.tile_types.make_layer_tile(tile_type);
+                match self.get_tile_at_mut(position) {
+                    Some(t) => t.overhead = Some(lt),
+                    None => error!("Can't blit {} at {}, its chunk is not loaded", tile_type, position),
+                }
+            }
+        }
     }
 
-    pub fn get_tile_at(&self, position: IVec2) -> Option<&MapTile> {
-        if position.x > self.width as i32 - 1 {
-            match self.horizontal_wrap {
-                WrapMode::Clamp => return self.get_tile_at(IVec2::new(self.width as i32 - 1, position.y)),
-                WrapMode::Repeat => return self.get_tile_at(IVec2::new(position.x % self.width as i32, position.y)),
-                WrapMode::Mirror => return self.get_tile_at(IVec2::new(self.width as i32 - position.x % self.width as i32, position.y)),
+    pub fn clear_layer_at(&mut self, layer: MapLayer, position: IVec2) {
+        let Some(t) = self.get_tile_at_mut(position) else {
+            return;
+        };
+        match layer {
+            MapLayer::Ground => {}
+            MapLayer::Feature => t.feature = None,
+            MapLayer::Overhead => t.overhead = None,
+        }
+    }
+
+    pub fn add_region(&mut self, region: MapRegion) {
+        self.regions.push(region);
+    }
+
+    /// Every region containing `position`, in the order they were added.
+    pub fn regions_at(&self, position: IVec2) -> Vec<&MapRegion> {
+        let position = self.normalize_position(position);
+        self.regions.iter().filter(|r| r.contains(position)).collect()
+    }
+
+    /// False inside regions marked `no_spawn`.
+    pub fn allows_spawn(&self, position: IVec2) -> bool {
+        !self.regions_at(position).iter().any(|r| r.property("no_spawn") == Some("true"))
+    }
+
+    /// `base`, or `base` with a number appended if a region of that name exists already.
+    pub fn unique_region_name(&self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut n = 1;
+        while self.region(&name).is_some() {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        name
+    }
+
+    pub fn region(&self, name: &str) -> Option<&MapRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    /// All tiles of the region called `name`; empty if there is no such region.
+    pub fn region_tiles(&self, name: &str) -> Vec<IVec2> {
+        self.region(name).map_or_else(Vec::new, |r| r.tiles())
+    }
+
+    /// The layer damage goes to: the feature if it has a durability, otherwise the ground.
+    fn destructible_layer_at(&self, position: IVec2) -> Option<(MapLayer, u32)> {
+        let tile = self.get_tile_at(position)?;
+        if let Some(feature) = tile.feature.as_ref() {
+            if let Some(durability) = self.tile_types.get_or_unknown(&feature.tile_type).durability {
+                return Some((MapLayer::Feature, durability));
             }
         }
-        if position.x < 0 {
-            match self.horizontal_wrap {
-                WrapMode::Clamp => return self.get_tile_at(IVec2::new(0, position.y)),
-                WrapMode::Repeat => return self.get_tile_at(IVec2::new(self.width as i32 + position.x % self.width as i32, position.y)),
-                WrapMode::Mirror => return self.get_tile_at(IVec2::new(-position.x % self.width as i32, position.y)),
+        self.tile_types.get_or_unknown(&tile.tile_type).durability.map(|d| (MapLayer::Ground, d))
+    }
+
+    pub fn is_destructible_at(&self, position: IVec2) -> bool {
+        self.destructible_layer_at(position).is_some()
+    }
+
+    /// Deals `amount` damage to the tile at `position`. Once the damage reaches the layer's
+    /// durability a feature is removed, or replaced with its `destroyed_into` type, and the
+    /// ground is replaced with its `destroyed_into` type.
+    pub fn damage_tile_at(&mut self, position: IVec2, amount: u32) -> TileDamage {
+        let position = self.normalize_position(position);
+        let Some((layer, durability)) = self.destructible_layer_at(position) else {
+            return TileDamage::Indestructible;
+        };
+        let Some(tile) = self.get_tile_at_mut(position) else {
+            return TileDamage::Indestructible;
+        };
+        tile.damage += amount;
+        if tile.damage < durability {
+            return TileDamage::Damaged { remaining: durability - tile.damage };
+        }
+        tile.damage = 0;
+        let destroyed_type = match layer {
+            MapLayer::Feature => tile.feature.as_ref().unwrap().tile_type.clone(),
+            _ => tile.tile_type.clone(),
+        };
+        let replacement = self.tile_types.get_or_unknown(&destroyed_type).destroyed_into.clone();
+        match (layer, replacement) {
+            (MapLayer::Feature, Some(r)) => self.blit_layer_type_at(MapLayer::Feature, position, &r),
+            (MapLayer::Feature, None) => self.clear_layer_at(MapLayer::Feature, position),
+            (_, Some(r)) => self.blit_tile_type_at(position, &r),
+            (_, None) => error!("Tile type {} has a durability but nothing to turn into", destroyed_type),
+        }
+        self.locks.remove(&position);
+        TileDamage::Destroyed
+    }
+
+    /// Whether the door on the feature layer at `position` is open; None if there is no door.
+    pub fn door_state_at(&self, position: IVec2) -> Option<bool> {
+        let feature = self.get_tile_at(position)?.feature.as_ref()?;
+        if !self.tile_types.has_flag(&feature.tile_type, "door") {
+            return None;
+        }
+        Some(self.tile_types.has_flag(&feature.tile_type, "open"))
+    }
+
+    /// Swaps the door at `position` for its open or closed tile type, which carries the matching
+    /// transparency and traversal cost. Returns false if there is no door there.
+    pub fn set_door_open(&mut self, position: IVec2, open: bool) -> bool {
+        let position = self.normalize_position(position);
+        if self.door_state_at(position).is_none() {
+            return false;
+        }
+        self.blit_layer_type_at(MapLayer::Feature, position, if open { "door_open" } else { "door" });
+        if self.locks.contains_key(&position) {
+            if let Some(feature) = self.get_tile_at_mut(position).and_then(|t| t.feature.as_mut()) {
+                feature.tile_visual = "door_locked".to_string();
             }
         }
-        if position.y > self.height as i32 - 1 {
-            match self.vertical_wrap {
-                WrapMode::Clamp => return self.get_tile_at(IVec2::new(position.x, self.height as i32 - 1)),
-                WrapMode::Repeat => return self.get_tile_at(IVec2::new(position.x, position.y % self.height as i32)),
-                WrapMode::Mirror => return self.get_tile_at(IVec2::new(position.x, self.height as i32 - position.y % self.height as i32)),
+        true
+    }
+
+    /// Closes the door at `position` and locks it so that only an actor carrying `key` can open it.
+    pub fn lock_door(&mut self, position: IVec2, key: &str) -> bool {
+        let position = self.normalize_position(position);
+        if self.door_state_at(position).is_none() {
+            return false;
+        }
+        self.locks.insert(position, key.to_string());
+        self.set_door_open(position, false)
+    }
+
+    pub fn unlock_door(&mut self, position: IVec2) {
+        let position = self.normalize_position(position);
+        if self.locks.remove(&position).is_some() {
+            if let Some(feature) = self.get_tile_at_mut(position).and_then(|t| t.feature.as_mut()) {
+                feature.tile_visual = feature.tile_type.clone();
+            }
+        }
+    }
+
+    /// All tiles with an overhead that are connected to `from` through other overhead tiles,
+    /// i.e. the whole roof or canopy above that position. Empty if `from` has no overhead.
+    pub fn connected_overhead(&self, from: IVec2) -> HashSet<IVec2> {
+        let mut region = HashSet::new();
+        let mut open = vec![from];
+        while let Some(pos) = open.pop() {
+            if pos.x < 0 || pos.y < 0 || pos.x >= self.width as i32 || pos.y >= self.height as i32 || region.contains(&pos) {
+                continue;
             }
+            if self.get_tile_at(pos).is_none_or(|t| t.overhead.is_none()) {
+                continue;
+            }
+            region.insert(pos);
+            open.push(pos + IVec2::new(1, 0));
+            open.push(pos + IVec2::new(-1, 0));
+            open.push(pos + IVec2::new(0, 1));
+            open.push(pos + IVec2::new(0, -1));
         }
-        if position.y < 0 {
-            match self.vertical_wrap {
-                WrapMode::Clamp => return self.get_tile_at(IVec2::new(position.x, 0)),
-                WrapMode::Repeat => return self.get_tile_at(IVec2::new(position.x, self.height as i32 + position.y % self.height as i32)),
-                WrapMode::Mirror => return self.get_tile_at(IVec2::new(position.x, -position.y % self.height as i32)),
+        region
+    }
+
+    /// Where `v` ends up on an axis of `size` tiles. Mirror reflects at the edges, so -1 maps to 0
+    /// and `size` to `size - 1`.
+    fn wrap_axis(v: i32, size: i32, mode: WrapMode) -> i32 {
+        match mode {
+            WrapMode::Clamp => v.clamp(0, size - 1),
+            WrapMode::Repeat => v.rem_euclid(size),
+            WrapMode::Mirror => {
+                let m = v.rem_euclid(2 * size);
+                if m < size { m } else { 2 * size - 1 - m }
             }
         }
-        let t = &self.tiles[(position.y * (self.width as i32) + position.x) as usize];
+    }
+
+    pub fn is_in_bounds(&self, position: IVec2) -> bool {
+        position.x >= 0 && position.y >= 0 && position.x < self.width as i32 && position.y < self.height as i32
+    }
+
+    /// The in-bounds position `position` refers to under the map's wrap modes. Positions past a
+    /// Clamp edge refer to the edge tile.
+    pub fn normalize_position(&self, position: IVec2) -> IVec2 {
+        IVec2::new(Self::wrap_axis(position.x, self.width as i32, self.horizontal_wrap),
+                   Self::wrap_axis(position.y, self.height as i32, self.vertical_wrap))
+    }
+
+    /// Like `normalize_position`, but None for positions past a Clamp edge: there is nothing
+    /// there to see or walk onto.
+    pub fn resolve_position(&self, position: IVec2) -> Option<IVec2> {
+        if (self.horizontal_wrap == WrapMode::Clamp && (position.x < 0 || position.x >= self.width as i32))
+            || (self.vertical_wrap == WrapMode::Clamp && (position.y < 0 || position.y >= self.height as i32)) {
+            return None;
+        }
+        Some(self.normalize_position(position))
+    }
+
+    /// The shortest offset from `from` to `to`. On Repeat axes this may cross the map edge;
+    /// halfway round, where both ways are as short, it doesn't, so the way back is the same.
+    pub fn wrapped_delta(&self, from: IVec2, to: IVec2) -> IVec2 {
+        let (from, to) = (self.normalize_position(from), self.normalize_position(to));
+        let axis = |raw: i32, size: i32, mode: WrapMode| {
+            if mode != WrapMode::Repeat {
+                return raw;
+            }
+            let d = raw.rem_euclid(size);
+            if d > size / 2 || (2 * d == size && raw < 0) { d - size } else { d }
+        };
+        IVec2::new(axis(to.x - from.x, self.width as i32, self.horizontal_wrap),
+                   axis(to.y - from.y, self.height as i32, self.vertical_wrap))
+    }
+
+    /// `to` moved next to `from` across wrapping edges, so grid functions can treat the two as
+    /// plain positions.
+    fn unwrapped_target(&self, from: IVec2, to: IVec2) -> (IVec2, IVec2) {
+        let from = self.normalize_position(from);
+        (from, from + self.wrapped_delta(from, to))
+    }
+
+    pub fn wrapped_distance(&self, from: IVec2, to: IVec2) -> f32 {
+        let (from, to) = self.unwrapped_target(from, to);
+        self.grid.euclidean_distance(from, to)
+    }
+
+    /// The number of steps between two positions on the map's grid.
+    pub fn grid_distance(&self, from: IVec2, to: IVec2) -> i32 {
+        let (from, to) = self.unwrapped_target(from, to);
+        self.grid.distance(from, to)
+    }
+
+    /// Every position an actor can step to from `position`.
+    pub fn neighbours(&self, position: IVec2) -> Vec<IVec2> {
+        self.grid.neighbour_offsets(position).into_iter()
+            .filter_map(|d| self.resolve_position(position + d))
+            .collect()
+    }
+
+    /// The positions sharing an edge with `position`, which is how liquid and gas spread.
+    pub fn orthogonal_neighbours(&self, position: IVec2) -> Vec<IVec2> {
+        self.grid.orthogonal_offsets(position).iter()
+            .filter_map(|d| self.resolve_position(position + *d))
+            .collect()
+    }
+
+    /// The position one step from `position` towards the screen direction `direction` (y up).
+    pub fn step(&self, position: IVec2, direction: Vec2) -> IVec2 {
+        let position = self.normalize_position(position);
+        self.normalize_position(self.grid.step_towards(position, direction))
+    }
+
+    /// The positions on a straight line from `from` to `to`, both included, taking the shorter
+    /// way across wrapping edges.
+    pub fn line(&self, from: IVec2, to: IVec2) -> Vec<IVec2> {
+        self.line_with(from, to, LineAlgorithm::default())
+    }
+
+    /// Like `line`, with the algorithm picking the tiles on square grids. Hex grids have only one
+    /// way to draw a line.
+    pub fn line_with(&self, from: IVec2, to: IVec2, algorithm: LineAlgorithm) -> Vec<IVec2> {
+        let (from, to) = self.unwrapped_target(from, to);
+        let line = match self.grid {
+            GridKind::Square | GridKind::Isometric => algorithm.line(from, to),
+            GridKind::HexOddRows => self.grid.line(from, to),
+        };
+        line.into_iter().map(|p| self.normalize_position(p)).collect()
+    }
+
+    /// None if the tile's chunk isn't loaded.
+    pub fn get_tile_at_mut(&mut self, position: IVec2) -> Option<&mut MapTile> {
+        let position = self.normalize_position(position);
+        let chunk = self.chunks.get_mut(&self.chunk_coord_of(position))?;
+        let idx = chunk.tile_index(position);
+        Some(&mut chunk.tiles[idx])
+    }
+
+    pub fn get_tile_at(&self, position: IVec2) -> Option<&MapTile> {
+        let position = self.normalize_position(position);
+        let chunk = self.chunks.get(&self.chunk_coord_of(position))?;
+        let t = &chunk.tiles[chunk.tile_index(position)];
         assert!(t.position == position, "Tile position mismatch: wanted {} got {}", position, t.position);
         Some(t)
     }
 
+    /// The positions along `line` from `from` towards `to` up to the first one `filter_func`
+    /// rejects or that isn't loaded.
     #[allow(dead_code)]
-    #[allow(non_snake_case)]
     pub fn line_trace(&self, from: IVec2, to: IVec2, filter_func: fn(&Self, IVec2, &MapTile) -> bool) -> Vec<IVec2> {
         let mut result = Vec::new();
-        let mut y = from.y;
-        let dx = (to.x - from.x).abs();
-        let dy = (to.y - from.y).abs();
-        let mut D = 2 * (dy - dx);
-        for x in from.x..=to.x {
-            if filter_func(self, IVec2::new(x, y), self.get_tile_at(IVec2::new(x, y)).unwrap()) {
-                result.push(IVec2::new(x, y));
-                if D > 0 {
-                    y += 1;
-                    D -= 2 * dx;
-                }
-                D += 2 * dy;
+        for p in self.line(from, to) {
+            match self.get_tile_at(p) {
+                Some(tile) if filter_func(self, p, tile) => result.push(p),
+                _ => break,
             }
         }
         result
     }
 
+    /// Whether nothing between the two positions blocks sight. The ends themselves may, so
+    /// walls can be seen.
     #[allow(dead_code)]
     pub fn can_see(&self, from: IVec2, to: IVec2) -> bool {
-        let line = self.line_trace(from, to, |_world, _pos, tile| {
-            if tile.transparency == Transparency::Opaque {
-                return false;
-            }
-            true
-        });
-        match line.last() {
-            Some(last) => {
-                last == &to
-            }
-            None => {
-                false
-            }
-        }
+        let line = self.line(from, to);
+        line.iter().skip(1).take(line.len().saturating_sub(2)).all(|p| !self.is_blocked(p.x, p.y))
     }
 
-    pub fn blit_tiles_from_charmap(&mut self, top_left_pos: IVec2, charmap: Vec<String>, char_mapper_func: fn(char) -> Option<MapTile>) {
+    pub fn blit_tiles_from_charmap(&mut self, top_left_pos: IVec2, charmap: Vec<String>, char_mapper_func: fn(char) -> Option<&'static str>) {
+        self.blit_layer_from_charmap(MapLayer::Ground, top_left_pos, charmap, char_mapper_func);
+    }
+
+    pub fn blit_layer_from_charmap(&mut self, layer: MapLayer, top_left_pos: IVec2, charmap: Vec<String>, char_mapper_func: fn(char) -> Option<&'static str>) {
         for (y, row) in charmap.iter().enumerate() {
-            println!("{}", row);
             for (x, c) in row.chars().enumerate() {
-                if let Some(tile) = char_mapper_func(c) {
-                    self.blit_tile_at(IVec2::new(top_left_pos.x + x as i32, top_left_pos.y + y as i32), tile);
+                if let Some(tile_type) = char_mapper_func(c) {
+                    self.blit_layer_type_at(layer, IVec2::new(top_left_pos.x + x as i32, top_left_pos.y + y as i32), tile_type);
                 }
             }
         }
@@ -360,8 +956,144 @@ impl Map {
 
 
     pub fn calc_vision(&self, from: IVec2, vis_radius: f32) -> Vec<IVec2> {
-        let mut fov = FOVCalc::start_new(from.x, from.y, vis_radius, self.width as usize, self.height as usize, self);
-        fov.calculate();
-        fov.results
+        self.calc_vision_with(from, vis_radius, FovAlgorithm::default())
     }
-}
\ No newline at end of file
+
+    /// The tiles seen from `from` within `vis_radius`, each once. Hex maps always use lines to
+    /// every tile in range, since the square algorithms don't apply to them.
+    pub fn calc_vision_with(&self, from: IVec2, vis_radius: f32, algorithm: FovAlgorithm) -> Vec<IVec2> {
+        let from = self.normalize_position(from);
+        let mut results = match self.grid {
+            // isometric maps are square underneath
+            GridKind::Square | GridKind::Isometric => algorithm.compute(from, vis_radius, self),
+            GridKind::HexOddRows => self.hex_vision(from, vis_radius),
+        };
+        // thin gas doesn't stop sight by itself, but enough of it in a row does
+        if results.iter().any(|p| self.get_tile_at(*p).is_some_and(|t| t.gas_opacity() > 0.0)) {
+            results.retain(|p| self.haze_between(from, *p) < 1.0);
+        }
+        results
+    }
+
+    /// Shadowcasting relies on the square grid's octants, so on hexes every tile in range gets
+    /// a line of its own: it is seen if nothing on the line before it blocks sight.
+    fn hex_vision(&self, from: IVec2, vis_radius: f32) -> Vec<IVec2> {
+        let reach = vis_radius.ceil() as i32 + 1;
+        let mut seen = HashSet::new();
+        let mut results = Vec::new();
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let to = from + IVec2::new(dx, dy);
+                if self.grid.euclidean_distance(from, to) > vis_radius {
+                    continue;
+                }
+                let Some(pos) = self.resolve_position(to) else {
+                    continue;
+                };
+                let line = self.grid.line(from, to);
+                let clear = line.iter().skip(1).take(line.len().saturating_sub(2)).all(|p| {
+                    self.resolve_position(*p).is_some_and(|p| !self.is_blocked(p.x, p.y))
+                });
+                if clear && seen.insert(pos) {
+                    results.push(pos);
+                }
+            }
+        }
+        results
+    }
+
+    /// The gas opacity summed over the tiles between `from` and `to`, both left out.
+    fn haze_between(&self, from: IVec2, to: IVec2) -> f32 {
+        let line = self.line(from, to);
+        line.iter().skip(1).take(line.len().saturating_sub(2))
+            .map(|p| self.get_tile_at(*p).map_or(0.0, |tile| tile.gas_opacity()))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{IVec2, Vec3};
+    use bevy::prelude::Color;
+    use crate::stibag::map::{tone_map, LightContribution, LightFalloff, Map, MapLayer};
+    use crate::stibag::map::tiletypes::TileTypeRegistry;
+
+    #[test]
+    fn layers_blit_and_clear() {
+        let mut map = Map::new_from_template("default", IVec2::new(3, 1));
+        map.blit_layer_from_charmap(MapLayer::Feature, IVec2::ZERO, vec![".t#".to_string()], |c| match c {
+            't' => Some("tree"),
+            '#' => Some("wall"),
+            _ => None,
+        });
+        map.blit_layer_type_at(MapLayer::Overhead, IVec2::new(1, 0), "roof");
+        let feature = |map: &Map, x| map.get_tile_at(IVec2::new(x, 0)).unwrap().feature.as_ref().map(|f| f.tile_type.clone());
+        assert_eq!(feature(&map, 0), None);
+        assert_eq!(feature(&map, 1), Some("tree".to_string()));
+        assert_eq!(feature(&map, 2), Some("wall".to_string()));
+
+        // the layers combine with the ground they are on, which stays as it was
+        let tree = map.get_tile_at(IVec2::new(1, 0)).unwrap();
+        assert_eq!(tree.tile_type, "grass");
+        assert_eq!(tree.overhead.as_ref().unwrap().tile_type, "roof");
+        assert_eq!(tree.effective_traversal_cost(), 2.0);
+        assert!(!tree.is_opaque());
+        let wall = map.get_tile_at(IVec2::new(2, 0)).unwrap();
+        assert!(wall.is_opaque());
+        assert!(!wall.is_passable());
+
+        map.clear_layer_at(MapLayer::Feature, IVec2::new(2, 0));
+        map.clear_layer_at(MapLayer::Overhead, IVec2::new(1, 0));
+        map.clear_layer_at(MapLayer::Ground, IVec2::new(1, 0));
+        assert_eq!(feature(&map, 2), None);
+        let wall = map.get_tile_at(IVec2::new(2, 0)).unwrap();
+        assert!(!wall.is_opaque());
+        assert!(wall.is_passable());
+        let tree = map.get_tile_at(IVec2::new(1, 0)).unwrap();
+        assert!(tree.overhead.is_none());
+        assert_eq!(feature(&map, 1), Some("tree".to_string()));
+        assert_eq!(tree.tile_type, "grass");
+    }
+
+    #[test]
+    fn falloff_curves() {
+        let table = [
+            (LightFalloff::InverseDistance, [1.0, 1.0, 0.5, 1.0 / 3.0, 0.0]),
+            (LightFalloff::Linear, [1.0, 0.75, 0.5, 0.25, 0.0]),
+            // (1 - (d / 4)^4)^2 / d^2
+            (LightFalloff::InverseSquare, [1.0, 0.99220276, 0.21972656, 0.05192227, 0.0]),
+            (LightFalloff::Custom(vec![1.0, 0.5, 0.0]), [1.0, 0.75, 0.5, 0.25, 0.0]),
+            (LightFalloff::Custom(vec![0.3]), [0.3, 0.3, 0.3, 0.3, 0.0]),
+            (LightFalloff::Custom(Vec::new()), [0.0; 5]),
+        ];
+        for (falloff, expected) in table {
+            for (d, expected) in expected.into_iter().enumerate() {
+                let got = falloff.attenuation(d as f32, 4.0);
+                assert!((got - expected).abs() < 1e-6, "{:?} at {}: {} instead of {}", falloff, d, got, expected);
+            }
+            assert_eq!(falloff.attenuation(6.0, 4.0), 0.0, "{:?} past the radius", falloff);
+        }
+    }
+
+    #[test]
+    fn coloured_lights_add_up() {
+        let mut tile = TileTypeRegistry::builtin().make_tile("floor");
+        tile.lighting.push(LightContribution::new_ambient(Color::WHITE, 0.125));
+        tile.lighting.push(LightContribution::new_emitter(1, Color::RED, 0.5));
+        tile.lighting.push(LightContribution::new_emitter(2, Color::BLUE, 0.25));
+        tile.resolve_lighting();
+        assert_eq!(tile.light, Vec3::new(0.625, 0.125, 0.375));
+        assert_eq!(tile.light_color.as_linear_rgba_f32(), [1.0, 0.0, 0.5, 1.0]);
+        assert_eq!(tile.light_amount, 0.75);
+    }
+
+    #[test]
+    fn tone_map_saturates() {
+        assert_eq!(tone_map(Vec3::ZERO), Vec3::ZERO);
+        let mapped = tone_map(Vec3::new(1.0, 4.0, 1000.0));
+        assert!((mapped.x - (1.0 - (-1.0f32).exp())).abs() < 1e-6, "{}", mapped);
+        assert!((mapped.y - (1.0 - (-4.0f32).exp())).abs() < 1e-6, "{}", mapped);
+        assert_eq!(mapped.z, 1.0);
+        assert!(tone_map(Vec3::splat(2.0)).cmplt(tone_map(Vec3::splat(3.0))).all());
+    }
+}
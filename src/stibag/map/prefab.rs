@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use bevy::math::IVec2;
+use bevy::prelude::Color;
+use serde::{Deserialize, Serialize};
+use crate::stibag::map::{ActorPlacement, ItemPlacement, LightPlacement, Map, MapLayer, MapTile};
+use crate::stibag::map::gen::{GenRng, GeneratedMap, Rect};
+use crate::stibag::map::mapfile::MapFileError;
+use crate::stibag::map::region::MapRegion;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrefabLight {
+    pub color: [f32; 4],
+    pub intensity: f32,
+}
+
+/// What a legend character puts on the map. Every part is optional; a character with an empty
+/// entry still counts as part of the prefab for overlap checks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LegendEntry {
+    #[serde(default)]
+    pub ground: Option<String>,
+    #[serde(default)]
+    pub feature: Option<String>,
+    #[serde(default)]
+    pub overhead: Option<String>,
+    #[serde(default)]
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub item: Option<String>,
+    #[serde(default)]
+    pub light: Option<PrefabLight>,
+    /// Generators connect entrances to the surrounding map after stamping.
+    #[serde(default)]
+    pub entrance: bool,
+}
+
+/// A hand-made piece of map in charmap form that carries its own legend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prefab {
+    pub name: String,
+    pub legend: HashMap<char, LegendEntry>,
+    pub rows: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+/// Mirroring is applied first (left-right), then the clockwise rotation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct PrefabTransform {
+    pub rotation: Rotation,
+    pub mirrored: bool,
+}
+
+impl PrefabTransform {
+    pub fn random(rng: &mut GenRng) -> Self {
+        let rotation = match rng.range(0, 4) {
+            0 => Rotation::None,
+            1 => Rotation::Cw90,
+            2 => Rotation::Cw180,
+            _ => Rotation::Cw270,
+        };
+        PrefabTransform { rotation, mirrored: rng.chance(0.5) }
+    }
+}
+
+/// Everything a stamped prefab wants spawned, in map coordinates.
+#[derive(Default)]
+pub struct StampResult {
+    pub actors: Vec<ActorPlacement>,
+    pub items: Vec<ItemPlacement>,
+    pub lights: Vec<LightPlacement>,
+    pub entrances: Vec<IVec2>,
+}
+
+impl Prefab {
+    pub fn from_ron_str(s: &str) -> Result<Self, MapFileError> {
+        let prefab: Prefab = ron::from_str(s)?;
+        let width = prefab.rows.first().map_or(0, |r| r.chars().count());
+        if width == 0 || prefab.rows.iter().any(|r| r.chars().count() != width) {
+            return Err(MapFileError::Invalid(format!("prefab {} rows must be non-empty and equally long", prefab.name)));
+        }
+        Ok(prefab)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MapFileError> {
+        Self::from_ron_str(&fs::read_to_string(path)?)
+    }
+
+    /// The vaults shipped in assets/prefabs.
+    pub fn builtin_vaults() -> Vec<Prefab> {
+        vec![
+            Prefab::from_ron_str(include_str!("../../../assets/prefabs/shrine.ron")).expect("builtin prefab must parse"),
+        ]
+    }
+
+    fn base_size(&self) -> IVec2 {
+        IVec2::new(self.rows[0].chars().count() as i32, self.rows.len() as i32)
+    }
+
+    pub fn size(&self, transform: PrefabTransform) -> IVec2 {
+        let s = self.base_size();
+        match transform.rotation {
+            Rotation::None | Rotation::Cw180 => s,
+            Rotation::Cw90 | Rotation::Cw270 => IVec2::new(s.y, s.x),
+        }
+    }
+
+    /// Where the character at `p` of the untransformed prefab ends up.
+    fn transform_pos(&self, p: IVec2, transform: PrefabTransform) -> IVec2 {
+        let s = self.base_size();
+        let p = if transform.mirrored { IVec2::new(s.x - 1 - p.x, p.y) } else { p };
+        match transform.rotation {
+            Rotation::None => p,
+            Rotation::Cw90 => IVec2::new(s.y - 1 - p.y, p.x),
+            Rotation::Cw180 => IVec2::new(s.x - 1 - p.x, s.y - 1 - p.y),
+            Rotation::Cw270 => IVec2::new(p.y, s.x - 1 - p.x),
+        }
+    }
+
+    /// Every legend character of the prefab with its offset after transforming.
+    pub fn cells(&self, transform: PrefabTransform) -> Vec<(IVec2, &LegendEntry)> {
+        let mut cells = Vec::new();
+        for (y, row) in self.rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if let Some(entry) = self.legend.get(&c) {
+                    cells.push((self.transform_pos(IVec2::new(x as i32, y as i32), transform), entry));
+                }
+            }
+        }
+        cells
+    }
+
+    pub fn stamp(&self, map: &mut Map, top_left: IVec2, transform: PrefabTransform) -> StampResult {
+        let mut result = StampResult::default();
+        for (offset, entry) in self.cells(transform) {
+            let position = top_left + offset;
+            if let Some(ground) = entry.ground.as_ref() {
+                map.blit_tile_type_at(position, ground);
+            }
+            match entry.feature.as_ref() {
+                Some(feature) => map.blit_layer_type_at(MapLayer::Feature, position, feature),
+                None => map.clear_layer_at(MapLayer::Feature, position),
+            }
+            if let Some(overhead) = entry.overhead.as_ref() {
+                map.blit_layer_type_at(MapLayer::Overhead, position, overhead);
+            }
+            if let Some(template) = entry.actor.as_ref() {
+                result.actors.push(ActorPlacement { template: template.clone(), position });
+            }
+            if let Some(template) = entry.item.as_ref() {
+                result.items.push(ItemPlacement { template: template.clone(), position });
+            }
+            if let Some(light) = entry.light.as_ref() {
+                let color = Color::rgba(light.color[0], light.color[1], light.color[2], light.color[3]);
+                result.lights.push(LightPlacement::new(position, color, light.intensity));
+            }
+            if entry.entrance {
+                result.entrances.push(position);
+            }
+        }
+        result
+    }
+}
+
+/// Looks for a spot where a `size` footprint plus a one tile margin lies inside the map, covers
+/// only tiles accepted by `is_free` and doesn't overlap any of `reserved`.
+pub fn find_free_spot(map: &Map, size: IVec2, reserved: &[Rect], rng: &mut GenRng, attempts: u32,
+                      is_free: impl Fn(&MapTile) -> bool) -> Option<IVec2> {
+    for _ in 0..attempts {
+        let top_left = IVec2::new(rng.range(1, map.width as i32 - size.x), rng.range(1, map.height as i32 - size.y));
+        let area = Rect::new(top_left.x - 1, top_left.y - 1, size.x + 2, size.y + 2);
+        if area.pos.x < 0 || area.pos.y < 0 || area.pos.x + area.size.x > map.width as i32 || area.pos.y + area.size.y > map.height as i32 {
+            continue;
+        }
+        if reserved.iter().any(|r| r.overlaps(&area)) {
+            continue;
+        }
+        if area.positions().all(|p| map.get_tile_at(p).is_some_and(&is_free)) {
+            return Some(top_left);
+        }
+    }
+    None
+}
+
+/// Digs a passage of `tile_type` from `from` to the nearest passable tile outside of `footprint`,
+/// going around the footprint.
+pub fn connect_to_nearest(map: &mut Map, from: IVec2, footprint: Rect, tile_type: &str) {
+    let dimensions = IVec2::new(map.width as i32, map.height as i32);
+    let idx = |p: IVec2| (p.y * dimensions.x + p.x) as usize;
+    let mut came_from: Vec<Option<IVec2>> = vec![None; (dimensions.x * dimensions.y) as usize];
+    came_from[idx(from)] = Some(from);
+    let mut open = VecDeque::from([from]);
+    let mut target = None;
+    while let Some(p) = open.pop_front() {
+        if p != from && map.get_tile_at(p).is_some_and(|t| t.is_passable()) {
+            target = Some(p);
+            break;
+        }
+        for d in [IVec2::new(1, 0), IVec2::new(-1, 0), IVec2::new(0, 1), IVec2::new(0, -1)] {
+            let n = p + d;
+            if n.x < 1 || n.y < 1 || n.x >= dimensions.x - 1 || n.y >= dimensions.y - 1 || footprint.contains(n) {
+                continue;
+            }
+            if came_from[idx(n)].is_none() {
+                came_from[idx(n)] = Some(p);
+                open.push_back(n);
+            }
+        }
+    }
+    let Some(mut p) = target else {
+        return;
+    };
+    while let Some(prev) = came_from[idx(p)] {
+        if prev == p || prev == from {
+            break;
+        }
+        map.blit_tile_type_at(prev, tile_type);
+        p = prev;
+    }
+}
+
+/// Stamps `prefab` with a random transform into a free spot of a generated map, adds what it
+/// placed to the map's spawns and connects its entrances. The prefab's area is added to
+/// `reserved` and marked as a no-spawn region named after the prefab. Returns false if no free
+/// spot was found.
+pub fn place_prefab(generated: &mut GeneratedMap, prefab: &Prefab, reserved: &mut Vec<Rect>, rng: &mut GenRng,
+                    connect_with: &str, is_free: impl Fn(&MapTile) -> bool) -> bool {
+    let transform = PrefabTransform::random(rng);
+    let size = prefab.size(transform);
+    let Some(top_left) = find_free_spot(&generated.map, size, reserved, rng, 50, is_free) else {
+        return false;
+    };
+    let footprint = Rect { pos: top_left, size };
+    let stamped = prefab.stamp(&mut generated.map, top_left, transform);
+    for entrance in stamped.entrances.iter() {
+        connect_to_nearest(&mut generated.map, *entrance, footprint, connect_with);
+    }
+    generated.actors.extend(stamped.actors);
+    generated.items.extend(stamped.items);
+    generated.lights.extend(stamped.lights);
+    let name = generated.map.unique_region_name(&prefab.name);
+    generated.map.add_region(MapRegion::new_rect(name, footprint.pos, footprint.size)
+        .with_property("kind", "vault")
+        .with_property("no_spawn", "true"));
+    reserved.push(footprint);
+    true
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashMap, HashSet};
+use bevy::math::IVec2;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionShape {
+    Rect { pos: IVec2, size: IVec2 },
+    Tiles(HashSet<IVec2>),
+}
+
+/// A named area of a map such as a room, a town or a shop interior. Properties are free-form;
+/// the ones the game looks at are `kind`, `no_spawn` and `story_choice` (a story choice that is
+/// selected when the possessed actor enters the region).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapRegion {
+    pub name: String,
+    pub shape: RegionShape,
+    pub properties: HashMap<String, String>,
+}
+
+impl MapRegion {
+    pub fn new_rect(name: impl Into<String>, pos: IVec2, size: IVec2) -> Self {
+        MapRegion {
+            name: name.into(),
+            shape: RegionShape::Rect { pos, size },
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn from_tiles(name: impl Into<String>, tiles: impl IntoIterator<Item=IVec2>) -> Self {
+        MapRegion {
+            name: name.into(),
+            shape: RegionShape::Tiles(tiles.into_iter().collect()),
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn with_property(mut self, key: &str, value: &str) -> Self {
+        self.properties.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(|v| v.as_str())
+    }
+
+    pub fn contains(&self, position: IVec2) -> bool {
+        match &self.shape {
+            RegionShape::Rect { pos, size } => {
+                position.x >= pos.x && position.y >= pos.y && position.x < pos.x + size.x && position.y < pos.y + size.y
+            }
+            RegionShape::Tiles(tiles) => tiles.contains(&position),
+        }
+    }
+
+    /// Every position in the region, row by row.
+    pub fn tiles(&self) -> Vec<IVec2> {
+        let mut tiles: Vec<IVec2> = match &self.shape {
+            RegionShape::Rect { pos, size } => (pos.y..pos.y + size.y)
+                .flat_map(|y| (pos.x..pos.x + size.x).map(move |x| IVec2::new(x, y)))
+                .collect(),
+            RegionShape::Tiles(tiles) => tiles.iter().copied().collect(),
+        };
+        tiles.sort_by_key(|p| (p.y, p.x));
+        tiles
+    }
+}
+
+/// On-disk representation of a `MapRegion`; exactly one of `rect` and `tiles` is used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionRecord {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rect: Option<((i32, i32), (i32, i32))>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<(i32, i32)>,
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+impl RegionRecord {
+    pub fn from_region(region: &MapRegion) -> Self {
+        let (rect, tiles) = match &region.shape {
+            RegionShape::Rect { pos, size } => (Some(((pos.x, pos.y), (size.x, size.y))), Vec::new()),
+            RegionShape::Tiles(_) => (None, region.tiles().into_iter().map(|p| (p.x, p.y)).collect()),
+        };
+        RegionRecord {
+            name: region.name.clone(),
+            rect,
+            tiles,
+            properties: region.properties.clone(),
+        }
+    }
+
+    pub fn into_region(self) -> MapRegion {
+        let shape = match self.rect {
+            Some(((x, y), (w, h))) => RegionShape::Rect { pos: IVec2::new(x, y), size: IVec2::new(w, h) },
+            None => RegionShape::Tiles(self.tiles.into_iter().map(|(x, y)| IVec2::new(x, y)).collect()),
+        };
+        MapRegion {
+            name: self.name,
+            shape,
+            properties: self.properties,
+        }
+    }
+}
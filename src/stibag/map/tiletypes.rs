@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use bevy::log::error;
+use bevy::math::{IVec2, Vec3};
+use bevy::prelude::Color;
+use bevy_ecs_tilemap::prelude::TileTextureIndex;
+use serde::{Deserialize, Serialize};
+use crate::stibag::core::ItemContainer;
+use crate::stibag::map::{LayerTile, MapTile, Transparency};
+use crate::stibag::map::mapfile::MapFileError;
+use crate::stibag::map::liquid::Liquid;
+
+fn default_tint() -> [f32; 4] {
+    [1.0, 1.0, 1.0, 1.0]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileTypeDef {
+    pub atlas_index: u32,
+    #[serde(default = "default_tint")]
+    pub tint: [f32; 4],
+    /// Character for the tile in ASCII dumps, the inverse of a charmap legend.
+    #[serde(default)]
+    pub glyph: Option<char>,
+    pub transparency: Transparency,
+    pub traversal_cost: f32,
+    #[serde(default)]
+    pub flags: HashSet<String>,
+    /// Damage the tile takes before it is destroyed; None for indestructible tiles.
+    #[serde(default)]
+    pub durability: Option<u32>,
+    /// What the tile turns into when destroyed. A destroyed feature without one is removed.
+    #[serde(default)]
+    pub destroyed_into: Option<String>,
+    /// Standing liquid that comes with the tile, like the water of a lake. It doesn't flow.
+    #[serde(default)]
+    pub liquid: Option<Liquid>,
+    /// Chance per turn to catch fire from a burning neighbour; 0 for tiles that don't burn.
+    #[serde(default)]
+    pub flammability: f32,
+    /// What the tile turns into when it burns out. A burnt feature without one is removed.
+    #[serde(default)]
+    pub burns_into: Option<String>,
+}
+
+impl TileTypeDef {
+    /// Used for tile types the registry does not know about, so they stand out when rendered.
+    pub fn unknown() -> Self {
+        TileTypeDef {
+            atlas_index: 8 * 32 + 32,
+            tint: default_tint(),
+            glyph: None,
+            transparency: Transparency::Transparent,
+            traversal_cost: 1.0,
+            flags: HashSet::new(),
+            durability: None,
+            destroyed_into: None,
+            liquid: None,
+            flammability: 0.0,
+            burns_into: None,
+        }
+    }
+
+    pub fn tint_color(&self) -> Color {
+        Color::rgba(self.tint[0], self.tint[1], self.tint[2], self.tint[3])
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(flag)
+    }
+}
+
+/// Definitions of every tile type, keyed by the names stored in `MapTile::tile_type` and
+/// `MapTile::tile_visual`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileTypeRegistry {
+    pub types: HashMap<String, TileTypeDef>,
+    #[serde(skip, default = "TileTypeDef::unknown")]
+    unknown: TileTypeDef,
+}
+
+impl TileTypeRegistry {
+    /// The registry shipped in assets/tile_types.ron, parsed once and shared.
+    pub fn builtin() -> Arc<TileTypeRegistry> {
+        static BUILTIN: OnceLock<Arc<TileTypeRegistry>> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            let s = include_str!("../../../assets/tile_types.ron");
+            Arc::new(TileTypeRegistry::from_ron_str(s).expect("builtin tile types must parse"))
+        }).clone()
+    }
+
+    pub fn from_ron_str(s: &str) -> Result<Self, MapFileError> {
+        Ok(ron::from_str(s)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MapFileError> {
+        let s = fs::read_to_string(path)?;
+        Self::from_ron_str(&s)
+    }
+
+    pub fn get(&self, tile_type: &str) -> Option<&TileTypeDef> {
+        self.types.get(tile_type)
+    }
+
+    /// Like `get`, but falls back to a placeholder definition for unknown types.
+    pub fn get_or_unknown(&self, tile_type: &str) -> &TileTypeDef {
+        self.types.get(tile_type).unwrap_or(&self.unknown)
+    }
+
+    pub fn has_flag(&self, tile_type: &str, flag: &str) -> bool {
+        self.get(tile_type).is_some_and(|def| def.has_flag(flag))
+    }
+
+    fn def_for_new_tile(&self, tile_type: &str) -> &TileTypeDef {
+        match self.get(tile_type) {
+            Some(def) => def,
+            None => {
+                error!("Unknown tile type: {}", tile_type);
+                &self.unknown
+            }
+        }
+    }
+
+    pub fn make_tile(&self, tile_type: &str) -> MapTile {
+        let def = self.def_for_new_tile(tile_type);
+        MapTile {
+            tile_type: tile_type.to_string(),
+            tile_visual: tile_type.to_string(),
+            position: IVec2::new(0, 0),
+            contained_items: ItemContainer::new(),
+            transparency: def.transparency,
+            light_color: Color::BLACK,
+            light_amount: 0.0,
+            light: Vec3::ZERO,
+            traversal_cost: def.traversal_cost,
+            lighting: Vec::new(),
+            feature: None,
+            overhead: None,
+            damage: 0,
+            liquid: def.liquid,
+            fire: 0,
+            gas: None,
+        }
+    }
+
+    pub fn make_layer_tile(&self, tile_type: &str) -> LayerTile {
+        let def = self.def_for_new_tile(tile_type);
+        LayerTile {
+            tile_type: tile_type.to_string(),
+            tile_visual: tile_type.to_string(),
+            transparency: def.transparency,
+            traversal_cost: def.traversal_cost,
+        }
+    }
+
+    pub fn texture_index(&self, tile_visual: &str) -> TileTextureIndex {
+        TileTextureIndex(self.get_or_unknown(tile_visual).atlas_index)
+    }
+
+    /// '?' for unknown types and types without a glyph.
+    pub fn glyph(&self, tile_type: &str) -> char {
+        self.get(tile_type).and_then(|def| def.glyph).unwrap_or('?')
+    }
+
+    pub fn tint(&self, tile_visual: &str) -> Color {
+        self.get_or_unknown(tile_visual).tint_color()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::stibag::map::Transparency;
+    use crate::stibag::map::tiletypes::{TileTypeDef, TileTypeRegistry};
+
+    #[test]
+    fn registry_loads_assets_and_falls_back_to_unknown() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets").join("tile_types.ron");
+        let registry = TileTypeRegistry::load(path).unwrap();
+        let wall = registry.get("wall").unwrap();
+        assert_eq!(wall.atlas_index, 79);
+        assert_eq!(wall.transparency, Transparency::Opaque);
+        let tile = registry.make_tile("wall");
+        assert_eq!((tile.tile_type.as_str(), tile.tile_visual.as_str()), ("wall", "wall"));
+        assert_eq!(tile.transparency, Transparency::Opaque);
+        assert_eq!(tile.traversal_cost, -1.0);
+        assert_eq!(registry.texture_index("wall").0, 79);
+
+        let unknown = TileTypeDef::unknown();
+        assert!(registry.get("marble").is_none());
+        assert_eq!(registry.get_or_unknown("marble").atlas_index, unknown.atlas_index);
+        assert_eq!(registry.texture_index("marble").0, unknown.atlas_index);
+        let tile = registry.make_tile("marble");
+        assert_eq!(tile.tile_type, "marble");
+        assert_eq!(tile.transparency, unknown.transparency);
+        assert_eq!(tile.traversal_cost, unknown.traversal_cost);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+use bevy::math::IVec2;
+use crate::stibag::map::{ActorPlacement, LightPlacement, Map};
+use crate::stibag::map::gen::flood_regions_with;
+
+/// A problem found by `validate_map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapIssue {
+    /// Walkable tiles that can't be reached from the start; one issue per disconnected area.
+    Unreachable { tiles: Vec<IVec2> },
+    StairsWithoutDestination(IVec2),
+    /// A link to a map that doesn't exist.
+    UnknownDestination { position: IVec2, map: String },
+    LightInWall(IVec2),
+    /// A tile stored at the index of `expected` claims to be at `found`.
+    PositionMismatch { expected: IVec2, found: IVec2 },
+    ActorOnImpassable { template: String, position: IVec2 },
+}
+
+impl fmt::Display for MapIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapIssue::Unreachable { tiles } => write!(f, "{} unreachable walkable tiles, e.g. at {}", tiles.len(), tiles[0]),
+            MapIssue::StairsWithoutDestination(p) => write!(f, "stairs at {} lead nowhere", p),
+            MapIssue::UnknownDestination { position, map } => write!(f, "link at {} leads to unknown map {}", position, map),
+            MapIssue::LightInWall(p) => write!(f, "light at {} is inside an opaque tile", p),
+            MapIssue::PositionMismatch { expected, found } => write!(f, "tile at {} has position {}", expected, found),
+            MapIssue::ActorOnImpassable { template, position } => write!(f, "actor {} at {} stands on an impassable tile", template, position),
+        }
+    }
+}
+
+/// What `validate_map` checks a map against besides the map itself.
+#[derive(Default)]
+pub struct ValidationContext<'a> {
+    pub lights: &'a [LightPlacement],
+    pub actors: &'a [ActorPlacement],
+    /// Reachability is checked from here; without it, from the largest walkable area.
+    pub start: Option<IVec2>,
+    /// Names of the maps links may lead to; link destinations aren't checked without it.
+    pub known_maps: Option<&'a [String]>,
+}
+
+/// Closed doors count as walkable since actors can open them, deep liquid doesn't.
+fn is_walkable(map: &Map, position: IVec2) -> bool {
+    map.get_tile_at(position).is_some_and(|t| t.can_enter(false)) || map.door_state_at(position).is_some()
+}
+
+/// Checks the loaded part of `map` and returns every problem found, empty if there are none.
+pub fn validate_map(map: &Map, context: &ValidationContext) -> Vec<MapIssue> {
+    let mut issues = Vec::new();
+
+    for coord in map.loaded_chunk_coords() {
+        let chunk = map.get_chunk(coord).unwrap();
+        for (i, tile) in chunk.tiles.iter().enumerate() {
+            let expected = chunk.origin + IVec2::new(i as i32 % chunk.size.x, i as i32 / chunk.size.x);
+            if tile.position != expected {
+                issues.push(MapIssue::PositionMismatch { expected, found: tile.position });
+            }
+        }
+    }
+    // the other checks look tiles up by position, which asserts on exactly this
+    if !issues.is_empty() {
+        return issues;
+    }
+
+    let dimensions = IVec2::new(map.width as i32, map.height as i32);
+    let mut regions = flood_regions_with(dimensions, |p| map.grid.orthogonal_offsets(p), |p| is_walkable(map, p));
+    let main = match context.start {
+        Some(start) => regions.iter().position(|r| r.contains(&map.normalize_position(start))),
+        None => (0..regions.len()).max_by_key(|i| regions[*i].len()),
+    };
+    if let Some(main) = main {
+        regions.remove(main);
+    }
+    for mut tiles in regions {
+        tiles.sort_by_key(|p| (p.y, p.x));
+        issues.push(MapIssue::Unreachable { tiles });
+    }
+
+    let mut stairs: Vec<IVec2> = map.tiles()
+        .filter(|t| map.tile_types.has_flag(&t.tile_type, "stairs")
+            || t.feature.as_ref().is_some_and(|f| map.tile_types.has_flag(&f.tile_type, "stairs")))
+        .map(|t| t.position)
+        .collect();
+    stairs.sort_by_key(|p| (p.y, p.x));
+    for position in stairs {
+        if !map.links.contains_key(&position) {
+            issues.push(MapIssue::StairsWithoutDestination(position));
+        }
+    }
+    if let Some(known_maps) = context.known_maps {
+        let mut links: Vec<_> = map.links.iter().collect();
+        links.sort_by_key(|(p, _)| (p.y, p.x));
+        for (position, link) in links {
+            if !known_maps.contains(&link.map) {
+                issues.push(MapIssue::UnknownDestination { position: *position, map: link.map.clone() });
+            }
+        }
+    }
+
+    for light in context.lights {
+        if map.get_tile_at(light.position).is_some_and(|t| t.is_opaque()) {
+            issues.push(MapIssue::LightInWall(light.position));
+        }
+    }
+    for actor in context.actors {
+        if !map.get_tile_at(actor.position).is_some_and(|t| t.is_passable()) {
+            issues.push(MapIssue::ActorOnImpassable { template: actor.template.clone(), position: actor.position });
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec2;
+    use bevy::prelude::Color;
+    use crate::stibag::map::{ActorPlacement, LightPlacement, Map, MapLayer, MapLink};
+    use crate::stibag::map::gen;
+    use crate::stibag::map::validate::{validate_map, MapIssue, ValidationContext};
+
+    #[test]
+    fn generated_maps_are_valid() {
+        for template in ["dungeon_bsp", "cave"] {
+            for seed in 0..4 {
+                let generated = gen::generate(template, IVec2::new(60, 40), seed);
+                let issues = validate_map(&generated.map, &ValidationContext {
+                    lights: &generated.lights,
+                    actors: &generated.actors,
+                    start: Some(generated.player_spawn),
+                    known_maps: None,
+                });
+                assert!(issues.is_empty(), "{} seed {}: {:?}", template, seed, issues);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_broken_map() {
+        let mut map = Map::new_filled(IVec2::new(12, 6), "floor");
+        map.blit_tiles_from_charmap(IVec2::new(0, 0), vec![
+            "############".into(),
+            "#....#.....#".into(),
+            "#....#..#..#".into(),
+            "#....#######".into(),
+            "#....#...#.#".into(),
+            "############".into(),
+        ], |c| match c {
+            '#' => Some("wall"),
+            _ => None
+        });
+        map.blit_layer_type_at(MapLayer::Feature, IVec2::new(1, 1), "stairs_down");
+        map.blit_layer_type_at(MapLayer::Feature, IVec2::new(4, 4), "stairs_up");
+        map.links.insert(IVec2::new(4, 4), MapLink { map: "nowhere".to_string(), position: IVec2::new(0, 0) });
+        let lights = [LightPlacement::new(IVec2::new(8, 2), Color::WHITE, 1.0)];
+        let actors = [ActorPlacement { template: "monster".to_string(), position: IVec2::new(0, 0) }];
+        let known_maps = ["town".to_string()];
+        let issues = validate_map(&map, &ValidationContext {
+            lights: &lights,
+            actors: &actors,
+            start: Some(IVec2::new(2, 2)),
+            known_maps: Some(&known_maps),
+        });
+        assert_eq!(issues.len(), 7, "{:?}", issues);
+        assert_eq!(issues.iter().filter(|i| matches!(i, MapIssue::Unreachable { .. })).count(), 3);
+        assert!(issues.contains(&MapIssue::StairsWithoutDestination(IVec2::new(1, 1))));
+        assert!(issues.contains(&MapIssue::UnknownDestination { position: IVec2::new(4, 4), map: "nowhere".to_string() }));
+        assert!(issues.contains(&MapIssue::LightInWall(IVec2::new(8, 2))));
+        assert!(issues.contains(&MapIssue::ActorOnImpassable { template: "monster".to_string(), position: IVec2::new(0, 0) }));
+    }
+}
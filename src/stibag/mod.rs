@@ -1,6 +1,7 @@
 ﻿pub mod map;
 pub mod core;
 
+use std::collections::HashSet;
 use std::fs;
 use std::sync::{Arc, Mutex};
 use bevy::prelude::*;
@@ -11,8 +12,13 @@ use bevy::math::vec3;
 use bevy_ecs_tilemap::prelude::*;
 use bladeink;
 use bladeink::story_error::StoryError;
+use crate::stibag::map::{tone_map, Map, MapLayer};
+use crate::stibag::map::chunk::ChunkChange;
+use crate::stibag::map::grid::GridKind;
 
 const TILE_SIZE: f32 = 32.0;
+/// Brightness of remembered tiles that are out of sight.
+const REMEMBERED_BRIGHTNESS: f32 = 0.35;
 
 #[derive(Resource)]
 pub struct StibagWorldRes {
@@ -53,13 +59,37 @@ pub struct PlayerMarker;
 #[derive(Component)]
 pub struct CameraMarker;
 
+/// Which map layer a tilemap, or a tile within it, renders.
+#[derive(Component)]
+pub struct TileLayerMarker(MapLayer);
+
+/// The map chunk a tilemap, or a tile within it, belongs to.
+#[derive(Component)]
+pub struct MapChunkMarker(IVec2);
+
+/// Map position of a tile entity; `TilePos` is relative to the tile's chunk.
+#[derive(Component)]
+pub struct MapPos(IVec2);
+
+#[derive(Resource)]
+struct TilesTexture(Handle<Image>);
+
+/// Text showing the name of the region the possessed actor is in.
+#[derive(Component)]
+pub struct RegionHudMarker;
+
 #[derive(Bundle)]
 struct PlayerBundle {
     player_marker: PlayerMarker,
 }
 
+/// A movement input as a screen direction, y up. The map's grid decides which tile that is.
 #[derive(Event)]
-struct PlayerMovementEvent(IVec2);
+struct PlayerMovementEvent(Vec2);
+
+/// How far the left stick has to be pushed to step, and let back to step again.
+const STICK_PUSHED: f32 = 0.6;
+const STICK_RELEASED: f32 = 0.3;
 
 #[derive(Resource)]
 struct StibagGamepad(Gamepad);
@@ -67,7 +97,7 @@ struct StibagGamepad(Gamepad);
 pub struct StibagGamePlugin {}
 
 fn plugin_init(mut commands: Commands, asset_server: Res<AssetServer>,
-               st_world: Res<StibagWorldRes>,
+               mut st_world: ResMut<StibagWorldRes>,
                mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
                mut choice_ev: EventWriter<StoryChoiceEvent>,
                // array_texture_loader?
@@ -103,128 +133,290 @@ fn plugin_init(mut commands: Commands, asset_server: Res<AssetServer>,
     }, PlayerMarker {}));
 
 
-    let tmap_size = TilemapSize { x: st_world.world.map.width, y: st_world.world.map.height };
-    let mut tile_storage = TileStorage::empty(tmap_size);
-    let tmap_entity = commands.spawn_empty().id();
+    commands.spawn((TextBundle::from_section("", TextStyle {
+        font_size: 24.0,
+        color: Color::WHITE,
+        ..default()
+    }).with_style(Style {
+        position_type: PositionType::Absolute,
+        top: Val::Px(8.0),
+        left: Val::Px(8.0),
+        ..default()
+    }), RegionHudMarker));
 
-    for x in 0..tmap_size.x {
-        for y in 0..tmap_size.y {
-            let tile = st_world.world.map.get_tile_at(IVec2::new(x as i32, y as i32)).unwrap();
-            let tile_pos = TilePos { x, y };
+    commands.insert_resource(TilesTexture(tiles_tex_handle));
+    // build every chunk that is already loaded through the same path as streamed-in chunks
+    let loaded = st_world.world.map.loaded_chunk_coords();
+    st_world.world.chunk_changes.extend(loaded.into_iter().map(ChunkChange::Loaded));
+
+    info!("Stibag plugin init");
+}
+
+fn tilemap_type(grid: GridKind) -> TilemapType {
+    match grid {
+        GridKind::Square => TilemapType::Square,
+        GridKind::HexOddRows => TilemapType::Hexagon(HexCoordSystem::RowOdd),
+        GridKind::Isometric => TilemapType::Isometric(IsoCoordSystem::Diamond),
+    }
+}
+
+fn tilemap_grid_size(grid: GridKind) -> TilemapGridSize {
+    match grid {
+        // diamonds are twice as wide as they are high
+        GridKind::Isometric => TilemapGridSize { x: TILE_SIZE, y: TILE_SIZE / 2.0 },
+        _ => TilemapGridSize { x: TILE_SIZE, y: TILE_SIZE },
+    }
+}
+
+/// World-space center of a map tile. Chunk tilemaps are placed so that this holds for every chunk;
+/// on hex maps that needs chunks to start on even rows, which the even chunk size ensures.
+fn map_pos_to_world(pos: IVec2, z: f32, grid: GridKind) -> Vec3 {
+    let tpos = TilePos { x: pos.x as u32, y: pos.y as u32 };
+    tpos.center_in_world(&tilemap_grid_size(grid), &tilemap_type(grid)).extend(z)
+}
+
+fn spawn_chunk_tilemaps(commands: &mut Commands, map: &Map, coord: IVec2, texture: &Handle<Image>) {
+    let Some(chunk) = map.get_chunk(coord) else {
+        return;
+    };
+    let tmap_size = TilemapSize { x: chunk.size.x as u32, y: chunk.size.y as u32 };
+    let tile_size = TilemapTileSize { x: TILE_SIZE, y: TILE_SIZE };
+    let grid_size = tilemap_grid_size(map.grid);
+    let map_type = tilemap_type(map.grid);
+
+    // one tilemap per map layer; overheads sit above the player sprite (z 5)
+    for (layer, z) in [(MapLayer::Ground, 0.0), (MapLayer::Feature, 1.0), (MapLayer::Overhead, 10.0)] {
+        let mut tile_storage = TileStorage::empty(tmap_size);
+        let tmap_entity = commands.spawn_empty().id();
+
+        for tile in chunk.tiles.iter() {
+            let Some(visual) = tile.layer_visual(layer) else {
+                continue;
+            };
+            let local = tile.position - chunk.origin;
+            let tile_pos = TilePos { x: local.x as u32, y: local.y as u32 };
             let tile_entity = commands.spawn((TileBundle {
                 position: tile_pos,
                 tilemap_id: TilemapId(tmap_entity),
-                texture_index: tile.get_texture_index(),
+                texture_index: map.tile_types.texture_index(visual),
                 ..Default::default()
-            }, )).id();
+            }, TileLayerMarker(layer), MapChunkMarker(coord), MapPos(tile.position))).id();
             tile_storage.set(&tile_pos, tile_entity);
         }
+
+        commands.entity(tmap_entity).insert((TilemapBundle {
+            grid_size,
+            map_type,
+            size: tmap_size,
+            storage: tile_storage,
+            texture: TilemapTexture::Single(texture.clone()),
+            tile_size,
+            transform: Transform::from_translation(map_pos_to_world(chunk.origin, z, map.grid)),
+            ..Default::default()
+        }, TileLayerMarker(layer), MapChunkMarker(coord)));
     }
+}
 
-    let tile_size = TilemapTileSize { x: 32.0, y: 32.0 };
-    let grid_size = tile_size.into();
-    let map_type = TilemapType::default();
-    commands.entity(tmap_entity).insert(TilemapBundle {
-        grid_size,
-        map_type,
-        size: tmap_size,
-        storage: tile_storage,
-        texture: TilemapTexture::Single(tiles_tex_handle),
-        tile_size,
-        transform: get_tilemap_center_transform(&tmap_size, &grid_size, &map_type, 0.0),
-        ..Default::default()
-    });
+/// Applies the chunk loads and unloads queued by the world: unloaded chunks are despawned and
+/// loaded ones get fresh tilemaps.
+fn chunk_streaming_sys(mut commands: Commands, mut st_world: ResMut<StibagWorldRes>,
+                       tiles_texture: Res<TilesTexture>,
+                       chunk_query: Query<(Entity, &MapChunkMarker)>) {
+    if st_world.world.chunk_changes.is_empty() {
+        return;
+    }
+    let changes = std::mem::take(&mut st_world.world.chunk_changes);
+    let mut final_state: Vec<(IVec2, bool)> = Vec::new();
+    for change in changes {
+        let (coord, loaded) = match change {
+            ChunkChange::Loaded(c) => (c, true),
+            ChunkChange::Unloaded(c) => (c, false),
+        };
+        final_state.retain(|(c, _)| *c != coord);
+        final_state.push((coord, loaded));
+    }
+    for (e, chunk) in chunk_query.iter() {
+        if final_state.iter().any(|(c, _)| *c == chunk.0) {
+            commands.entity(e).despawn();
+        }
+    }
+    for (coord, loaded) in final_state {
+        if loaded {
+            spawn_chunk_tilemaps(&mut commands, &st_world.world.map, coord, &tiles_texture.0);
+        }
+    }
+}
 
-    info!("Stibag plugin init");
+/// Sent for every map position whose tiles changed after their tile entities were built.
+#[derive(Event)]
+pub struct TileChangedEvent(pub IVec2);
+
+/// Turns the tile changes queued by the world into `TileChangedEvent`s.
+fn tile_change_events_sys(mut st_world: ResMut<StibagWorldRes>, mut ev_tile_changed: EventWriter<TileChangedEvent>) {
+    for pos in std::mem::take(&mut st_world.world.tile_changes) {
+        ev_tile_changed.send(TileChangedEvent(pos));
+    }
+}
+
+/// Updates the tile entities of changed tiles in place. A layer that shows up where its tilemap
+/// has no tile gets the whole chunk rebuilt instead.
+fn tile_refresh_sys(mut st_world: ResMut<StibagWorldRes>, mut ev_tile_changed: EventReader<TileChangedEvent>,
+                    mut tile_query: Query<(&MapPos, &TileLayerMarker, &mut TileTextureIndex, &mut TileVisible)>) {
+    let changed: HashSet<IVec2> = ev_tile_changed.read().map(|ev| ev.0).collect();
+    if changed.is_empty() {
+        return;
+    }
+    let map = &st_world.world.map;
+    let mut refreshed: HashSet<(IVec2, MapLayer)> = HashSet::new();
+    for (map_pos, layer, mut texture, mut visible) in tile_query.iter_mut() {
+        if !changed.contains(&map_pos.0) {
+            continue;
+        }
+        refreshed.insert((map_pos.0, layer.0));
+        let Some(tile) = map.get_tile_at(map_pos.0) else {
+            continue;
+        };
+        match tile.layer_visual(layer.0) {
+            Some(visual) => {
+                *texture = match layer.0 {
+                    MapLayer::Ground => tile.get_texture_index(&map.tile_types),
+                    _ => map.tile_types.texture_index(visual),
+                };
+                // overheads are shown and hidden by overhead_visibility_sys
+                if layer.0 != MapLayer::Overhead {
+                    visible.0 = true;
+                }
+            }
+            None => visible.0 = false,
+        }
+    }
+    let mut rebuild: Vec<IVec2> = Vec::new();
+    for pos in changed {
+        let Some(tile) = map.get_tile_at(pos) else {
+            continue;
+        };
+        let missing = [MapLayer::Ground, MapLayer::Feature, MapLayer::Overhead].into_iter()
+            .any(|layer| tile.layer_visual(layer).is_some() && !refreshed.contains(&(pos, layer)));
+        let coord = map.chunk_coord_of(pos);
+        if missing && !rebuild.contains(&coord) {
+            rebuild.push(coord);
+        }
+    }
+    st_world.world.chunk_changes.extend(rebuild.into_iter().map(ChunkChange::Loaded));
 }
 
 fn entity_sprite_position_sys(mut query: Query<(&mut Transform, ), With<PlayerMarker>>,
-                              mut tilemap_q: ParamSet<(Query<(&Transform, &TilemapType, &TilemapGridSize, &TileStorage), (With<TileStorage>, Without<PlayerMarker>, Without<CameraMarker>)>, )>,
                               st_world: ResMut<StibagWorldRes>) {
-    let tmq = tilemap_q.p0();
-    let (map_transform, map_type, grid_size, _tilemap_storage) = tmq.single();
-
     // possessed actor
     let plr_a = st_world.world.player_interface.possessed_actor;
     let plr_pos = st_world.world.get_actor_pos(plr_a);
 
     for (mut transform, ) in query.iter_mut() {
-        let tpos = TilePos { x: plr_pos.x as u32, y: plr_pos.y as u32 };
-        let world_pos = tpos.center_in_world(grid_size, map_type).extend(5.0);
-        transform.translation = map_transform.translation + world_pos;
+        transform.translation = map_pos_to_world(plr_pos, 5.0, st_world.world.map.grid);
     }
 }
 
-fn player_movement_sys(mut plr_set: ParamSet<(Query<&mut Transform, With<PlayerMarker>>, )>,
-                       mut ev_movement: EventReader<PlayerMovementEvent>, mut st_world: ResMut<StibagWorldRes>, ) {
+fn player_movement_sys(mut ev_movement: EventReader<PlayerMovementEvent>, mut st_world: ResMut<StibagWorldRes>, ) {
     let plr_a = st_world.world.player_interface.possessed_actor;
-    let mut p = plr_set.p0();
-    let plr_transform = p.single_mut();
-    let mut pos = plr_transform.translation;
     for ev in ev_movement.read() {
-        let moved = st_world.world.try_move_actor_by(plr_a, IVec2::new(ev.0.x, ev.0.y));
+        let plr_pos = st_world.world.get_actor_pos(plr_a);
+        let target = st_world.world.map.step(plr_pos, ev.0);
+        let moved = st_world.world.try_move_actor_to(plr_a, target);
         if moved {
             info!("Player moved by {:?} now at {:?}", ev.0, st_world.world.get_actor_pos(plr_a));
         } else {
             info!("Player could not move by {:?}", ev.0);
         }
         info!("Player now at {:?}", st_world.world.get_actor_pos(plr_a));
-        pos.x += ev.0.x as f32 * TILE_SIZE;
-        pos.y += ev.0.y as f32 * TILE_SIZE;
-        // plr_transform.translation = pos;
-        // TODO geo check
     }
 }
 
 fn camera_recenter_sys(mut cam_set: ParamSet<(Query<&mut Transform, (With<CameraMarker>, Without<PlayerMarker>)>, )>,
-                       mut tilemap_q: ParamSet<(Query<(&Transform, &TilemapType, &TilemapGridSize, &TileStorage), (With<TileStorage>, Without<PlayerMarker>, Without<CameraMarker>)>, )>,
                        st_world: ResMut<StibagWorldRes>) {
     let plr_a = st_world.world.player_interface.possessed_actor;
-    let tmq = tilemap_q.p0();
-    let (map_transform, map_type, grid_size, _tilemap_storage) = tmq.single();
     let mut c = cam_set.p0();
     let mut cam_trans = c.single_mut();
     let plr_map_pos = st_world.world.get_actor_pos(plr_a);
-    let tpos = TilePos { x: plr_map_pos.x as u32, y: plr_map_pos.y as u32 };
-    let t_center = tpos.center_in_world(&grid_size, map_type).extend(1.0);
-    cam_trans.translation = map_transform.translation + t_center;
+    cam_trans.translation = map_pos_to_world(plr_map_pos, 1.0, st_world.world.map.grid);
 }
 
-fn reassign_vision_markers_sys(mut commands: Commands, st_world: Res<StibagWorldRes>, mut current_viz_query: Query<(Entity, ), With<InVisionMarker>>,
-                               map_tile_query: Query<(Entity, &TilePos, &mut TileColor)>,
+fn reassign_vision_markers_sys(mut commands: Commands, mut st_world: ResMut<StibagWorldRes>, mut current_viz_query: Query<(Entity, ), With<InVisionMarker>>,
+                               map_tile_query: Query<(Entity, &MapPos, &mut TileColor)>,
 ) {
-    let plr_map_pos = st_world.world.get_possessed_actor_pos();
-    let plr_vision = st_world.world.map.calc_vision(plr_map_pos, 50.0);
+    let plr_a = st_world.world.player_interface.possessed_actor;
+    let plr_map_pos = st_world.world.get_actor_pos(plr_a);
+    let plr_vision = st_world.world.map.calc_vision_with(plr_map_pos, 50.0, st_world.world.get_actor_fov(plr_a));
+    st_world.world.remember_tiles(plr_a, &plr_vision);
     for (e, ) in current_viz_query.iter_mut() {
         commands.entity(e).remove::<InVisionMarker>();
     }
-    for (e, tile_pos, _tile_color) in map_tile_query.iter() {
-        if plr_vision.contains(&IVec2::new(tile_pos.x as i32, tile_pos.y as i32)) {
+    for (e, map_pos, _tile_color) in map_tile_query.iter() {
+        if plr_vision.contains(&map_pos.0) {
             commands.entity(e).insert(InVisionMarker);
         }
     }
 }
 
+/// Lights the tiles in sight and shows the others as the possessed actor remembers them, dimmed,
+/// or black if it has never seen them.
 fn set_material_colors_sys(mut _commands: Commands, st_world: Res<StibagWorldRes>,
-                           mut viz_query: Query<(Entity, &TilePos, &mut TileColor), With<InVisionMarker>>,
-                           mut noviz_query: Query<(Entity, &TilePos, &mut TileColor), Without<InVisionMarker>>) {
-    for (_e, tilepos, mut color) in viz_query.iter_mut() {
-        let wt = st_world.world.map.get_tile_at(IVec2::new(tilepos.x as i32, tilepos.y as i32)).unwrap();
-        let lval = st_world.world.get_light_value_at(IVec2::new(tilepos.x as i32, tilepos.y as i32));
-        let ambient = st_world.world.get_ambient_light_value();
-        let final_color = if lval.1 > 0.0 {
-            wt.get_color() * lval.1
-        } else {
-            ambient.0 * ambient.1
+                           mut viz_query: Query<(Entity, &MapPos, &mut TileColor, &TileLayerMarker, &mut TileTextureIndex), With<InVisionMarker>>,
+                           mut noviz_query: Query<(Entity, &MapPos, &mut TileColor, &TileLayerMarker, &mut TileTextureIndex), Without<InVisionMarker>>) {
+    let tile_types = &st_world.world.map.tile_types;
+    for (_e, map_pos, mut color, layer, mut texture) in viz_query.iter_mut() {
+        let Some(wt) = st_world.world.map.get_tile_at(map_pos.0) else {
+            continue;
+        };
+        // the tile may have been showing what was remembered of it
+        match layer.0 {
+            MapLayer::Ground => *texture = wt.get_texture_index(tile_types),
+            other => if let Some(visual) = wt.layer_visual(other) {
+                *texture = tile_types.texture_index(visual);
+            },
+        }
+        let light = tone_map(st_world.world.get_hdr_light_at(map_pos.0));
+        let base_color = match layer.0 {
+            MapLayer::Ground => wt.get_color(tile_types),
+            other => tile_types.tint(wt.layer_visual(other).unwrap_or_default()),
+        };
+        let [r, g, b, a] = base_color.as_linear_rgba_f32();
+        *color = TileColor::from(Color::rgba_linear(r * light.x, g * light.y, b * light.z, a));
+    }
+    let plr_a = st_world.world.player_interface.possessed_actor;
+    for (_e, map_pos, mut color, layer, mut texture) in noviz_query.iter_mut() {
+        let remembered = st_world.world.recall_tile(plr_a, map_pos.0);
+        *color = match remembered.map(|r| r.layer_visual(layer.0)) {
+            Some(Some(visual)) => {
+                *texture = tile_types.texture_index(visual);
+                TileColor::from(tile_types.tint(visual) * REMEMBERED_BRIGHTNESS)
+            }
+            // the layer was empty when the tile was last seen
+            Some(None) => TileColor::from(Color::NONE),
+            None => TileColor::from(Color::BLACK),
         };
-        *color = TileColor::from(final_color);
     }
-    for (_e, _tilepos, mut color) in noviz_query.iter_mut() {
-        *color = TileColor::from(Color::BLACK);
+}
+
+/// Hides the roof or canopy the possessed actor is standing under.
+fn overhead_visibility_sys(st_world: Res<StibagWorldRes>,
+                           mut overhead_query: Query<(&MapPos, &TileLayerMarker, &mut TileVisible)>) {
+    let plr_map_pos = st_world.world.get_possessed_actor_pos();
+    let hidden = st_world.world.map.connected_overhead(plr_map_pos);
+    for (map_pos, layer, mut visible) in overhead_query.iter_mut() {
+        if layer.0 != MapLayer::Overhead {
+            continue;
+        }
+        let should_show = !hidden.contains(&map_pos.0);
+        if visible.0 != should_show {
+            visible.0 = should_show;
+        }
     }
 }
 
-fn gamepad_input_events(mut _commands: Commands, stibag_gamepad: Option<Res<StibagGamepad>>, mut gamepad_evr: EventReader<GamepadEvent>, mut ev_movement: EventWriter<PlayerMovementEvent>) {
+/// Steps with the D-pad, or with the left stick in any direction, which reaches the diagonal
+/// neighbours on square grids and all six on hex grids. The stick steps once per push.
+fn gamepad_input_events(mut _commands: Commands, stibag_gamepad: Option<Res<StibagGamepad>>, mut gamepad_evr: EventReader<GamepadEvent>, mut ev_movement: EventWriter<PlayerMovementEvent>,
+                        mut stick: Local<Vec2>, mut stick_pushed: Local<bool>) {
     if let Some(gamepad) = stibag_gamepad {
         for ev in gamepad_evr.read() {
             match ev {
@@ -233,23 +425,36 @@ fn gamepad_input_events(mut _commands: Commands, stibag_gamepad: Option<Res<Stib
                         info!("Button event: {:?}", input);
                         match input.button_type {
                             GamepadButtonType::DPadUp => {
-                                ev_movement.send(PlayerMovementEvent(IVec2::new(0, 1)));
+                                ev_movement.send(PlayerMovementEvent(Vec2::new(0.0, 1.0)));
                             }
                             GamepadButtonType::DPadDown => {
-                                ev_movement.send(PlayerMovementEvent(IVec2::new(0, -1)));
+                                ev_movement.send(PlayerMovementEvent(Vec2::new(0.0, -1.0)));
                             }
                             GamepadButtonType::DPadLeft => {
-                                ev_movement.send(PlayerMovementEvent(IVec2::new(-1, 0)));
+                                ev_movement.send(PlayerMovementEvent(Vec2::new(-1.0, 0.0)));
                             }
                             GamepadButtonType::DPadRight => {
-                                ev_movement.send(PlayerMovementEvent(IVec2::new(1, 0)));
+                                ev_movement.send(PlayerMovementEvent(Vec2::new(1.0, 0.0)));
                             }
                             _ => {}
                         }
                     }
                 }
                 GamepadEvent::Axis(input) => {
-                    info!("Axis event: {:?}", input);
+                    if input.gamepad.id != gamepad.0.id {
+                        continue;
+                    }
+                    match input.axis_type {
+                        GamepadAxisType::LeftStickX => stick.x = input.value,
+                        GamepadAxisType::LeftStickY => stick.y = input.value,
+                        _ => continue,
+                    }
+                    if !*stick_pushed && stick.length() > STICK_PUSHED {
+                        *stick_pushed = true;
+                        ev_movement.send(PlayerMovementEvent(*stick));
+                    } else if *stick_pushed && stick.length() < STICK_RELEASED {
+                        *stick_pushed = false;
+                    }
                 }
                 _ => {}
             }
@@ -297,12 +502,29 @@ struct StoryTagsEvent(String, String);
 #[derive(Event)]
 struct ChangeMapEvent(String); // change the map to the one specified
 
-fn story_tag_handler_sys(mut commands: Commands, mut ev_tags: EventReader<StoryTagsEvent>, mut st_world: ResMut<StibagWorldRes>) {
+fn story_tag_handler_sys(mut commands: Commands, mut ev_tags: EventReader<StoryTagsEvent>, mut st_world: ResMut<StibagWorldRes>,
+                         mut ev_change_map: EventWriter<ChangeMapEvent>) {
     for ev in ev_tags.read() {
         let (tag, args) = (ev.0.clone(), ev.1.clone());
         match tag.as_str() {
             "change_map" => {
-                unimplemented!();
+                ev_change_map.send(ChangeMapEvent(args.trim().to_string()));
+            }
+            "ignite" => {
+                // ignite:x,y sets the map on fire there
+                let coords: Vec<Result<i32, _>> = args.split(',').map(|c| c.trim().parse::<i32>()).collect();
+                match coords.as_slice() {
+                    [Ok(x), Ok(y)] => {
+                        st_world.world.ignite(IVec2::new(*x, *y));
+                    }
+                    _ => error!("Invalid ignite tag arguments: {}", args),
+                }
+            }
+            "script" => {
+                // script:<koto code> runs the code, which may call ignite, pour_liquid and release_gas
+                if let Err(e) = st_world.world.run_script(&args) {
+                    error!("Story script failed: {}", e);
+                }
             }
             _ => {
                 error!("Unknown tag event: {:?}/{}", tag, args);
@@ -311,6 +533,43 @@ fn story_tag_handler_sys(mut commands: Commands, mut ev_tags: EventReader<StoryT
     }
 }
 
+/// Moves the possessed actor to the named map; the old map's tilemaps are torn down and the new
+/// ones built by `chunk_streaming_sys` from the queued chunk changes.
+fn change_map_sys(mut ev_change_map: EventReader<ChangeMapEvent>, mut st_world: ResMut<StibagWorldRes>) {
+    for ev in ev_change_map.read() {
+        st_world.world.change_map(&ev.0, None);
+    }
+}
+
+/// Sent when the possessed actor enters a named map region.
+#[derive(Event)]
+pub struct RegionEnteredEvent(pub String);
+
+/// Forwards the region changes queued by the world; entering a region with a `story_choice`
+/// property selects that story choice.
+fn region_events_sys(mut st_world: ResMut<StibagWorldRes>, mut ev_region_entered: EventWriter<RegionEnteredEvent>,
+                     mut ev_story_choice: EventWriter<StoryChoiceEvent>) {
+    for ev in std::mem::take(&mut st_world.world.region_events) {
+        let core::RegionEvent::Entered(name) = ev else {
+            continue;
+        };
+        if let Some(choice) = st_world.world.map.region(&name).and_then(|r| r.property("story_choice")) {
+            ev_story_choice.send(StoryChoiceEvent(choice.to_string()));
+        }
+        ev_region_entered.send(RegionEnteredEvent(name));
+    }
+}
+
+/// Shows the innermost region the possessed actor is in.
+fn region_hud_sys(st_world: Res<StibagWorldRes>, mut hud_query: Query<&mut Text, With<RegionHudMarker>>) {
+    let name = st_world.world.possessed_regions.last().map(|n| n.as_str()).unwrap_or("");
+    for mut text in hud_query.iter_mut() {
+        if text.sections[0].value != name {
+            text.sections[0].value = name.to_string();
+        }
+    }
+}
+
 fn story_progression_sys(mut commands: Commands, mut st_world: ResMut<StibagWorldRes>,
                          mut ev_story_text: EventWriter<StoryTextEvent>,
                          mut ev_tags: EventWriter<StoryTagsEvent>,
@@ -403,16 +662,26 @@ impl Plugin for StibagGamePlugin {
         app.add_event::<StoryChoiceEvent>();
         app.add_event::<StoryChoiceEventWithIndex>();
         app.add_event::<StoryTagsEvent>();
+        app.add_event::<ChangeMapEvent>();
+        app.add_event::<TileChangedEvent>();
+        app.add_event::<RegionEnteredEvent>();
 
         app.add_systems(Startup, plugin_init);
         app.add_systems(Update, gamepad_connections);
         app.add_systems(Update, gamepad_input_events);
         app.add_systems(Update, player_movement_sys);
+        app.add_systems(Update, change_map_sys.after(story_tag_handler_sys));
+        app.add_systems(Update, tile_change_events_sys.after(player_movement_sys));
+        app.add_systems(Update, tile_refresh_sys.after(tile_change_events_sys));
+        app.add_systems(Update, chunk_streaming_sys.after(player_movement_sys).after(change_map_sys).after(tile_refresh_sys));
         app.add_systems(Update, entity_sprite_position_sys.after(player_movement_sys));
         app.add_systems(Update, camera_recenter_sys);
         app.add_systems(Update, reassign_vision_markers_sys.after(player_movement_sys));
         app.add_systems(Update, set_material_colors_sys.after(reassign_vision_markers_sys));
-        app.add_systems(Update, story_progression_sys);
+        app.add_systems(Update, overhead_visibility_sys.after(player_movement_sys));
+        app.add_systems(Update, region_events_sys.after(player_movement_sys));
+        app.add_systems(Update, region_hud_sys.after(region_events_sys));
+        app.add_systems(Update, story_progression_sys.after(region_events_sys));
         app.add_systems(Update, story_tag_handler_sys.after(story_progression_sys));
     }
 }
\ No newline at end of file